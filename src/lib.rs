@@ -13,3 +13,5 @@ pub mod secret;
 pub mod stream;
 #[cfg(feature = "terminal")]
 pub mod terminal;
+#[cfg(feature = "tls")]
+pub mod tls;
@@ -1,18 +1,159 @@
-use secrecy::SecretString;
+use anyhow::{bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use md5::Md5;
+use secrecy::{ExposeSecret, SecretString};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use stringprep::saslprep;
 
-#[derive(Clone, Debug)]
+/// SCRAM variants, from the strongest to the weakest.
+const SCRAM_MECHANISMS: [(&str, ScramHash, bool); 4] = [
+    ("SCRAM-SHA-256-PLUS", ScramHash::Sha256, true),
+    ("SCRAM-SHA-256", ScramHash::Sha256, false),
+    ("SCRAM-SHA-1-PLUS", ScramHash::Sha1, true),
+    ("SCRAM-SHA-1", ScramHash::Sha1, false),
+];
+
+#[derive(Clone, Debug, Default)]
 pub struct Sasl {
     pub mechanism: Option<SaslMechanism>,
     pub login: Option<SaslLogin>,
     pub plain: Option<SaslPlain>,
     pub anonymous: Option<SaslAnonymous>,
+    pub xoauth2: Option<SaslXOAuth2>,
+    pub oauthbearer: Option<SaslOAuthBearer>,
+    pub scram: Option<SaslScram>,
+    pub external: Option<SaslExternal>,
+    pub cram_md5: Option<SaslCramMd5>,
 }
 
-#[derive(Clone, Debug)]
+/// Mechanisms picked when none is explicitly configured, from the
+/// most to the least preferred (see [`Sasl::select_mechanism`]).
+const PREFERRED_MECHANISMS: [SaslMechanism; 8] = [
+    SaslMechanism::External,
+    SaslMechanism::Scram,
+    SaslMechanism::Plain,
+    SaslMechanism::Login,
+    SaslMechanism::OAuthBearer,
+    SaslMechanism::XOAuth2,
+    SaslMechanism::CramMd5,
+    SaslMechanism::Anonymous,
+];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SaslMechanism {
     Login,
     Plain,
     Anonymous,
+    XOAuth2,
+    OAuthBearer,
+    /// Strongest SCRAM variant offered by the server.
+    Scram,
+    /// Authentication established outside of SASL, like a TLS client
+    /// certificate.
+    External,
+    /// Weak challenge-response mechanism, for legacy servers.
+    CramMd5,
+}
+
+impl Sasl {
+    /// Returns whether the given mechanism is configured.
+    ///
+    /// EXTERNAL and ANONYMOUS need no configuration, but are only
+    /// used when configured, even with their defaults.
+    pub fn is_configured(&self, mechanism: SaslMechanism) -> bool {
+        match mechanism {
+            SaslMechanism::Login => self.login.is_some(),
+            SaslMechanism::Plain => self.plain.is_some(),
+            SaslMechanism::Anonymous => self.anonymous.is_some(),
+            SaslMechanism::XOAuth2 => self.xoauth2.is_some(),
+            SaslMechanism::OAuthBearer => self.oauthbearer.is_some(),
+            SaslMechanism::Scram => self.scram.is_some(),
+            SaslMechanism::External => self.external.is_some(),
+            SaslMechanism::CramMd5 => self.cram_md5.is_some(),
+        }
+    }
+
+    /// Picks the mechanism to authenticate with, given the mechanisms
+    /// advertised by the server.
+    ///
+    /// The explicitly configured mechanism is used as long as the
+    /// server offers it. Otherwise, the preferred mechanism among the
+    /// configured and offered ones is picked, weak mechanisms only
+    /// when nothing stronger is available, and anonymous access last.
+    ///
+    /// Servers advertising no mechanism at all are assumed to accept
+    /// any, except EXTERNAL.
+    pub fn select_mechanism(&self, offered: &[String]) -> Result<SaslMechanism> {
+        if let Some(mechanism) = self.mechanism {
+            if !offered.is_empty() && !mechanism.is_offered(offered) {
+                let offered = offered.join(", ");
+                bail!("SASL {mechanism:?} is not offered by the server (offered: {offered})");
+            }
+
+            return Ok(mechanism);
+        }
+
+        let mut candidates: Vec<_> = PREFERRED_MECHANISMS
+            .into_iter()
+            .filter(|mechanism| self.is_configured(*mechanism))
+            .collect();
+
+        if candidates.is_empty() {
+            bail!("no SASL mechanism configured");
+        }
+
+        candidates.retain(|mechanism| match mechanism {
+            // only works when the server asked for a TLS client
+            // certificate, which it tells by advertising it
+            SaslMechanism::External if offered.is_empty() => false,
+            _ if offered.is_empty() => true,
+            mechanism => mechanism.is_offered(offered),
+        });
+
+        // the sort is stable: preferences are kept otherwise
+        candidates.sort_by_key(|mechanism| {
+            let anonymous = *mechanism == SaslMechanism::Anonymous;
+            (anonymous, mechanism.is_weak())
+        });
+
+        match candidates.first() {
+            Some(mechanism) => Ok(*mechanism),
+            None => {
+                let offered = offered.join(", ");
+                bail!("no configured SASL mechanism offered by the server (offered: {offered})")
+            }
+        }
+    }
+}
+
+impl SaslMechanism {
+    /// Returns `true` for mechanisms considered weak, only picked
+    /// when explicitly configured or when nothing better is
+    /// available.
+    pub fn is_weak(&self) -> bool {
+        matches!(self, Self::CramMd5)
+    }
+
+    /// Returns whether the mechanism is among the ones offered by
+    /// the server, any SCRAM variant counting for
+    /// [`SaslMechanism::Scram`].
+    pub fn is_offered(&self, offered: &[String]) -> bool {
+        let is_offered = |name: &str| offered.iter().any(|m| m.eq_ignore_ascii_case(name));
+
+        match self {
+            Self::Login => is_offered("LOGIN"),
+            Self::Plain => is_offered("PLAIN"),
+            Self::Anonymous => is_offered("ANONYMOUS"),
+            Self::XOAuth2 => is_offered("XOAUTH2"),
+            Self::OAuthBearer => is_offered("OAUTHBEARER"),
+            Self::Scram => SCRAM_MECHANISMS.iter().any(|(name, _, _)| is_offered(name)),
+            Self::External => is_offered("EXTERNAL"),
+            Self::CramMd5 => is_offered("CRAM-MD5"),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -28,7 +169,788 @@ pub struct SaslPlain {
     pub passwd: SecretString,
 }
 
-#[derive(Clone, Debug)]
+/// Anonymous access (RFC 4505).
+#[derive(Clone, Debug, Default)]
 pub struct SaslAnonymous {
+    /// Trace information, like an email address, up to 255
+    /// characters.
     pub message: Option<String>,
 }
+
+/// Authentication established outside of SASL, like a TLS client
+/// certificate (RFC 4422 Appendix A).
+///
+/// Only picked when the server advertises it, which it does once it
+/// received a client certificate.
+#[derive(Clone, Debug, Default)]
+pub struct SaslExternal {
+    /// Identity to act as, when different from the one derived from
+    /// the external authentication.
+    pub authzid: Option<String>,
+}
+
+/// Legacy challenge-response mechanism (RFC 2195).
+///
+/// The password does not cross the wire, but the exchange can be
+/// brute-forced offline and the server must store the password in
+/// clear.
+#[derive(Clone, Debug)]
+pub struct SaslCramMd5 {
+    pub username: String,
+    pub password: SecretString,
+}
+
+/// Google and Microsoft proprietary OAuth 2.0 mechanism.
+#[derive(Clone, Debug)]
+pub struct SaslXOAuth2 {
+    pub user: String,
+    /// OAuth 2.0 access token.
+    pub token: SecretString,
+}
+
+/// OAuth 2.0 bearer token mechanism (RFC 7628).
+#[derive(Clone, Debug)]
+pub struct SaslOAuthBearer {
+    pub user: String,
+    /// OAuth 2.0 access token.
+    pub token: SecretString,
+}
+
+/// Salted Challenge Response Authentication Mechanisms (RFC 5802,
+/// RFC 7677): the password never crosses the wire, and the server
+/// proves it knows it too.
+#[derive(Clone, Debug)]
+pub struct SaslScram {
+    pub authzid: Option<String>,
+    pub username: String,
+    pub password: SecretString,
+}
+
+/// Channel binding of the connection (RFC 5056), used by the `-PLUS`
+/// variants of SCRAM.
+#[derive(Clone, Debug)]
+pub struct SaslChannelBinding {
+    /// Channel binding type, like `tls-exporter`.
+    pub name: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// Client side of a SASL mechanism, as driven by the sessions.
+///
+/// Messages are raw: encoding them is up to the protocol.
+pub trait SaslClient: Send {
+    /// Name of the mechanism, as sent to the server.
+    fn mechanism(&self) -> &'static str;
+
+    /// Returns the initial response, for mechanisms where the client
+    /// speaks first.
+    fn initial_response(&mut self) -> Option<Vec<u8>>;
+
+    /// Answers a challenge of the server.
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>>;
+
+    /// Describes why the server rejected the authentication, from the
+    /// challenges received.
+    fn failure(&self) -> Option<String> {
+        None
+    }
+
+    /// Checks that the exchange completed once the server accepted
+    /// the authentication, for mechanisms authenticating the server
+    /// as well.
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl SaslAnonymous {
+    /// Returns the client side of the mechanism.
+    pub fn client(self) -> Result<Box<dyn SaslClient>> {
+        let message = self.message.unwrap_or_default();
+
+        if message.chars().count() > 255 {
+            bail!("SASL ANONYMOUS trace message exceeds 255 characters");
+        }
+
+        Ok(Box::new(SingleResponseClient {
+            mechanism: "ANONYMOUS",
+            initial_response: Some(message.into_bytes()),
+        }))
+    }
+}
+
+impl SaslExternal {
+    /// Returns the client side of the mechanism.
+    pub fn client(self) -> Box<dyn SaslClient> {
+        let authzid = self.authzid.unwrap_or_default();
+
+        Box::new(SingleResponseClient {
+            mechanism: "EXTERNAL",
+            initial_response: Some(authzid.into_bytes()),
+        })
+    }
+}
+
+impl SaslCramMd5 {
+    /// Returns the client side of the mechanism.
+    pub fn client(self) -> Box<dyn SaslClient> {
+        warn!("using weak SASL CRAM-MD5 mechanism");
+
+        Box::new(CramMd5Client {
+            username: self.username,
+            password: self.password,
+        })
+    }
+}
+
+impl SaslXOAuth2 {
+    /// Returns the client side of the mechanism.
+    pub fn client(self) -> Box<dyn SaslClient> {
+        let response = format!(
+            "user={}\x01auth=Bearer {}\x01\x01",
+            self.user,
+            self.token.expose_secret()
+        );
+
+        Box::new(OAuthClient {
+            mechanism: "XOAUTH2",
+            initial_response: Some(response.into_bytes()),
+            error_response: b"",
+            error: None,
+        })
+    }
+}
+
+impl SaslOAuthBearer {
+    /// Returns the client side of the mechanism.
+    pub fn client(self) -> Box<dyn SaslClient> {
+        // the GS2 header escapes `,` and `=` in the authorization
+        // identity (RFC 5801)
+        let user = self.user.replace('=', "=3D").replace(',', "=2C");
+        let response = format!(
+            "n,a={user},\x01auth=Bearer {}\x01\x01",
+            self.token.expose_secret()
+        );
+
+        Box::new(OAuthClient {
+            mechanism: "OAUTHBEARER",
+            initial_response: Some(response.into_bytes()),
+            error_response: b"\x01",
+            error: None,
+        })
+    }
+}
+
+impl SaslScram {
+    /// Returns the client side of the strongest SCRAM variant among
+    /// the mechanisms offered by the server.
+    ///
+    /// With a channel binding, `-PLUS` variants are preferred, and
+    /// the server is told when none is offered so that it can detect
+    /// downgrade attacks. SCRAM-SHA-256 is used when the server does
+    /// not advertise its mechanisms.
+    ///
+    /// The user name and the password are prepared with SASLprep
+    /// (RFC 4013).
+    pub fn client(
+        self,
+        offered: &[String],
+        channel_binding: Option<SaslChannelBinding>,
+    ) -> Result<Box<dyn SaslClient>> {
+        let is_offered = |name: &str| offered.iter().any(|m| m.eq_ignore_ascii_case(name));
+        let plus_offered = SCRAM_MECHANISMS
+            .iter()
+            .any(|(name, _, plus)| *plus && is_offered(name));
+
+        let variant = SCRAM_MECHANISMS
+            .into_iter()
+            .filter(|(name, _, plus)| is_offered(name) && (!plus || channel_binding.is_some()))
+            // -PLUS variants first, then the strongest hash
+            .min_by_key(|(_, _, plus)| !plus);
+
+        let (mechanism, hash, plus) = match variant {
+            Some(variant) => variant,
+            None if offered.is_empty() => SCRAM_MECHANISMS[1],
+            None => bail!("no SCRAM mechanism offered by the server"),
+        };
+
+        debug!("using SASL {mechanism}");
+
+        // tells the server whether channel binding was skipped
+        // because it does not support it, so that it can detect
+        // downgrade attacks
+        let (cbind_flag, cbind_data) = match channel_binding {
+            Some(binding) if plus => (format!("p={}", binding.name), binding.data),
+            Some(_) if !offered.is_empty() && !plus_offered => ("y".to_owned(), Vec::new()),
+            _ => ("n".to_owned(), Vec::new()),
+        };
+
+        let mut nonce = [0; 24];
+        getrandom::getrandom(&mut nonce).context("Generate SCRAM nonce error")?;
+        let nonce = BASE64_STANDARD.encode(nonce);
+
+        let client = self.scram_client(mechanism, hash, cbind_flag, cbind_data, nonce)?;
+        Ok(Box::new(client))
+    }
+
+    fn scram_client(
+        self,
+        mechanism: &'static str,
+        hash: ScramHash,
+        cbind_flag: String,
+        cbind_data: Vec<u8>,
+        nonce: String,
+    ) -> Result<ScramClient> {
+        let gs2_header = match &self.authzid {
+            Some(authzid) => format!("{cbind_flag},a={},", scram_name(authzid)),
+            None => format!("{cbind_flag},,"),
+        };
+
+        let username = saslprep(&self.username).context("Prepare SCRAM user name error")?;
+        let password = saslprep(self.password.expose_secret())
+            .context("Prepare SCRAM password error")?
+            .into_owned()
+            .into();
+
+        let client_first_bare = format!("n={},r={nonce}", scram_name(&username));
+
+        Ok(ScramClient {
+            mechanism,
+            hash,
+            password,
+            gs2_header,
+            cbind_data,
+            nonce,
+            client_first_bare,
+            server_signature: None,
+            verified: false,
+        })
+    }
+}
+
+/// Escapes a user name for SCRAM (RFC 5802 §5.1).
+fn scram_name(name: &str) -> String {
+    name.replace('=', "=3D").replace(',', "=2C")
+}
+
+#[derive(Clone, Copy, Debug)]
+enum ScramHash {
+    Sha1,
+    Sha256,
+}
+
+impl ScramHash {
+    fn hash(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha1 => Sha1::digest(data).to_vec(),
+            Self::Sha256 => Sha256::digest(data).to_vec(),
+        }
+    }
+
+    fn hmac(self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::Sha1 => {
+                let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC accepts any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+            Self::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key");
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+
+    fn salted_password(self, password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+        match self {
+            Self::Sha1 => {
+                pbkdf2::pbkdf2_hmac_array::<Sha1, 20>(password, salt, iterations).to_vec()
+            }
+            Self::Sha256 => {
+                pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password, salt, iterations).to_vec()
+            }
+        }
+    }
+}
+
+struct ScramClient {
+    mechanism: &'static str,
+    hash: ScramHash,
+    password: SecretString,
+    gs2_header: String,
+    cbind_data: Vec<u8>,
+    nonce: String,
+    client_first_bare: String,
+    /// Signature expected from the server, once the client final
+    /// message is sent.
+    server_signature: Option<Vec<u8>>,
+    verified: bool,
+}
+
+impl ScramClient {
+    fn client_final(&mut self, server_first: &str) -> Result<Vec<u8>> {
+        let mut nonce = None;
+        let mut salt = None;
+        let mut iterations = None;
+
+        for attr in server_first.split(',') {
+            match attr.split_once('=') {
+                Some(("r", value)) => nonce = Some(value),
+                Some(("s", value)) => salt = Some(value),
+                Some(("i", value)) => iterations = Some(value),
+                Some(("m", _)) => bail!("unsupported SCRAM extension"),
+                Some(("e", err)) => bail!("SCRAM error: {err}"),
+                _ => (),
+            }
+        }
+
+        let (Some(nonce), Some(salt), Some(iterations)) = (nonce, salt, iterations) else {
+            bail!("invalid SCRAM server first message");
+        };
+
+        if !nonce.starts_with(&self.nonce) || nonce.len() == self.nonce.len() {
+            bail!("invalid SCRAM server nonce");
+        }
+
+        let salt = BASE64_STANDARD.decode(salt).context("Invalid SCRAM salt")?;
+        let iterations: u32 = iterations
+            .parse()
+            .context("Invalid SCRAM iteration count")?;
+
+        if iterations == 0 {
+            bail!("invalid SCRAM iteration count 0");
+        }
+
+        let mut cbind_input = self.gs2_header.clone().into_bytes();
+        cbind_input.extend(&self.cbind_data);
+
+        let client_final_without_proof =
+            format!("c={},r={nonce}", BASE64_STANDARD.encode(cbind_input));
+
+        let auth_message = format!(
+            "{},{server_first},{client_final_without_proof}",
+            self.client_first_bare
+        );
+
+        let hash = self.hash;
+        let password = self.password.expose_secret().as_bytes();
+        let salted_password = hash.salted_password(password, &salt, iterations);
+        let client_key = hash.hmac(&salted_password, b"Client Key");
+        let stored_key = hash.hash(&client_key);
+        let client_signature = hash.hmac(&stored_key, auth_message.as_bytes());
+        let server_key = hash.hmac(&salted_password, b"Server Key");
+
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(client_signature)
+            .map(|(key, signature)| key ^ signature)
+            .collect();
+
+        self.server_signature = Some(hash.hmac(&server_key, auth_message.as_bytes()));
+
+        let client_final = format!(
+            "{client_final_without_proof},p={}",
+            BASE64_STANDARD.encode(proof)
+        );
+
+        Ok(client_final.into_bytes())
+    }
+
+    fn verify_server_final(&mut self, expected: &[u8], server_final: &str) -> Result<Vec<u8>> {
+        if let Some(err) = server_final.strip_prefix("e=") {
+            bail!("SCRAM error: {err}");
+        }
+
+        let Some(signature) = server_final.strip_prefix("v=") else {
+            bail!("invalid SCRAM server final message");
+        };
+
+        let signature = BASE64_STANDARD
+            .decode(signature)
+            .context("Invalid SCRAM server signature")?;
+
+        if signature != expected {
+            bail!("invalid SCRAM server signature: the server does not know the password");
+        }
+
+        self.verified = true;
+        Ok(Vec::new())
+    }
+}
+
+impl SaslClient for ScramClient {
+    fn mechanism(&self) -> &'static str {
+        self.mechanism
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        let first = format!("{}{}", self.gs2_header, self.client_first_bare);
+        Some(first.into_bytes())
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let challenge = std::str::from_utf8(challenge).context("Invalid SCRAM challenge")?;
+
+        match self.server_signature.clone() {
+            None => self.client_final(challenge),
+            Some(expected) => self.verify_server_final(&expected, challenge),
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        if !self.verified {
+            bail!("SCRAM server signature not received: the server was not authenticated");
+        }
+
+        Ok(())
+    }
+}
+
+/// Client of the mechanisms made of a single initial response, to
+/// which the server does not send any challenge.
+struct SingleResponseClient {
+    mechanism: &'static str,
+    initial_response: Option<Vec<u8>>,
+}
+
+impl SaslClient for SingleResponseClient {
+    fn mechanism(&self) -> &'static str {
+        self.mechanism
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        self.initial_response.take()
+    }
+
+    fn respond(&mut self, _challenge: &[u8]) -> Result<Vec<u8>> {
+        bail!("unexpected SASL {} challenge", self.mechanism)
+    }
+}
+
+struct CramMd5Client {
+    username: String,
+    password: SecretString,
+}
+
+impl SaslClient for CramMd5Client {
+    fn mechanism(&self) -> &'static str {
+        "CRAM-MD5"
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        let key = self.password.expose_secret().as_bytes();
+        let mut mac = Hmac::<Md5>::new_from_slice(key).expect("HMAC accepts any key");
+        mac.update(challenge);
+
+        let digest: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+
+        Ok(format!("{} {digest}", self.username).into_bytes())
+    }
+}
+
+/// Client of the OAuth 2.0 mechanisms: the server answers the
+/// initial response with a challenge only on failure, holding a JSON
+/// error status, to which the client answers with a dummy response
+/// before receiving the final failure.
+struct OAuthClient {
+    mechanism: &'static str,
+    initial_response: Option<Vec<u8>>,
+    error_response: &'static [u8],
+    error: Option<String>,
+}
+
+impl SaslClient for OAuthClient {
+    fn mechanism(&self) -> &'static str {
+        self.mechanism
+    }
+
+    fn initial_response(&mut self) -> Option<Vec<u8>> {
+        self.initial_response.take()
+    }
+
+    fn respond(&mut self, challenge: &[u8]) -> Result<Vec<u8>> {
+        self.error = Some(parse_oauth_error(challenge));
+        Ok(self.error_response.to_vec())
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.error.clone()
+    }
+}
+
+/// Formats the JSON error status sent by the server (RFC 7628
+/// §3.2.2), falling back to the raw challenge.
+fn parse_oauth_error(challenge: &[u8]) -> String {
+    let Ok(serde_json::Value::Object(error)) = serde_json::from_slice(challenge) else {
+        return String::from_utf8_lossy(challenge).into_owned();
+    };
+
+    let mut details = Vec::new();
+
+    for key in ["status", "scope", "schemes", "openid-configuration"] {
+        match error.get(key) {
+            Some(serde_json::Value::String(value)) => details.push(format!("{key}: {value}")),
+            Some(value) if !value.is_null() => details.push(format!("{key}: {value}")),
+            _ => (),
+        }
+    }
+
+    format!("OAuth error ({})", details.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Sasl, SaslChannelBinding, SaslClient, SaslCramMd5, SaslMechanism, SaslPlain, SaslScram,
+        ScramHash,
+    };
+
+    fn offered(mechanisms: &[&str]) -> Vec<String> {
+        mechanisms.iter().map(|m| m.to_string()).collect()
+    }
+
+    fn plain() -> SaslPlain {
+        SaslPlain {
+            authzid: None,
+            authcid: "tim".into(),
+            passwd: "tanstaaftanstaaf".to_owned().into(),
+        }
+    }
+
+    fn cram_md5() -> SaslCramMd5 {
+        SaslCramMd5 {
+            username: "tim".into(),
+            password: "tanstaaftanstaaf".to_owned().into(),
+        }
+    }
+
+    #[test]
+    fn select_offered_mechanism() {
+        let sasl = Sasl {
+            plain: Some(plain()),
+            cram_md5: Some(cram_md5()),
+            ..Default::default()
+        };
+
+        let mechanism = sasl.select_mechanism(&offered(&["CRAM-MD5"])).unwrap();
+        assert_eq!(mechanism, SaslMechanism::CramMd5);
+
+        let mechanism = sasl
+            .select_mechanism(&offered(&["cram-md5", "plain"]))
+            .unwrap();
+        assert_eq!(mechanism, SaslMechanism::Plain);
+
+        assert!(sasl.select_mechanism(&offered(&["GSSAPI"])).is_err());
+    }
+
+    #[test]
+    fn select_weak_mechanism_last() {
+        let sasl = Sasl {
+            cram_md5: Some(cram_md5()),
+            anonymous: Some(Default::default()),
+            plain: Some(plain()),
+            ..Default::default()
+        };
+
+        let all = offered(&["ANONYMOUS", "CRAM-MD5", "PLAIN"]);
+        assert_eq!(sasl.select_mechanism(&all).unwrap(), SaslMechanism::Plain);
+
+        let weak = offered(&["ANONYMOUS", "CRAM-MD5"]);
+        assert_eq!(
+            sasl.select_mechanism(&weak).unwrap(),
+            SaslMechanism::CramMd5
+        );
+
+        let anonymous = offered(&["ANONYMOUS"]);
+        assert_eq!(
+            sasl.select_mechanism(&anonymous).unwrap(),
+            SaslMechanism::Anonymous
+        );
+    }
+
+    #[test]
+    fn select_explicit_mechanism() {
+        let sasl = Sasl {
+            mechanism: Some(SaslMechanism::CramMd5),
+            plain: Some(plain()),
+            cram_md5: Some(cram_md5()),
+            ..Default::default()
+        };
+
+        let mechanism = sasl
+            .select_mechanism(&offered(&["PLAIN", "CRAM-MD5"]))
+            .unwrap();
+        assert_eq!(mechanism, SaslMechanism::CramMd5);
+
+        assert!(sasl.select_mechanism(&offered(&["PLAIN"])).is_err());
+        assert!(sasl.select_mechanism(&[]).is_ok());
+    }
+
+    #[test]
+    fn select_external_when_offered() {
+        let sasl = Sasl {
+            external: Some(Default::default()),
+            plain: Some(plain()),
+            ..Default::default()
+        };
+
+        let mechanism = sasl
+            .select_mechanism(&offered(&["PLAIN", "EXTERNAL"]))
+            .unwrap();
+        assert_eq!(mechanism, SaslMechanism::External);
+
+        let mechanism = sasl.select_mechanism(&offered(&["PLAIN"])).unwrap();
+        assert_eq!(mechanism, SaslMechanism::Plain);
+
+        let mechanism = sasl.select_mechanism(&[]).unwrap();
+        assert_eq!(mechanism, SaslMechanism::Plain);
+
+        let sasl = Sasl {
+            external: Some(Default::default()),
+            ..Default::default()
+        };
+
+        assert!(sasl.select_mechanism(&offered(&["PLAIN"])).is_err());
+    }
+
+    #[test]
+    fn select_without_configuration() {
+        let err = Sasl::default().select_mechanism(&offered(&["PLAIN"]));
+        assert!(err.is_err());
+    }
+
+    fn scram(username: &str, password: &str) -> SaslScram {
+        SaslScram {
+            authzid: None,
+            username: username.into(),
+            password: password.to_owned().into(),
+        }
+    }
+
+    fn binding() -> Option<SaslChannelBinding> {
+        Some(SaslChannelBinding {
+            name: "tls-exporter",
+            data: vec![1, 2, 3],
+        })
+    }
+
+    fn initial_response(mut client: Box<dyn SaslClient>) -> String {
+        String::from_utf8(client.initial_response().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn scram_channel_binding_flag() {
+        let offered = offered(&["SCRAM-SHA-1-PLUS", "SCRAM-SHA-256"]);
+        let client = scram("user", "pencil").client(&offered, binding()).unwrap();
+        assert_eq!(client.mechanism(), "SCRAM-SHA-1-PLUS");
+        assert!(initial_response(client).starts_with("p=tls-exporter,,n=user,r="));
+
+        let client = scram("user", "pencil").client(&offered, None).unwrap();
+        assert_eq!(client.mechanism(), "SCRAM-SHA-256");
+        assert!(initial_response(client).starts_with("n,,n=user,r="));
+
+        let offered = self::offered(&["SCRAM-SHA-256", "SCRAM-SHA-1"]);
+        let client = scram("user", "pencil").client(&offered, binding()).unwrap();
+        assert_eq!(client.mechanism(), "SCRAM-SHA-256");
+        assert!(initial_response(client).starts_with("y,,n=user,r="));
+
+        let client = scram("user", "pencil").client(&[], binding()).unwrap();
+        assert_eq!(client.mechanism(), "SCRAM-SHA-256");
+        assert!(initial_response(client).starts_with("n,,n=user,r="));
+    }
+
+    /// Runs a SCRAM exchange with a fixed client nonce.
+    fn scram_exchange(
+        hash: ScramHash,
+        nonce: &str,
+        server_first: &str,
+        client_final: &str,
+        server_final: &str,
+    ) {
+        let mut client = scram("user", "pencil")
+            .scram_client("SCRAM", hash, "n".into(), Vec::new(), nonce.into())
+            .unwrap();
+
+        let client_first = client.initial_response().unwrap();
+        assert_eq!(client_first, format!("n,,n=user,r={nonce}").into_bytes());
+
+        let response = client.respond(server_first.as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(response).unwrap(), client_final);
+
+        assert!(client.finish().is_err());
+        client.respond(server_final.as_bytes()).unwrap();
+        client.finish().unwrap();
+    }
+
+    #[test]
+    fn scram_sha1_rfc5802() {
+        scram_exchange(
+            ScramHash::Sha1,
+            "fyko+d2lbbFgONRv9qkxdawL",
+            "r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,s=QSXCR+Q6sek8bf92,i=4096",
+            "c=biws,r=fyko+d2lbbFgONRv9qkxdawL3rfcNHYJY1ZVvWVs7j,p=v0X8v3Bz2T0CJGbJQyF0X+HI4Ts=",
+            "v=rmF9pqV8S7suAoZWja4dJRkFsKQ=",
+        );
+    }
+
+    #[test]
+    fn scram_sha256_rfc7677() {
+        scram_exchange(
+            ScramHash::Sha256,
+            "rOprNGfwEbeRWgbNEkqO",
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+            "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=",
+        );
+    }
+
+    #[test]
+    fn scram_rejects_wrong_server_signature() {
+        let mut client = scram("user", "pencil")
+            .scram_client(
+                "SCRAM",
+                ScramHash::Sha256,
+                "n".into(),
+                Vec::new(),
+                "rOprNGfwEbeRWgbNEkqO".into(),
+            )
+            .unwrap();
+
+        client.initial_response();
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        client.respond(server_first.as_bytes()).unwrap();
+        assert!(client.respond(b"v=rmF9pqV8S7suAoZWja4dJRkFsKQ=").is_err());
+        assert!(client.finish().is_err());
+    }
+
+    #[test]
+    fn scram_saslprep() {
+        // soft hyphens are mapped to nothing
+        let client = scram("us\u{AD}er", "pencil").client(&[], None).unwrap();
+        assert!(initial_response(client).starts_with("n,,n=user,r="));
+
+        // prohibited control character
+        assert!(scram("user\u{7}", "pencil").client(&[], None).is_err());
+    }
+
+    #[test]
+    fn cram_md5_rfc2195() {
+        let mut client = cram_md5().client();
+        assert_eq!(client.mechanism(), "CRAM-MD5");
+        assert_eq!(client.initial_response(), None);
+
+        let challenge = b"<1896.697170952@postoffice.reston.mci.net>";
+        let response = client.respond(challenge).unwrap();
+        assert_eq!(response, b"tim b913a602c7eda7a495b4e6e7334d3890");
+    }
+}
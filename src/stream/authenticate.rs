@@ -0,0 +1,398 @@
+//! Sans-I/O SASL helpers shared by the blocking and async sessions:
+//! mechanism selection, parsing of the advertised mechanisms, and
+//! coroutines driving SASL exchanges the I/O crates do not support,
+//! for any [`SaslClient`].
+
+use anyhow::{anyhow, bail, Error, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use log::debug;
+
+use crate::sasl::{Sasl, SaslChannelBinding, SaslClient, SaslLogin, SaslMechanism, SaslPlain};
+
+/// Tag of the IMAP `AUTHENTICATE` command.
+#[cfg(feature = "imap")]
+const IMAP_TAG: &str = "auth";
+
+/// SASL authentication picked for a session, with its
+/// configuration.
+pub(crate) enum SaslAuth {
+    /// LOGIN, driven by the coroutines of the I/O crate.
+    Login(SaslLogin),
+    /// PLAIN, driven by the coroutines of the I/O crate.
+    Plain(SaslPlain),
+    /// Any other mechanism, driven by [`ImapAuthenticate`] or
+    /// [`SmtpAuthenticate`].
+    Client(Box<dyn SaslClient>),
+}
+
+impl SaslAuth {
+    /// Picks the mechanism to authenticate with (see
+    /// [`Sasl::select_mechanism`]), then takes its configuration out
+    /// of `sasl`.
+    ///
+    /// `offered` are the mechanisms advertised by the server, and
+    /// `channel_binding` is only called for mechanisms that can bind
+    /// to the TLS channel.
+    pub(crate) fn new(
+        mut sasl: Sasl,
+        offered: &[String],
+        channel_binding: impl FnOnce() -> Result<Option<SaslChannelBinding>>,
+    ) -> Result<Self> {
+        let auth = match sasl.select_mechanism(offered)? {
+            SaslMechanism::Login => {
+                let Some(auth) = sasl.login.take() else {
+                    bail!("missing SASL LOGIN configuration");
+                };
+
+                Self::Login(auth)
+            }
+            SaslMechanism::Plain => {
+                let Some(auth) = sasl.plain.take() else {
+                    bail!("missing SASL PLAIN configuration");
+                };
+
+                Self::Plain(auth)
+            }
+            SaslMechanism::XOAuth2 => {
+                let Some(auth) = sasl.xoauth2.take() else {
+                    bail!("missing SASL XOAUTH2 configuration");
+                };
+
+                Self::Client(auth.client())
+            }
+            SaslMechanism::OAuthBearer => {
+                let Some(auth) = sasl.oauthbearer.take() else {
+                    bail!("missing SASL OAUTHBEARER configuration");
+                };
+
+                Self::Client(auth.client())
+            }
+            SaslMechanism::Scram => {
+                let Some(auth) = sasl.scram.take() else {
+                    bail!("missing SASL SCRAM configuration");
+                };
+
+                Self::Client(auth.client(offered, channel_binding()?)?)
+            }
+            SaslMechanism::External => {
+                // the identity comes from the TLS client certificate
+                let auth = sasl.external.take().unwrap_or_default();
+                Self::Client(auth.client())
+            }
+            SaslMechanism::CramMd5 => {
+                let Some(auth) = sasl.cram_md5.take() else {
+                    bail!("missing SASL CRAM-MD5 configuration");
+                };
+
+                Self::Client(auth.client())
+            }
+            SaslMechanism::Anonymous => {
+                // no configuration is needed to access anonymously
+                let auth = sasl.anonymous.take().unwrap_or_default();
+                Self::Client(auth.client()?)
+            }
+        };
+
+        Ok(auth)
+    }
+}
+
+pub(crate) enum AuthenticateResult {
+    Ok,
+    WantsRead,
+    WantsWrite(Vec<u8>),
+    Err(Error),
+}
+
+/// IMAP `AUTHENTICATE` command (RFC 3501, RFC 4959).
+#[cfg(feature = "imap")]
+pub(crate) struct ImapAuthenticate {
+    client: Box<dyn SaslClient>,
+    /// Initial response waiting for the first continuation request,
+    /// when the server does not support `SASL-IR`.
+    initial_response: Option<Vec<u8>>,
+    command: Option<Vec<u8>>,
+}
+
+#[cfg(feature = "imap")]
+impl ImapAuthenticate {
+    pub(crate) fn new(mut client: Box<dyn SaslClient>, ir: bool) -> Self {
+        let mechanism = client.mechanism();
+        let initial_response = client.initial_response();
+        debug!("authenticating using SASL {mechanism}");
+
+        let command = match initial_response.as_deref() {
+            Some(response) if ir => {
+                format!(
+                    "{IMAP_TAG} AUTHENTICATE {mechanism} {}\r\n",
+                    encode(response)
+                )
+            }
+            _ => format!("{IMAP_TAG} AUTHENTICATE {mechanism}\r\n"),
+        };
+
+        Self {
+            client,
+            initial_response: initial_response.filter(|_| !ir),
+            command: Some(command.into_bytes()),
+        }
+    }
+
+    pub(crate) fn resume(&mut self, arg: Option<&[u8]>) -> AuthenticateResult {
+        if let Some(command) = self.command.take() {
+            return AuthenticateResult::WantsWrite(command);
+        }
+
+        let Some(line) = arg else {
+            return AuthenticateResult::WantsRead;
+        };
+
+        if line.is_empty() {
+            return AuthenticateResult::Err(anyhow!("connection closed during authentication"));
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end();
+
+        if let Some(challenge) = line.strip_prefix('+') {
+            return self.respond(challenge.trim_start());
+        }
+
+        let Some(status) = line
+            .strip_prefix(IMAP_TAG)
+            .and_then(|l| l.strip_prefix(' '))
+        else {
+            // untagged responses, like capabilities
+            return AuthenticateResult::WantsRead;
+        };
+
+        let (status, text) = status.split_once(' ').unwrap_or((status, ""));
+
+        if status.eq_ignore_ascii_case("OK") {
+            return match self.client.finish() {
+                Ok(()) => AuthenticateResult::Ok,
+                Err(err) => AuthenticateResult::Err(err),
+            };
+        }
+
+        AuthenticateResult::Err(failure(self.client.as_ref(), "IMAP AUTHENTICATE", text))
+    }
+
+    fn respond(&mut self, challenge: &str) -> AuthenticateResult {
+        let response = match self.initial_response.take() {
+            Some(response) => Ok(response),
+            None => decode(challenge).and_then(|challenge| self.client.respond(&challenge)),
+        };
+
+        match response {
+            Ok(response) => {
+                let response = format!("{}\r\n", encode_continuation(&response));
+                AuthenticateResult::WantsWrite(response.into_bytes())
+            }
+            Err(err) => AuthenticateResult::Err(err),
+        }
+    }
+}
+
+/// SMTP `AUTH` command (RFC 4954).
+#[cfg(feature = "smtp")]
+pub(crate) struct SmtpAuthenticate {
+    client: Box<dyn SaslClient>,
+    command: Option<Vec<u8>>,
+    /// Text of the lines of a multi-line reply read so far.
+    text: Vec<String>,
+}
+
+#[cfg(feature = "smtp")]
+impl SmtpAuthenticate {
+    pub(crate) fn new(mut client: Box<dyn SaslClient>) -> Self {
+        let mechanism = client.mechanism();
+        debug!("authenticating using SASL {mechanism}");
+
+        let command = match client.initial_response() {
+            Some(response) => format!("AUTH {mechanism} {}\r\n", encode(&response)),
+            None => format!("AUTH {mechanism}\r\n"),
+        };
+
+        Self {
+            client,
+            command: Some(command.into_bytes()),
+            text: Vec::new(),
+        }
+    }
+
+    pub(crate) fn resume(&mut self, arg: Option<&[u8]>) -> AuthenticateResult {
+        if let Some(command) = self.command.take() {
+            return AuthenticateResult::WantsWrite(command);
+        }
+
+        let Some(line) = arg else {
+            return AuthenticateResult::WantsRead;
+        };
+
+        if line.is_empty() {
+            return AuthenticateResult::Err(anyhow!("connection closed during authentication"));
+        }
+
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end();
+
+        let (Some(code), sep) = (line.get(..3), line.get(3..4).unwrap_or(" ")) else {
+            return AuthenticateResult::Err(anyhow!("invalid SMTP reply: {line}"));
+        };
+
+        self.text.push(line.get(4..).unwrap_or_default().to_owned());
+
+        if sep == "-" {
+            return AuthenticateResult::WantsRead;
+        }
+
+        let text = self.text.join(" ");
+        self.text.clear();
+
+        match code {
+            "235" => match self.client.finish() {
+                Ok(()) => AuthenticateResult::Ok,
+                Err(err) => AuthenticateResult::Err(err),
+            },
+            "334" => {
+                let response = decode(&text).and_then(|challenge| self.client.respond(&challenge));
+
+                match response {
+                    Ok(response) => {
+                        let response = format!("{}\r\n", encode_continuation(&response));
+                        AuthenticateResult::WantsWrite(response.into_bytes())
+                    }
+                    Err(err) => AuthenticateResult::Err(err),
+                }
+            }
+            code => {
+                let text = format!("{code} {text}");
+                AuthenticateResult::Err(failure(self.client.as_ref(), "SMTP AUTH", &text))
+            }
+        }
+    }
+}
+
+/// Returns the SASL mechanisms advertised by the `AUTH=` IMAP
+/// capabilities, uppercased.
+///
+/// `LOGIN` stands for the `LOGIN` command, available unless the
+/// server advertises `LOGINDISABLED`.
+#[cfg(feature = "imap")]
+pub(crate) fn imap_auth_mechanisms(
+    capabilities: impl IntoIterator<Item = impl ToString>,
+) -> Vec<String> {
+    let mut login = true;
+    let mut mechanisms = Vec::new();
+
+    for capability in capabilities {
+        let capability = capability.to_string().to_ascii_uppercase();
+
+        if capability == "LOGINDISABLED" {
+            login = false;
+        } else if let Some(mechanism) = capability.strip_prefix("AUTH=") {
+            mechanisms.push(mechanism.to_owned());
+        }
+    }
+
+    if login && !mechanisms.iter().any(|m| m == "LOGIN") {
+        mechanisms.push("LOGIN".to_owned());
+    }
+
+    mechanisms
+}
+
+/// Returns the SASL mechanisms advertised by an `AUTH` line of an
+/// SMTP `EHLO` reply, uppercased, or `None` for other lines.
+///
+/// Some servers still advertise them with the obsolete `AUTH=` form.
+#[cfg(feature = "smtp")]
+pub(crate) fn smtp_auth_mechanisms(line: &str) -> Option<Vec<String>> {
+    let line = line.trim_end();
+    let keyword = line.get(4..)?;
+
+    if !line.starts_with("250") {
+        return None;
+    }
+
+    let (name, params) = keyword.split_once([' ', '=']).unwrap_or((keyword, ""));
+
+    if !name.eq_ignore_ascii_case("AUTH") {
+        return None;
+    }
+
+    let mechanisms = params
+        .split_whitespace()
+        .map(|mechanism| mechanism.to_ascii_uppercase())
+        .collect();
+
+    Some(mechanisms)
+}
+
+/// Returns the SASL mechanisms advertised by the `AUTH` lines of a
+/// chunk of an SMTP `EHLO` reply, if any.
+#[cfg(feature = "smtp")]
+pub(crate) fn smtp_ehlo_mechanisms(bytes: &[u8]) -> Option<Vec<String>> {
+    String::from_utf8_lossy(bytes)
+        .lines()
+        .rev()
+        .find_map(smtp_auth_mechanisms)
+}
+
+fn failure(client: &dyn SaslClient, command: &str, text: &str) -> Error {
+    let mechanism = client.mechanism();
+
+    match client.failure() {
+        Some(reason) => anyhow!("{command} {mechanism} failed: {text}: {reason}"),
+        None => anyhow!("{command} {mechanism} failed: {text}"),
+    }
+}
+
+fn encode(response: &[u8]) -> String {
+    // an empty initial response is sent as `=` (RFC 4959, RFC 4954)
+    if response.is_empty() {
+        "=".into()
+    } else {
+        BASE64_STANDARD.encode(response)
+    }
+}
+
+fn encode_continuation(response: &[u8]) -> String {
+    BASE64_STANDARD.encode(response)
+}
+
+fn decode(challenge: &str) -> Result<Vec<u8>> {
+    match BASE64_STANDARD.decode(challenge.trim()) {
+        Ok(challenge) => Ok(challenge),
+        Err(err) => bail!("invalid base64 SASL challenge: {err}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "imap")]
+    #[test]
+    fn imap_mechanisms() {
+        use super::imap_auth_mechanisms;
+
+        let mechanisms =
+            imap_auth_mechanisms(["IMAP4rev1", "SASL-IR", "auth=plain", "AUTH=CRAM-MD5"]);
+        assert_eq!(mechanisms, ["PLAIN", "CRAM-MD5", "LOGIN"]);
+
+        let mechanisms = imap_auth_mechanisms(["IMAP4rev1", "STARTTLS", "LOGINDISABLED"]);
+        assert!(mechanisms.is_empty());
+    }
+
+    #[cfg(feature = "smtp")]
+    #[test]
+    fn smtp_mechanisms() {
+        use super::smtp_ehlo_mechanisms;
+
+        let ehlo = b"250-mail.example.com\r\n250-AUTH=LOGIN\r\n250-AUTH plain cram-md5\r\n250 8BITMIME\r\n";
+        let mechanisms = smtp_ehlo_mechanisms(ehlo).unwrap();
+        assert_eq!(mechanisms, ["PLAIN", "CRAM-MD5"]);
+
+        assert!(smtp_ehlo_mechanisms(b"250-mail.example.com\r\n250 8BITMIME\r\n").is_none());
+    }
+}
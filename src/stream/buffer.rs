@@ -0,0 +1,325 @@
+use std::io::{self, Read};
+
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const DEFAULT_CAPACITY: usize = 8 * 1024;
+const MIN_READ_SIZE: usize = 4 * 1024;
+
+/// Default maximum size of a frame, see
+/// [`ReadBuffer::with_max_frame_size`].
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// How the bytes of a [`ReadBuffer`] are split before being fed to
+/// coroutines.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReadFraming {
+    /// Everything available is fed at once.
+    None,
+    /// Complete CRLF-terminated lines are fed one by one, so that a
+    /// coroutine never sees bytes following its final response line
+    /// (SMTP).
+    Lines,
+    /// Like [`ReadFraming::Lines`], except that a line announcing a
+    /// literal (`{N}\r\n`) is fed together with the N bytes of the
+    /// literal (IMAP).
+    Imap,
+}
+
+/// Growable read buffer owned by a session.
+///
+/// Bytes read from the stream but not fed to a coroutine yet are
+/// kept in the buffer, so that they can be fed to the next coroutine
+/// (pipelined responses, greeting merged with the capability line…).
+/// The buffer grows to hold frames larger than its capacity, like
+/// big IMAP literals, up to a maximum frame size.
+#[derive(Clone, Debug)]
+pub struct ReadBuffer {
+    framing: ReadFraming,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    max_frame_size: usize,
+}
+
+impl Default for ReadBuffer {
+    fn default() -> Self {
+        Self::new(ReadFraming::None)
+    }
+}
+
+impl ReadBuffer {
+    pub fn new(framing: ReadFraming) -> Self {
+        Self::with_capacity(framing, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(framing: ReadFraming, capacity: usize) -> Self {
+        Self {
+            framing,
+            buf: vec![0; capacity.max(MIN_READ_SIZE)],
+            start: 0,
+            end: 0,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Sets the maximum size of a frame, [`DEFAULT_MAX_FRAME_SIZE`]
+    /// by default.
+    ///
+    /// Reading a frame announced or growing larger fails with
+    /// [`io::ErrorKind::InvalidData`], instead of letting the server
+    /// make the buffer grow without bounds.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Returns the bytes read but not consumed yet.
+    pub fn pending(&self) -> &[u8] {
+        &self.buf[self.start..self.end]
+    }
+
+    /// Returns the next frame to feed a coroutine with, reading from
+    /// the stream only when no complete frame is buffered.
+    ///
+    /// An empty frame means that the stream reached its end.
+    pub fn read<R: Read>(&mut self, stream: &mut R) -> io::Result<&[u8]> {
+        loop {
+            if let Some(len) = self.next_frame_len()? {
+                return Ok(self.consume(len));
+            }
+
+            self.reserve()?;
+
+            let n = stream.read(&mut self.buf[self.end..])?;
+
+            if n == 0 {
+                let len = self.end - self.start;
+                return Ok(self.consume(len));
+            }
+
+            self.end += n;
+        }
+    }
+
+    /// Async variant of [`ReadBuffer::read`].
+    #[cfg(feature = "tokio")]
+    pub async fn read_async<R: AsyncRead + Unpin>(&mut self, stream: &mut R) -> io::Result<&[u8]> {
+        loop {
+            if let Some(len) = self.next_frame_len()? {
+                return Ok(self.consume(len));
+            }
+
+            self.reserve()?;
+
+            let n = stream.read(&mut self.buf[self.end..]).await?;
+
+            if n == 0 {
+                let len = self.end - self.start;
+                return Ok(self.consume(len));
+            }
+
+            self.end += n;
+        }
+    }
+
+    fn consume(&mut self, len: usize) -> &[u8] {
+        let frame = &self.buf[self.start..self.start + len];
+        self.start += len;
+        frame
+    }
+
+    /// Makes room for at least [`MIN_READ_SIZE`] more bytes, by
+    /// moving pending bytes to the front of the buffer first, then by
+    /// growing it.
+    fn reserve(&mut self) -> io::Result<()> {
+        if self.start > 0 {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+        }
+
+        // no complete frame is pending, so the next one is at least
+        // as long as the pending bytes
+        let wanted = match self.framing {
+            ReadFraming::Imap => self.pending_literal_len()?.unwrap_or(self.end),
+            _ => self.end,
+        };
+
+        self.check_frame_len(Some(wanted))?;
+
+        let Some(len) = wanted.max(self.end).checked_add(MIN_READ_SIZE) else {
+            return Err(too_large(wanted));
+        };
+
+        if len > self.buf.len() {
+            self.buf.resize(len, 0);
+        }
+
+        Ok(())
+    }
+
+    fn next_frame_len(&self) -> io::Result<Option<usize>> {
+        let pending = self.pending();
+
+        if pending.is_empty() {
+            return Ok(None);
+        }
+
+        match self.framing {
+            ReadFraming::None => Ok(Some(pending.len())),
+            ReadFraming::Lines => Ok(line_len(pending)),
+            ReadFraming::Imap => match self.pending_literal_len()? {
+                Some(len) if pending.len() >= len => Ok(Some(len)),
+                Some(_) => Ok(None),
+                None => Ok(line_len(pending)),
+            },
+        }
+    }
+
+    /// Returns the total length of the pending line and its literal,
+    /// if the pending line announces a literal.
+    fn pending_literal_len(&self) -> io::Result<Option<usize>> {
+        let pending = self.pending();
+
+        let Some(line) = line_len(pending) else {
+            return Ok(None);
+        };
+
+        match literal_len(&pending[..line])? {
+            Some(literal) => self.check_frame_len(line.checked_add(literal)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Fails when a frame length overflows or exceeds the maximum
+    /// frame size.
+    fn check_frame_len(&self, len: Option<usize>) -> io::Result<usize> {
+        match len {
+            Some(len) if len <= self.max_frame_size => Ok(len),
+            Some(len) => Err(too_large(len)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame length overflow",
+            )),
+        }
+    }
+}
+
+fn too_large(len: usize) -> io::Error {
+    let msg = format!("frame of {len} bytes exceeds the maximum frame size");
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn line_len(bytes: &[u8]) -> Option<usize> {
+    bytes.windows(2).position(|w| w == b"\r\n").map(|i| i + 2)
+}
+
+/// Parses the size of the literal announced at the end of an IMAP
+/// line (`{N}\r\n` or `{N+}\r\n`).
+fn literal_len(line: &[u8]) -> io::Result<Option<usize>> {
+    let Some(line) = line.strip_suffix(b"}\r\n") else {
+        return Ok(None);
+    };
+
+    let line = line.strip_suffix(b"+").unwrap_or(line);
+
+    let Some(open) = line.iter().rposition(|b| *b == b'{') else {
+        return Ok(None);
+    };
+
+    let digits = &line[open + 1..];
+
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return Ok(None);
+    }
+
+    // only digits are left, so parsing can only fail on overflow
+    match std::str::from_utf8(digits)
+        .ok()
+        .and_then(|n| n.parse().ok())
+    {
+        Some(len) => Ok(Some(len)),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "literal length overflow",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use super::{ReadBuffer, ReadFraming};
+
+    /// Reader returning one chunk per read.
+    struct Chunks(Vec<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+
+            let chunk = self.0.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn split_literal() {
+        let mut stream = Chunks(vec![
+            b"* 1 FETCH (BODY[] {11}\r\nhel",
+            b"lo world",
+            b")\r\nA1 OK done\r\n",
+        ]);
+        let mut buffer = ReadBuffer::new(ReadFraming::Imap);
+
+        let frame = buffer.read(&mut stream).unwrap();
+        assert_eq!(frame, b"* 1 FETCH (BODY[] {11}\r\nhello world");
+
+        let frame = buffer.read(&mut stream).unwrap();
+        assert_eq!(frame, b")\r\n");
+
+        let frame = buffer.read(&mut stream).unwrap();
+        assert_eq!(frame, b"A1 OK done\r\n");
+
+        let frame = buffer.read(&mut stream).unwrap();
+        assert!(frame.is_empty());
+    }
+
+    #[test]
+    fn oversized_literal() {
+        let mut stream = Chunks(vec![b"* 1 FETCH (BODY[] {1025}\r\n"]);
+        let mut buffer = ReadBuffer::new(ReadFraming::Imap).with_max_frame_size(1024);
+
+        let err = buffer.read(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn oversized_line() {
+        let mut stream = Chunks(vec![&[b'a'; 2048]]);
+        let mut buffer = ReadBuffer::new(ReadFraming::Lines).with_max_frame_size(1024);
+
+        let err = buffer.read(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn overflowing_literal() {
+        let mut stream = Chunks(vec![b"* 1 FETCH (BODY[] {18446744073709551615}\r\n"]);
+        let mut buffer = ReadBuffer::new(ReadFraming::Imap).with_max_frame_size(usize::MAX);
+
+        let err = buffer.read(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut stream = Chunks(vec![b"* 1 FETCH (BODY[] {99999999999999999999999}\r\n"]);
+        let mut buffer = ReadBuffer::new(ReadFraming::Imap).with_max_frame_size(usize::MAX);
+
+        let err = buffer.read(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    process::{self, Child, ChildStdin, ChildStdout, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use io_process::command::Command;
+use log::{debug, warn};
+use percent_encoding::percent_decode_str;
+use url::{Position, Url};
+
+/// Stream talking to a spawned program through its standard input
+/// and output, like OpenSSH's `ProxyCommand` or mutt's `tunnel`.
+///
+/// The standard error of the program is collected in the
+/// background, and appended to I/O errors so that failures of the
+/// program (SSH authentication, unknown host…) are not lost.
+pub struct CommandStream {
+    program: String,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    stderr: Arc<Mutex<Vec<u8>>>,
+    stderr_reader: Option<JoinHandle<()>>,
+}
+
+/// How long a command stream waits for its program to exit once it
+/// closed its output, so that its exit status and last error
+/// messages end up in the end of stream error.
+const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl CommandStream {
+    /// Spawns the given command.
+    pub fn spawn(cmd: Command) -> Result<Self> {
+        let (mut cmd, program) = stream_command(cmd);
+
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Spawn stream command {program} error"))?;
+
+        let (Some(stdin), Some(stdout), Some(mut child_stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            bail!("Pipe stream command {program} error");
+        };
+
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let stderr_ref = stderr.clone();
+
+        let stderr_reader = thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+
+            loop {
+                match child_stderr.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => match stderr_ref.lock() {
+                        Ok(mut stderr) => stderr.extend_from_slice(&buf[..n]),
+                        Err(_) => break,
+                    },
+                    Err(err) => {
+                        warn!("cannot read stream command stderr: {err}");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            program,
+            child,
+            stdin,
+            stdout,
+            stderr,
+            stderr_reader: Some(stderr_reader),
+        })
+    }
+
+    /// Spawns the given command line through the system shell.
+    ///
+    /// This is what `cmd:` URLs use, for example `cmd:ssh mailhost
+    /// /usr/lib/dovecot/imap`.
+    pub fn spawn_shell(line: &str) -> Result<Self> {
+        Self::spawn(shell_command(line))
+    }
+
+    /// Spawns the command line of a `cmd:` URL through the system
+    /// shell (see [`url_command_line`]).
+    pub fn spawn_url(url: &Url) -> Result<Self> {
+        Self::spawn_shell(&url_command_line(url)?)
+    }
+
+    /// Returns what the program wrote on its standard error so far.
+    pub fn stderr(&self) -> String {
+        stderr_string(&self.stderr)
+    }
+
+    fn error(&mut self, kind: io::ErrorKind, reason: impl fmt::Display) -> io::Error {
+        let status = self.child.try_wait().ok().flatten();
+        command_error(&self.program, status, &self.stderr(), kind, reason)
+    }
+
+    /// Waits a bit for the program to exit and for its standard error
+    /// to be fully collected, after it closed its output.
+    fn wait_exit(&mut self) {
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+
+        while Instant::now() < deadline {
+            let exited = matches!(self.child.try_wait(), Ok(Some(_)) | Err(_));
+            let collected = match &self.stderr_reader {
+                Some(reader) => reader.is_finished(),
+                None => true,
+            };
+
+            if exited && collected {
+                if let Some(reader) = self.stderr_reader.take() {
+                    let _ = reader.join();
+                }
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// Prepares the given command for a command stream, with all its
+/// standard streams piped. Returns the command and its program name.
+pub(crate) fn stream_command(cmd: Command) -> (process::Command, String) {
+    let mut cmd = process::Command::from(cmd);
+    let program = cmd.get_program().to_string_lossy().to_string();
+    debug!("spawning stream command {program}");
+
+    cmd.stdin(Stdio::piped());
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+
+    (cmd, program)
+}
+
+/// Builds the command running the given command line through the
+/// system shell.
+pub(crate) fn shell_command(line: &str) -> Command {
+    #[cfg(unix)]
+    let (shell, flag) = ("sh", "-c");
+    #[cfg(windows)]
+    let (shell, flag) = ("cmd", "/C");
+
+    let mut cmd = Command::new(shell);
+    cmd.arg(flag);
+    cmd.arg(line);
+    cmd
+}
+
+/// Returns the standard error collected from a stream command.
+pub(crate) fn stderr_string(stderr: &Mutex<Vec<u8>>) -> String {
+    match stderr.lock() {
+        Ok(stderr) => String::from_utf8_lossy(&stderr).trim().to_owned(),
+        Err(_) => String::new(),
+    }
+}
+
+/// Builds an I/O error of a stream command, mentioning its exit
+/// status and standard error when known.
+pub(crate) fn command_error(
+    program: &str,
+    status: Option<ExitStatus>,
+    stderr: &str,
+    kind: io::ErrorKind,
+    reason: impl fmt::Display,
+) -> io::Error {
+    let status = match status {
+        Some(status) => format!(" ({status})"),
+        None => String::new(),
+    };
+
+    let err = if stderr.is_empty() {
+        format!("stream command {program}{status}: {reason}")
+    } else {
+        format!("stream command {program}{status}: {reason}: {stderr}")
+    };
+
+    io::Error::new(kind, err)
+}
+
+/// Returns the command line of a `cmd:` URL.
+///
+/// The command line is everything following the scheme, query and
+/// fragment included, percent-decoded: `?` and `#` do not need to be
+/// escaped, but literal `%` do (`%25`).
+pub fn url_command_line(url: &Url) -> Result<String> {
+    let line = percent_decode_str(&url[Position::BeforePath..])
+        .decode_utf8()
+        .with_context(|| format!("Invalid command in URL {url}"))?;
+
+    if line.trim().is_empty() {
+        bail!("Missing command in URL {url}");
+    }
+
+    Ok(line.into_owned())
+}
+
+impl fmt::Debug for CommandStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandStream")
+            .field("program", &self.program)
+            .field("pid", &self.child.id())
+            .finish()
+    }
+}
+
+impl Read for CommandStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stdout.read(buf) {
+            Ok(0) if !buf.is_empty() => {
+                debug!("stream command {} closed its output", self.program);
+                self.wait_exit();
+                Err(self.error(io::ErrorKind::UnexpectedEof, "closed its output"))
+            }
+            Ok(n) => Ok(n),
+            Err(err) => Err(self.error(err.kind(), err)),
+        }
+    }
+}
+
+impl Write for CommandStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stdin.write(buf) {
+            Ok(n) => Ok(n),
+            Err(err) => Err(self.error(err.kind(), err)),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stdin.flush() {
+            Ok(()) => Ok(()),
+            Err(err) => Err(self.error(err.kind(), err)),
+        }
+    }
+}
+
+impl Drop for CommandStream {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            debug!("killing stream command {}", self.program);
+            let _ = self.child.kill();
+        }
+
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{ErrorKind, Read};
+
+    use super::CommandStream;
+
+    #[test]
+    fn end_of_output() {
+        let mut stream = CommandStream::spawn_shell("echo hello; echo oops >&2; exit 3").unwrap();
+
+        let mut buf = [0u8; 6];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello\n");
+
+        let err = stream.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let err = err.to_string();
+        assert!(err.starts_with("stream command sh ("), "{err}");
+        assert!(err.contains('3'), "{err}");
+        assert!(err.ends_with("closed its output: oops"), "{err}");
+    }
+}
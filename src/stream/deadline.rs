@@ -0,0 +1,107 @@
+use std::{
+    net::Shutdown,
+    sync::{Arc, Condvar, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use log::{debug, warn};
+
+use super::Stream;
+
+/// Deadline of a session setup (see
+/// [`Tcp::handshake_timeout`](super::Tcp::handshake_timeout)).
+///
+/// Once watching a stream, the TCP connection is shut down when the
+/// deadline expires, which aborts any pending read or write.
+#[derive(Debug)]
+pub struct HandshakeDeadline {
+    timeout: Option<Duration>,
+    start: Instant,
+    state: Arc<(Mutex<DeadlineState>, Condvar)>,
+}
+
+#[derive(Debug, Default)]
+struct DeadlineState {
+    finished: bool,
+    expired: bool,
+}
+
+impl HandshakeDeadline {
+    /// Starts the deadline. Nothing is enforced when `timeout` is
+    /// unset.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            start: Instant::now(),
+            state: Default::default(),
+        }
+    }
+
+    /// Watches the given stream, shutting it down when the deadline
+    /// expires.
+    pub fn watch(&self, stream: &Stream) -> Result<()> {
+        let Some(timeout) = self.timeout else {
+            return Ok(());
+        };
+
+        let Some(remaining) = timeout.checked_sub(self.start.elapsed()) else {
+            bail!("Handshake timed out after {timeout:?}");
+        };
+
+        let Some(tcp) = stream.tcp() else {
+            debug!("handshake deadline only supported by TCP streams, ignoring");
+            return Ok(());
+        };
+
+        let tcp = tcp.try_clone()?;
+        let state = self.state.clone();
+
+        thread::spawn(move || {
+            let (lock, cvar) = &*state;
+
+            let Ok(guard) = lock.lock() else {
+                return;
+            };
+
+            let Ok((mut state, _)) = cvar.wait_timeout_while(guard, remaining, |s| !s.finished)
+            else {
+                return;
+            };
+
+            if !state.finished {
+                warn!("handshake timed out after {timeout:?}, shutting connection down");
+                state.expired = true;
+                let _ = tcp.shutdown(Shutdown::Both);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Stops watching, then returns the result of the session setup,
+    /// turned into a timeout error if the deadline expired.
+    pub fn finish<T>(self, result: Result<T>) -> Result<T> {
+        let (lock, cvar) = &*self.state;
+
+        let expired = match lock.lock() {
+            Ok(mut state) => {
+                state.finished = true;
+                state.expired
+            }
+            Err(_) => false,
+        };
+
+        cvar.notify_all();
+
+        let Some(timeout) = self.timeout.filter(|_| expired) else {
+            return result;
+        };
+
+        match result {
+            Ok(_) => bail!("Handshake timed out after {timeout:?}"),
+            Err(err) => Err(err.context(format!("Handshake timed out after {timeout:?}"))),
+        }
+    }
+}
@@ -0,0 +1,809 @@
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use url::{Position, Url};
+
+use crate::stream::{http::HttpSession, Tcp, Tls};
+
+/// Default Thunderbird ISP database.
+const ISP_DB_URL: &str = "https://autoconfig.thunderbird.net/v1.1/";
+
+/// Maximum number of HTTP redirects followed by the default fetch
+/// function.
+const MAX_REDIRECTS: usize = 5;
+
+/// Maximum size of the HTTP responses read by the default fetch
+/// function.
+const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+/// Function fetching a URL with an HTTP GET request, following
+/// redirects.
+pub type DiscoveryFetch = Arc<dyn Fn(&Url) -> Result<DiscoveryResponse> + Send + Sync>;
+
+/// Response to an HTTP request made during discovery.
+#[derive(Clone, Debug)]
+pub struct DiscoveryResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// Discovery of the servers of an email address.
+///
+/// Candidates are gathered from DNS SRV records (RFC 6186 for IMAP
+/// and SMTP, RFC 6764 for CalDAV, RFC 8620 for JMAP), Thunderbird
+/// autoconfig files published by the domain or found in the ISP
+/// database, and the `/.well-known/jmap` endpoint of the domain.
+///
+/// SRV records are looked up through the resolver of
+/// [`Discovery::tcp`]. Autoconfig files are only fetched over HTTPS.
+#[derive(Clone)]
+pub struct Discovery {
+    /// Options of the TCP connections, including the resolver.
+    pub tcp: Tcp,
+    /// Options of the TLS connections, given to candidates as well.
+    pub tls: Tls,
+    /// Thunderbird ISP database, queried when the domain publishes
+    /// no autoconfig file. Disabled when unset.
+    pub isp_db: Option<Url>,
+    /// Fetches URLs instead of the built-in HTTP client.
+    pub fetch: Option<DiscoveryFetch>,
+}
+
+impl fmt::Debug for Discovery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Discovery")
+            .field("tcp", &self.tcp)
+            .field("tls", &self.tls)
+            .field("isp_db", &self.isp_db)
+            .field("fetch", &self.fetch.is_some())
+            .finish()
+    }
+}
+
+impl Discovery {
+    pub fn new(tcp: Tcp, tls: Tls) -> Self {
+        Self {
+            tcp,
+            tls,
+            isp_db: Some(Url::parse(ISP_DB_URL).expect("ISP database URL should be valid")),
+            fetch: None,
+        }
+    }
+
+    pub fn with_fetch(
+        mut self,
+        fetch: impl Fn(&Url) -> Result<DiscoveryResponse> + Send + Sync + 'static,
+    ) -> Self {
+        self.fetch = Some(Arc::new(fetch));
+        self
+    }
+
+    /// Discovers the servers of the given email address.
+    ///
+    /// Candidates are ranked from the most to the least secure
+    /// (implicit TLS, STARTTLS, then plain text), then by source:
+    /// SRV records, autoconfig file of the domain, `/.well-known`
+    /// endpoints, then ISP database. Sources failing are skipped.
+    pub fn discover(&self, email: &str) -> Result<Vec<DiscoveryCandidate>> {
+        let Some((local_part, domain)) = email.trim().rsplit_once('@') else {
+            bail!("Invalid email address {email}: missing domain");
+        };
+
+        if local_part.is_empty() || domain.is_empty() {
+            bail!("Invalid email address {email}");
+        }
+
+        let domain = domain.to_ascii_lowercase();
+
+        let email = Email {
+            address: email.trim(),
+            local_part,
+            domain: &domain,
+        };
+
+        let mut candidates = self.discover_srv(&email);
+
+        let mut autoconfig = self.discover_autoconfig(&email);
+
+        if autoconfig.is_empty() {
+            autoconfig = self.discover_isp_db(&email);
+        }
+
+        candidates.extend(autoconfig);
+        candidates.extend(self.discover_well_known_jmap(&email));
+
+        candidates.sort_by_key(|candidate| (candidate.security(), candidate.source.rank()));
+
+        let mut ranked: Vec<DiscoveryCandidate> = Vec::with_capacity(candidates.len());
+
+        // keeps the best ranked duplicate, completed with the
+        // username given by autoconfig files
+        for candidate in candidates {
+            let duplicate = ranked.iter_mut().find(|c| {
+                c.protocol == candidate.protocol
+                    && c.url == candidate.url
+                    && c.starttls == candidate.starttls
+            });
+
+            match duplicate {
+                Some(duplicate) if duplicate.username.is_none() => {
+                    duplicate.username = candidate.username;
+                }
+                Some(_) => (),
+                None => ranked.push(candidate),
+            }
+        }
+
+        debug!(
+            "discovered {} candidate(s) for {}",
+            ranked.len(),
+            email.address
+        );
+        Ok(ranked)
+    }
+
+    fn discover_srv(&self, email: &Email<'_>) -> Vec<DiscoveryCandidate> {
+        let services = [
+            (DiscoveryProtocol::Imap, "_imaps", "imaps", false),
+            (DiscoveryProtocol::Imap, "_imap", "imap", true),
+            (DiscoveryProtocol::Smtp, "_submissions", "smtps", false),
+            (DiscoveryProtocol::Smtp, "_submission", "smtp", true),
+            (DiscoveryProtocol::Jmap, "_jmap", "https", false),
+            (DiscoveryProtocol::Caldav, "_caldavs", "https", false),
+            (DiscoveryProtocol::Caldav, "_caldav", "http", false),
+        ];
+
+        let mut candidates = Vec::new();
+
+        for (protocol, service, scheme, starttls) in services {
+            let name = format!("{service}._tcp.{}", email.domain);
+
+            let records = match self.tcp.resolver.resolve_srv(&name) {
+                Ok(records) => records,
+                Err(err) => {
+                    debug!("skipping SRV discovery of {name}: {err:#}");
+                    continue;
+                }
+            };
+
+            for record in records {
+                // RFC 6764 gives the CalDAV path in a TXT record,
+                // falling back to the well-known URI
+                let path = match protocol {
+                    DiscoveryProtocol::Jmap => "/.well-known/jmap",
+                    DiscoveryProtocol::Caldav => "/.well-known/caldav",
+                    _ => "",
+                };
+
+                let url = format!("{scheme}://{}:{}{path}", record.target, record.port);
+
+                match Url::parse(&url) {
+                    Ok(url) => candidates.push(self.candidate(
+                        protocol,
+                        url,
+                        starttls,
+                        None,
+                        DiscoverySource::Srv(name.clone()),
+                    )),
+                    Err(err) => debug!("skipping invalid SRV target {url}: {err}"),
+                }
+            }
+        }
+
+        candidates
+    }
+
+    fn discover_autoconfig(&self, email: &Email<'_>) -> Vec<DiscoveryCandidate> {
+        let mut url = Url::parse(&format!(
+            "https://autoconfig.{}/mail/config-v1.1.xml",
+            email.domain
+        ));
+
+        if let Ok(url) = &mut url {
+            url.query_pairs_mut()
+                .append_pair("emailaddress", email.address);
+        }
+
+        let urls = [
+            url,
+            Url::parse(&format!(
+                "https://{}/.well-known/autoconfig/mail/config-v1.1.xml",
+                email.domain
+            )),
+        ];
+
+        for url in urls.into_iter().flatten() {
+            match self.fetch_autoconfig(&url, email, DiscoverySource::Autoconfig(url.clone())) {
+                Ok(candidates) if !candidates.is_empty() => return candidates,
+                Ok(_) => debug!("no server found in autoconfig at {url}"),
+                Err(err) => debug!("skipping autoconfig at {url}: {err:#}"),
+            }
+        }
+
+        Vec::new()
+    }
+
+    fn discover_isp_db(&self, email: &Email<'_>) -> Vec<DiscoveryCandidate> {
+        let Some(isp_db) = &self.isp_db else {
+            return Vec::new();
+        };
+
+        let url = match isp_db.join(email.domain) {
+            Ok(url) => url,
+            Err(err) => {
+                debug!("skipping ISP database: {err}");
+                return Vec::new();
+            }
+        };
+
+        match self.fetch_autoconfig(&url, email, DiscoverySource::IspDb(url.clone())) {
+            Ok(candidates) => candidates,
+            Err(err) => {
+                debug!("skipping ISP database at {url}: {err:#}");
+                Vec::new()
+            }
+        }
+    }
+
+    fn discover_well_known_jmap(&self, email: &Email<'_>) -> Option<DiscoveryCandidate> {
+        let url = Url::parse(&format!("https://{}/.well-known/jmap", email.domain)).ok()?;
+
+        // the session resource requires authentication
+        match self.fetch(&url) {
+            Ok(res) if res.status == 200 || res.status == 401 => Some(self.candidate(
+                DiscoveryProtocol::Jmap,
+                url.clone(),
+                false,
+                None,
+                DiscoverySource::WellKnown(url),
+            )),
+            Ok(res) => {
+                debug!("skipping {url}: HTTP status {}", res.status);
+                None
+            }
+            Err(err) => {
+                debug!("skipping {url}: {err:#}");
+                None
+            }
+        }
+    }
+
+    fn fetch_autoconfig(
+        &self,
+        url: &Url,
+        email: &Email<'_>,
+        source: DiscoverySource,
+    ) -> Result<Vec<DiscoveryCandidate>> {
+        let res = self.fetch(url)?;
+
+        if res.status != 200 {
+            bail!("Fetch autoconfig error: HTTP status {}", res.status);
+        }
+
+        let xml = String::from_utf8(res.body).context("Invalid autoconfig encoding")?;
+        let doc = roxmltree::Document::parse(&xml).context("Parse autoconfig error")?;
+        let mut candidates = Vec::new();
+
+        for node in doc.descendants().filter(|node| node.is_element()) {
+            let kind = node.attribute("type").unwrap_or_default();
+
+            let protocol = match (node.tag_name().name(), kind) {
+                ("incomingServer", "imap") => DiscoveryProtocol::Imap,
+                ("outgoingServer", "smtp") => DiscoveryProtocol::Smtp,
+                ("calendar", "caldav") => DiscoveryProtocol::Caldav,
+                _ => continue,
+            };
+
+            let child = |name: &str| {
+                node.children()
+                    .find(|child| child.has_tag_name(name))
+                    .and_then(|child| child.text())
+                    .map(|text| email.expand(text.trim()))
+            };
+
+            let username = child("username");
+
+            let (url, starttls) = if protocol == DiscoveryProtocol::Caldav {
+                let Some(url) = child("serverURL") else {
+                    continue;
+                };
+
+                (url, false)
+            } else {
+                let (Some(host), Some(port)) = (child("hostname"), child("port")) else {
+                    continue;
+                };
+
+                let socket_type = child("socketType").unwrap_or_default();
+                let secure = socket_type.eq_ignore_ascii_case("SSL");
+                let starttls = socket_type.eq_ignore_ascii_case("STARTTLS");
+
+                let scheme = match (protocol, secure) {
+                    (DiscoveryProtocol::Imap, true) => "imaps",
+                    (DiscoveryProtocol::Imap, false) => "imap",
+                    (_, true) => "smtps",
+                    (_, false) => "smtp",
+                };
+
+                (format!("{scheme}://{host}:{port}"), starttls)
+            };
+
+            match Url::parse(&url) {
+                Ok(url) => {
+                    candidates.push(self.candidate(
+                        protocol,
+                        url,
+                        starttls,
+                        username,
+                        source.clone(),
+                    ));
+                }
+                Err(err) => debug!("skipping invalid autoconfig server {url}: {err}"),
+            }
+        }
+
+        Ok(candidates)
+    }
+
+    fn fetch(&self, url: &Url) -> Result<DiscoveryResponse> {
+        debug!("fetching {url}");
+
+        match &self.fetch {
+            Some(fetch) => fetch(url),
+            None => self.http_get(url),
+        }
+    }
+
+    /// Fetches a URL with a minimal HTTP/1.0 client, following
+    /// redirects to HTTPS URLs only.
+    fn http_get(&self, url: &Url) -> Result<DiscoveryResponse> {
+        let mut url = url.clone();
+
+        for _ in 0..MAX_REDIRECTS {
+            let mut session = HttpSession::new_with_tcp(&url, self.tcp.clone(), self.tls.clone())?;
+
+            let host = match (url.host_str(), url.port()) {
+                (Some(host), Some(port)) => format!("{host}:{port}"),
+                (Some(host), None) => host.to_owned(),
+                (None, _) => bail!("Invalid URL {url}: missing host"),
+            };
+
+            let target = &url[Position::BeforePath..Position::AfterQuery];
+            let req = format!("GET {target} HTTP/1.0\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+            session.stream.write_all(req.as_bytes())?;
+            session.stream.flush()?;
+
+            let mut bytes = Vec::new();
+            let read = (&mut session.stream)
+                .take(MAX_RESPONSE_SIZE)
+                .read_to_end(&mut bytes);
+
+            match read {
+                Ok(_) => (),
+                // servers often close the connection without TLS
+                // close notify
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && !bytes.is_empty() => (),
+                Err(err) => return Err(err).with_context(|| format!("Read {url} error")),
+            }
+
+            let (status, location, body) = parse_http_response(bytes)
+                .with_context(|| format!("Parse HTTP response from {url} error"))?;
+
+            match location {
+                Some(location) if (300..400).contains(&status) => {
+                    debug!("following redirect to {location}");
+                    url = url.join(&location)?;
+
+                    // never downgrade discovery to cleartext
+                    if url.scheme() != "https" {
+                        bail!("Fetch {location} error: redirect to non-HTTPS URL");
+                    }
+                }
+                _ => return Ok(DiscoveryResponse { status, body }),
+            }
+        }
+
+        bail!("Fetch {url} error: too many redirects")
+    }
+
+    fn candidate(
+        &self,
+        protocol: DiscoveryProtocol,
+        url: Url,
+        starttls: bool,
+        username: Option<String>,
+        source: DiscoverySource,
+    ) -> DiscoveryCandidate {
+        DiscoveryCandidate {
+            protocol,
+            url,
+            starttls,
+            tls: self.tls.clone(),
+            username,
+            source,
+        }
+    }
+}
+
+/// Server proposed by [`Discovery::discover`].
+///
+/// The URL, TLS options and STARTTLS flag can be given as is to
+/// [`ImapSession::new`](super::imap::ImapSession::new) and
+/// friends. JMAP URLs point to the session resource.
+#[derive(Clone, Debug)]
+pub struct DiscoveryCandidate {
+    pub protocol: DiscoveryProtocol,
+    pub url: Url,
+    pub starttls: bool,
+    pub tls: Tls,
+    /// Login name, when given by an autoconfig file.
+    pub username: Option<String>,
+    /// Where the candidate comes from.
+    pub source: DiscoverySource,
+}
+
+impl DiscoveryCandidate {
+    /// Returns `true` if the connection is protected by TLS, either
+    /// implicit or via STARTTLS.
+    pub fn is_secure(&self) -> bool {
+        self.security() < 2
+    }
+
+    fn security(&self) -> u8 {
+        let scheme = self.url.scheme();
+
+        if ["imaps", "smtps", "https"].contains(&scheme) {
+            0
+        } else if self.starttls {
+            1
+        } else {
+            2
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiscoveryProtocol {
+    Imap,
+    Smtp,
+    Jmap,
+    Caldav,
+}
+
+/// Provenance of a [`DiscoveryCandidate`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DiscoverySource {
+    /// DNS SRV record of the given name.
+    Srv(String),
+    /// Autoconfig file published by the domain, at the given URL.
+    Autoconfig(Url),
+    /// Well-known URI of the domain (RFC 8615).
+    WellKnown(Url),
+    /// Autoconfig file of the ISP database, at the given URL.
+    IspDb(Url),
+}
+
+impl DiscoverySource {
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Srv(_) => 0,
+            Self::Autoconfig(_) => 1,
+            Self::WellKnown(_) => 2,
+            Self::IspDb(_) => 3,
+        }
+    }
+}
+
+struct Email<'a> {
+    address: &'a str,
+    local_part: &'a str,
+    domain: &'a str,
+}
+
+impl Email<'_> {
+    /// Expands the placeholders of autoconfig files.
+    fn expand(&self, text: &str) -> String {
+        text.replace("%EMAILADDRESS%", self.address)
+            .replace("%EMAILLOCALPART%", self.local_part)
+            .replace("%EMAILDOMAIN%", self.domain)
+    }
+}
+
+/// Parses an HTTP response into its status, `Location` header and
+/// body.
+fn parse_http_response(mut bytes: Vec<u8>) -> Result<(u16, Option<String>, Vec<u8>)> {
+    let Some(end) = bytes.windows(4).position(|w| w == b"\r\n\r\n") else {
+        bail!("missing end of headers");
+    };
+
+    let body = bytes.split_off(end + 4);
+    let head = String::from_utf8_lossy(&bytes);
+    let mut lines = head.lines();
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|status| status.parse().ok())
+        .context("invalid status line")?;
+
+    let location = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("location")
+            .then(|| value.trim().to_owned())
+    });
+
+    Ok((status, location, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use anyhow::Result;
+    use url::Url;
+
+    use super::{
+        parse_http_response, Discovery, DiscoveryCandidate, DiscoveryProtocol, DiscoveryResponse,
+        DiscoverySource,
+    };
+    use crate::stream::{Resolver, SrvRecord, Tcp, Tls};
+
+    const AUTOCONFIG: &str = r#"<?xml version="1.0"?>
+<clientConfig version="1.1">
+  <emailProvider id="example.com">
+    <incomingServer type="imap">
+      <hostname>mail.example.com</hostname>
+      <port>993</port>
+      <socketType>SSL</socketType>
+      <username>%EMAILADDRESS%</username>
+    </incomingServer>
+    <incomingServer type="pop3">
+      <hostname>mail.example.com</hostname>
+      <port>995</port>
+      <socketType>SSL</socketType>
+    </incomingServer>
+    <outgoingServer type="smtp">
+      <hostname>smtp.%EMAILDOMAIN%</hostname>
+      <port>587</port>
+      <socketType>STARTTLS</socketType>
+      <username>%EMAILLOCALPART%</username>
+    </outgoingServer>
+  </emailProvider>
+</clientConfig>"#;
+
+    fn srv(priority: u16, port: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight: 0,
+            port,
+            target: target.to_owned(),
+        }
+    }
+
+    fn response(status: u16, body: &str) -> Result<DiscoveryResponse> {
+        Ok(DiscoveryResponse {
+            status,
+            body: body.as_bytes().to_vec(),
+        })
+    }
+
+    fn discovery(
+        srv_lookup: impl Fn(&str) -> io::Result<Vec<SrvRecord>> + Send + Sync + 'static,
+        fetch: impl Fn(&Url) -> Result<DiscoveryResponse> + Send + Sync + 'static,
+    ) -> Discovery {
+        let tcp = Tcp {
+            resolver: Resolver::default().with_srv_lookup(srv_lookup),
+            ..Default::default()
+        };
+
+        Discovery::new(tcp, Tls::default()).with_fetch(fetch)
+    }
+
+    fn urls(candidates: &[DiscoveryCandidate]) -> Vec<(&str, bool)> {
+        candidates
+            .iter()
+            .map(|candidate| (candidate.url.as_str(), candidate.starttls))
+            .collect()
+    }
+
+    #[test]
+    fn srv_records() {
+        let discovery = discovery(
+            |name| match name {
+                "_imaps._tcp.example.com" => Ok(vec![
+                    srv(20, 993, "imap2.example.com"),
+                    srv(10, 993, "imap1.example.com"),
+                ]),
+                "_submission._tcp.example.com" => Ok(vec![srv(0, 587, "smtp.example.com")]),
+                "_jmap._tcp.example.com" => Ok(vec![srv(0, 0, ".")]),
+                "_caldavs._tcp.example.com" => Err(io::ErrorKind::TimedOut.into()),
+                _ => Ok(Vec::new()),
+            },
+            |_| response(404, ""),
+        );
+
+        let candidates = discovery.discover("alice@Example.com").unwrap();
+
+        assert_eq!(
+            urls(&candidates),
+            [
+                ("imaps://imap1.example.com:993", false),
+                ("imaps://imap2.example.com:993", false),
+                ("smtp://smtp.example.com:587", true),
+            ]
+        );
+
+        assert_eq!(
+            candidates[2].source,
+            DiscoverySource::Srv("_submission._tcp.example.com".into())
+        );
+        assert_eq!(candidates[2].protocol, DiscoveryProtocol::Smtp);
+    }
+
+    #[test]
+    fn autoconfig() {
+        let discovery = discovery(
+            |_| Ok(Vec::new()),
+            |url| match url.host_str() {
+                Some("autoconfig.example.com") => {
+                    assert_eq!(url.query(), Some("emailaddress=alice%40example.com"));
+                    response(200, AUTOCONFIG)
+                }
+                Some("autoconfig.thunderbird.net") => panic!("unexpected ISP database query"),
+                _ => response(404, ""),
+            },
+        );
+
+        let candidates = discovery.discover("alice@example.com").unwrap();
+
+        assert_eq!(
+            urls(&candidates),
+            [
+                ("imaps://mail.example.com:993", false),
+                ("smtp://smtp.example.com:587", true),
+            ]
+        );
+
+        assert_eq!(candidates[0].username.as_deref(), Some("alice@example.com"));
+        assert_eq!(candidates[1].username.as_deref(), Some("alice"));
+        assert!(matches!(
+            &candidates[0].source,
+            DiscoverySource::Autoconfig(url) if url.host_str() == Some("autoconfig.example.com")
+        ));
+    }
+
+    #[test]
+    fn isp_db() {
+        let discovery = discovery(
+            |_| Ok(Vec::new()),
+            |url| match url.as_str() {
+                "https://autoconfig.thunderbird.net/v1.1/example.com" => response(200, AUTOCONFIG),
+                // invalid autoconfig files are skipped
+                "https://example.com/.well-known/autoconfig/mail/config-v1.1.xml" => {
+                    response(200, "<clientConfig>")
+                }
+                _ => response(404, ""),
+            },
+        );
+
+        let candidates = discovery.discover("alice@example.com").unwrap();
+
+        assert_eq!(candidates.len(), 2);
+
+        let isp_db = Url::parse("https://autoconfig.thunderbird.net/v1.1/example.com").unwrap();
+        for candidate in &candidates {
+            assert_eq!(candidate.source, DiscoverySource::IspDb(isp_db.clone()));
+        }
+
+        let discovery = Discovery {
+            isp_db: None,
+            ..discovery
+        };
+
+        assert!(discovery.discover("alice@example.com").unwrap().is_empty());
+    }
+
+    #[test]
+    fn well_known_jmap() {
+        let discovery = discovery(
+            |_| Ok(Vec::new()),
+            |url| match url.path() {
+                "/.well-known/jmap" => response(401, ""),
+                _ => response(404, ""),
+            },
+        );
+
+        let candidates = discovery.discover("alice@example.com").unwrap();
+
+        assert_eq!(
+            urls(&candidates),
+            [("https://example.com/.well-known/jmap", false)]
+        );
+        assert_eq!(candidates[0].protocol, DiscoveryProtocol::Jmap);
+        assert!(matches!(
+            candidates[0].source,
+            DiscoverySource::WellKnown(_)
+        ));
+    }
+
+    #[test]
+    fn ranking_and_deduplication() {
+        let autoconfig = r#"<clientConfig version="1.1">
+  <emailProvider id="example.com">
+    <incomingServer type="imap">
+      <hostname>mail.example.com</hostname>
+      <port>143</port>
+      <socketType>plain</socketType>
+    </incomingServer>
+    <incomingServer type="imap">
+      <hostname>mail.example.com</hostname>
+      <port>993</port>
+      <socketType>SSL</socketType>
+      <username>%EMAILADDRESS%</username>
+    </incomingServer>
+  </emailProvider>
+</clientConfig>"#;
+
+        let discovery = discovery(
+            |name| match name {
+                "_imap._tcp.example.com" => Ok(vec![srv(0, 143, "mail.example.com")]),
+                "_imaps._tcp.example.com" => Ok(vec![srv(0, 993, "mail.example.com")]),
+                _ => Ok(Vec::new()),
+            },
+            move |url| match url.host_str() {
+                Some("autoconfig.example.com") => response(200, autoconfig),
+                _ => response(404, ""),
+            },
+        );
+
+        let candidates = discovery.discover("alice@example.com").unwrap();
+
+        // implicit TLS first, then STARTTLS, then plain text
+        assert_eq!(
+            urls(&candidates),
+            [
+                ("imaps://mail.example.com:993", false),
+                ("imap://mail.example.com:143", true),
+                ("imap://mail.example.com:143", false),
+            ]
+        );
+
+        // the SRV candidate wins, completed with the autoconfig
+        // username
+        assert_eq!(
+            candidates[0].source,
+            DiscoverySource::Srv("_imaps._tcp.example.com".into())
+        );
+        assert_eq!(candidates[0].username.as_deref(), Some("alice@example.com"));
+
+        assert!(candidates[0].is_secure());
+        assert!(candidates[1].is_secure());
+        assert!(!candidates[2].is_secure());
+    }
+
+    #[test]
+    fn invalid_email() {
+        let discovery = discovery(|_| Ok(Vec::new()), |_| response(404, ""));
+
+        assert!(discovery.discover("example.com").is_err());
+        assert!(discovery.discover("@example.com").is_err());
+        assert!(discovery.discover("alice@").is_err());
+    }
+
+    #[test]
+    fn http_response() {
+        let bytes = b"HTTP/1.1 301 Moved\r\nLocation: /new\r\n\r\nbody".to_vec();
+        let (status, location, body) = parse_http_response(bytes).unwrap();
+
+        assert_eq!(status, 301);
+        assert_eq!(location.as_deref(), Some("/new"));
+        assert_eq!(body, b"body");
+
+        assert!(parse_http_response(b"HTTP/1.1 200 OK\r\n".to_vec()).is_err());
+    }
+}
@@ -1,14 +1,8 @@
-use std::{net::TcpStream, sync::Arc};
-
 use anyhow::{bail, Result};
 use log::info;
-#[cfg(feature = "native-tls")]
-use native_tls::TlsConnector;
-#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-use rustls::{ClientConnection, StreamOwned};
 use url::Url;
 
-use crate::stream::{Stream, Tls, TlsProvider};
+use crate::stream::{HandshakeDeadline, Stream, Tcp, Tls, TlsInfo};
 
 #[derive(Debug)]
 pub struct HttpSession {
@@ -17,6 +11,17 @@ pub struct HttpSession {
 
 impl HttpSession {
     pub fn new(url: &Url, tls: Tls) -> Result<Self> {
+        Self::new_with_tcp(url, Tcp::default(), tls)
+    }
+
+    /// Same as [`Self::new`], connecting with the given TCP options.
+    pub fn new_with_tcp(url: &Url, tcp: Tcp, tls: Tls) -> Result<Self> {
+        let deadline = HandshakeDeadline::new(tcp.handshake_timeout);
+        let session = Self::handshake(url, tcp, tls, &deadline);
+        deadline.finish(session)
+    }
+
+    fn handshake(url: &Url, tcp: Tcp, tls: Tls, deadline: &HandshakeDeadline) -> Result<Self> {
         info!("connecting to HTTP server using {url}");
 
         let host = url.host_str().unwrap_or("127.0.0.1");
@@ -24,43 +29,28 @@ impl HttpSession {
         let stream = match url.scheme() {
             scheme if scheme.eq_ignore_ascii_case("http") => {
                 let port = url.port().unwrap_or(80);
-                let stream = TcpStream::connect((host, port))?;
-                Stream::Tcp(stream)
+                let stream = Stream::connect_tcp(&tcp, scheme, host, port)?;
+                deadline.watch(&stream)?;
+                stream
             }
             scheme if scheme.eq_ignore_ascii_case("https") => {
                 let port = url.port().unwrap_or(443);
-                let stream = TcpStream::connect((host, port))?;
-                match tls.provider()? {
-                    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-                    TlsProvider::Rustls => {
-                        let config = tls.build_rustls_client_config()?;
-                        let server_name = host.to_string().try_into()?;
-                        let conn = ClientConnection::new(Arc::new(config), server_name)?;
-                        Stream::Rustls(StreamOwned::new(conn, stream))
-                    }
-                    #[cfg(feature = "native-tls")]
-                    TlsProvider::NativeTls => {
-                        let mut builder = TlsConnector::builder();
-
-                        if let Some(pem_path) = &tls.cert {
-                            debug!("using TLS cert at {}", pem_path.display());
-                            let pem = std::fs::read(pem_path)?;
-                            let cert = native_tls::Certificate::from_pem(&pem)?;
-                            builder.add_root_certificate(cert);
-                        }
-
-                        let connector = builder.build()?;
-                        Stream::NativeTls(connector.connect(host, stream)?)
-                    }
-                    #[allow(unreachable_patterns)]
-                    _ => unreachable!(),
-                }
+                let stream = Stream::connect_tcp(&tcp, scheme, host, port)?;
+                deadline.watch(&stream)?;
+                stream.upgrade_tls(host, port, &tls, None)?
             }
             scheme => {
                 bail!("Unknown scheme {scheme}, expected imap, imaps or unix");
             }
         };
 
+        let stream = stream.with_transcript(tcp.transcript.as_ref())?;
+
         Ok(Self { stream })
     }
+
+    /// Returns the details negotiated by the TLS layer, if any.
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        self.stream.tls_info()
+    }
 }
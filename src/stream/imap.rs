@@ -1,10 +1,6 @@
+use std::io::{Read, Write};
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
-use std::{
-    io::{Read, Write},
-    net::TcpStream,
-    sync::Arc,
-};
 
 use anyhow::{bail, Result};
 use io_imap::{
@@ -24,17 +20,16 @@ use io_imap::{
     types::response::Capability,
 };
 use log::info;
-#[cfg(feature = "native-tls")]
-use native_tls::TlsConnector;
-#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-use rustls::{ClientConnection, StreamOwned};
 #[cfg(windows)]
 use uds_windows::UnixStream;
 use url::Url;
 
 use crate::{
-    sasl::{Sasl, SaslMechanism},
-    stream::{Stream, Tls, TlsProvider},
+    sasl::{Sasl, SaslClient},
+    stream::{
+        authenticate::{imap_auth_mechanisms, AuthenticateResult, ImapAuthenticate, SaslAuth},
+        CommandStream, HandshakeDeadline, ReadBuffer, ReadFraming, Stream, Tcp, Tls, TlsInfo,
+    },
 };
 
 const READ_BUFFER_SIZE: usize = 16 * 1024;
@@ -43,41 +38,14 @@ const READ_BUFFER_SIZE: usize = 16 * 1024;
 pub struct ImapSession {
     pub context: ImapContext,
     pub stream: Stream,
-}
-
-fn upgrade_tls(host: &str, tcp: TcpStream, tls: &Tls) -> Result<Stream> {
-    match tls.provider()? {
-        #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-        TlsProvider::Rustls => {
-            let mut config = tls.build_rustls_client_config()?;
-            config.alpn_protocols = vec![b"imap".to_vec()];
-            let server_name = host.to_string().try_into()?;
-            let conn = ClientConnection::new(Arc::new(config), server_name)?;
-            Ok(Stream::Rustls(StreamOwned::new(conn, tcp)))
-        }
-        #[cfg(feature = "native-tls")]
-        TlsProvider::NativeTls => {
-            let mut builder = TlsConnector::builder();
-
-            if let Some(pem_path) = &tls.cert {
-                let pem = std::fs::read(pem_path)?;
-                let cert = native_tls::Certificate::from_pem(&pem)?;
-                builder.add_root_certificate(cert);
-            }
-
-            let connector = builder.build()?;
-            Ok(Stream::NativeTls(connector.connect(host, tcp)?))
-        }
-        #[allow(unreachable_patterns)]
-        _ => unreachable!(),
-    }
+    pub buffer: ReadBuffer,
 }
 
 fn drive_greeting_with_capability<S: Read + Write>(
     stream: &mut S,
+    buffer: &mut ReadBuffer,
     context: ImapContext,
 ) -> Result<ImapContext> {
-    let mut buf = [0u8; READ_BUFFER_SIZE];
     let mut coroutine = ImapGreetingWithCapabilityGet::new(context);
     let mut arg: Option<&[u8]> = None;
 
@@ -85,8 +53,7 @@ fn drive_greeting_with_capability<S: Read + Write>(
         match coroutine.resume(arg.take()) {
             ImapGreetingWithCapabilityGetResult::Ok(context) => return Ok(context),
             ImapGreetingWithCapabilityGetResult::WantsRead => {
-                let n = stream.read(&mut buf)?;
-                arg = Some(&buf[..n]);
+                arg = Some(buffer.read(stream)?);
             }
             ImapGreetingWithCapabilityGetResult::WantsWrite(bytes) => {
                 stream.write_all(&bytes)?;
@@ -97,8 +64,11 @@ fn drive_greeting_with_capability<S: Read + Write>(
     }
 }
 
-fn drive_capability<S: Read + Write>(stream: &mut S, context: ImapContext) -> Result<ImapContext> {
-    let mut buf = [0u8; READ_BUFFER_SIZE];
+fn drive_capability<S: Read + Write>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    context: ImapContext,
+) -> Result<ImapContext> {
     let mut coroutine = ImapCapabilityGet::new(context);
     let mut arg: Option<&[u8]> = None;
 
@@ -106,8 +76,7 @@ fn drive_capability<S: Read + Write>(stream: &mut S, context: ImapContext) -> Re
         match coroutine.resume(arg.take()) {
             ImapCapabilityGetResult::Ok(context) => return Ok(context),
             ImapCapabilityGetResult::WantsRead => {
-                let n = stream.read(&mut buf)?;
-                arg = Some(&buf[..n]);
+                arg = Some(buffer.read(stream)?);
             }
             ImapCapabilityGetResult::WantsWrite(bytes) => {
                 stream.write_all(&bytes)?;
@@ -118,8 +87,11 @@ fn drive_capability<S: Read + Write>(stream: &mut S, context: ImapContext) -> Re
     }
 }
 
-fn drive_starttls<S: Read + Write>(stream: &mut S, context: ImapContext) -> Result<ImapContext> {
-    let mut buf = [0u8; READ_BUFFER_SIZE];
+fn drive_starttls<S: Read + Write>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    context: ImapContext,
+) -> Result<ImapContext> {
     let mut coroutine = ImapStartTls::new(context);
     let mut arg: Option<&[u8]> = None;
 
@@ -127,8 +99,7 @@ fn drive_starttls<S: Read + Write>(stream: &mut S, context: ImapContext) -> Resu
         match coroutine.resume(arg.take()) {
             ImapStartTlsResult::WantsStartTls { context, .. } => return Ok(context),
             ImapStartTlsResult::WantsRead => {
-                let n = stream.read(&mut buf)?;
-                arg = Some(&buf[..n]);
+                arg = Some(buffer.read(stream)?);
             }
             ImapStartTlsResult::WantsWrite(bytes) => {
                 stream.write_all(&bytes)?;
@@ -139,79 +110,149 @@ fn drive_starttls<S: Read + Write>(stream: &mut S, context: ImapContext) -> Resu
     }
 }
 
+/// Authenticates with a SASL mechanism the I/O crate does not
+/// support, then refreshes the capabilities, which may change once
+/// authenticated.
+fn drive_authenticate<S: Read + Write>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    context: ImapContext,
+    client: Box<dyn SaslClient>,
+    ir: bool,
+) -> Result<ImapContext> {
+    let mut coroutine = ImapAuthenticate::new(client, ir);
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            AuthenticateResult::Ok => break,
+            AuthenticateResult::WantsRead => {
+                arg = Some(buffer.read(stream)?);
+            }
+            AuthenticateResult::WantsWrite(bytes) => {
+                stream.write_all(&bytes)?;
+                arg = None;
+            }
+            AuthenticateResult::Err(err) => return Err(err),
+        }
+    }
+
+    let mut context = drive_capability(stream, buffer, context)?;
+    context.authenticated = true;
+    Ok(context)
+}
+
 impl ImapSession {
-    pub fn new(url: Url, tls: Tls, starttls: bool, mut sasl: Sasl) -> Result<Self> {
+    /// Connects and authenticates to the IMAP server at `url`.
+    ///
+    /// Supported schemes: `imap` (plain TCP), `imaps` (TLS over TCP),
+    /// `unix` and `unixs` (plain and TLS over a Unix socket, whose
+    /// path is the URL path), `cmd` and `cmds` (plain and TLS over
+    /// the standard I/O of a command spawned through the shell, for
+    /// example `cmd:ssh mailhost /usr/lib/dovecot/imap`). For TLS
+    /// schemes, `starttls` selects STARTTLS instead of implicit TLS.
+    pub fn new(url: Url, tls: Tls, starttls: bool, sasl: Sasl) -> Result<Self> {
+        Self::new_with_tcp(url, Tcp::default(), tls, starttls, sasl)
+    }
+
+    /// Same as [`Self::new`], connecting with the given TCP options.
+    pub fn new_with_tcp(url: Url, tcp: Tcp, tls: Tls, starttls: bool, sasl: Sasl) -> Result<Self> {
+        let deadline = HandshakeDeadline::new(tcp.handshake_timeout);
+        let session = Self::handshake(url, tcp, tls, starttls, sasl, &deadline);
+        deadline.finish(session)
+    }
+
+    fn handshake(
+        url: Url,
+        tcp: Tcp,
+        tls: Tls,
+        starttls: bool,
+        sasl: Sasl,
+        deadline: &HandshakeDeadline,
+    ) -> Result<Self> {
         info!("connecting to IMAP server using {url}");
 
         let context = ImapContext::new();
         let host = url.host_str().unwrap_or("127.0.0.1");
 
-        let (mut context, mut stream) = match url.scheme() {
+        // secure schemes use TLS, either implicit or via STARTTLS,
+        // whatever the transport is
+        let (stream, secure) = match url.scheme() {
             scheme if scheme.eq_ignore_ascii_case("imap") => {
                 let port = url.port().unwrap_or(143);
-                let mut tcp = TcpStream::connect((host, port))?;
-                let context = drive_greeting_with_capability(&mut tcp, context)?;
-                (context, Stream::Tcp(tcp))
+                (Stream::connect_tcp(&tcp, scheme, host, port)?, false)
             }
             scheme if scheme.eq_ignore_ascii_case("imaps") => {
                 let port = url.port().unwrap_or(993);
-                let mut tcp = TcpStream::connect((host, port))?;
+                (Stream::connect_tcp(&tcp, scheme, host, port)?, true)
+            }
+            scheme if scheme.eq_ignore_ascii_case("unix") => {
+                (Stream::Unix(UnixStream::connect(url.path())?), false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("unixs") => {
+                (Stream::Unix(UnixStream::connect(url.path())?), true)
+            }
+            scheme if scheme.eq_ignore_ascii_case("cmd") => {
+                (Stream::Command(CommandStream::spawn_url(&url)?), false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("cmds") => {
+                (Stream::Command(CommandStream::spawn_url(&url)?), true)
+            }
+            scheme => {
+                bail!("Unknown scheme {scheme}, expected imap, imaps, unix, unixs, cmd or cmds");
+            }
+        };
 
-                let context = if starttls {
-                    drive_starttls(&mut tcp, context)?
-                } else {
-                    context
-                };
+        let mut stream = stream.with_transcript(tcp.transcript.as_ref())?;
+        deadline.watch(&stream)?;
 
-                let mut stream = upgrade_tls(host, tcp, &tls)?;
+        let mut buffer = ReadBuffer::with_capacity(ReadFraming::Imap, READ_BUFFER_SIZE);
 
-                let context = if starttls {
-                    drive_capability(&mut stream, context)?
-                } else {
-                    drive_greeting_with_capability(&mut stream, context)?
-                };
+        let mut context = if !secure {
+            drive_greeting_with_capability(&mut stream, &mut buffer, context)?
+        } else {
+            let context = if starttls {
+                drive_starttls(&mut stream, &mut buffer, context)?
+            } else {
+                context
+            };
 
-                (context, stream)
+            // bytes received in plaintext after STARTTLS could have
+            // been injected by an attacker
+            if !buffer.pending().is_empty() {
+                bail!("unexpected plaintext data received before TLS handshake");
             }
-            scheme if scheme.eq_ignore_ascii_case("unix") => {
-                let sock_path = url.path();
-                let mut unix = UnixStream::connect(sock_path)?;
-                let context = drive_greeting_with_capability(&mut unix, context)?;
-                (context, Stream::Unix(unix))
-            }
-            scheme => {
-                bail!("Unknown scheme {scheme}, expected imap, imaps or unix");
+
+            // identifies the server in the known hosts, whatever the
+            // transport is
+            let port = url.port().unwrap_or(993);
+            stream = stream.upgrade_tls(host, port, &tls, Some(b"imap"))?;
+
+            if starttls {
+                drive_capability(&mut stream, &mut buffer, context)?
+            } else {
+                drive_greeting_with_capability(&mut stream, &mut buffer, context)?
             }
         };
 
         if !context.authenticated {
             let ir = context.capability.contains(&Capability::SaslIr);
+            let mechanisms = imap_auth_mechanisms(context.capability.iter());
+            let channel_binding = || Ok(stream.tls_channel_binding()?.map(Into::into));
 
-            let mechanism = sasl
-                .mechanism
-                .or(Some(SaslMechanism::Plain).filter(|_| sasl.plain.is_some()))
-                .or(Some(SaslMechanism::Login).filter(|_| sasl.login.is_some()));
-
-            match mechanism {
-                None => bail!("no SASL mechanism configured"),
-                Some(SaslMechanism::Login) => {
-                    let Some(auth) = sasl.login.take() else {
-                        bail!("missing SASL LOGIN configuration");
-                    };
-
-                    let mut buf = [0u8; READ_BUFFER_SIZE];
+            context = match SaslAuth::new(sasl, &mechanisms, channel_binding)? {
+                SaslAuth::Login(auth) => {
                     let mut coroutine = ImapSessionLogin::new(
                         context,
                         ImapSessionLoginParams::new(auth.username, auth.password)?,
                     );
                     let mut arg: Option<&[u8]> = None;
 
-                    context = loop {
+                    loop {
                         match coroutine.resume(arg.take()) {
                             ImapSessionLoginResult::Ok(c) => break c,
                             ImapSessionLoginResult::WantsRead => {
-                                let n = stream.read(&mut buf)?;
-                                arg = Some(&buf[..n]);
+                                arg = Some(buffer.read(&mut stream)?);
                             }
                             ImapSessionLoginResult::WantsWrite(bytes) => {
                                 stream.write_all(&bytes)?;
@@ -219,14 +260,9 @@ impl ImapSession {
                             }
                             ImapSessionLoginResult::Err { err, .. } => bail!(err),
                         }
-                    };
+                    }
                 }
-                Some(SaslMechanism::Plain) => {
-                    let Some(auth) = sasl.plain.take() else {
-                        bail!("missing SASL PLAIN configuration");
-                    };
-
-                    let mut buf = [0u8; READ_BUFFER_SIZE];
+                SaslAuth::Plain(auth) => {
                     let mut coroutine = ImapSessionAuthenticatePlain::new(
                         context,
                         ImapSessionAuthenticatePlainParams::new(
@@ -238,12 +274,11 @@ impl ImapSession {
                     );
                     let mut arg: Option<&[u8]> = None;
 
-                    context = loop {
+                    loop {
                         match coroutine.resume(arg.take()) {
                             ImapSessionAuthenticatePlainResult::Ok(c) => break c,
                             ImapSessionAuthenticatePlainResult::WantsRead => {
-                                let n = stream.read(&mut buf)?;
-                                arg = Some(&buf[..n]);
+                                arg = Some(buffer.read(&mut stream)?);
                             }
                             ImapSessionAuthenticatePlainResult::WantsWrite(bytes) => {
                                 stream.write_all(&bytes)?;
@@ -251,14 +286,103 @@ impl ImapSession {
                             }
                             ImapSessionAuthenticatePlainResult::Err { err, .. } => bail!(err),
                         }
-                    };
+                    }
                 }
-                Some(SaslMechanism::Anonymous) => {
-                    unimplemented!("ANONYMOUS SASL mechanism not yet implemented")
+                SaslAuth::Client(client) => {
+                    drive_authenticate(&mut stream, &mut buffer, context, client, ir)?
                 }
-            }
+            };
         }
 
-        Ok(Self { context, stream })
+        Ok(Self {
+            context,
+            stream,
+            buffer,
+        })
+    }
+
+    /// Returns the details negotiated by the TLS layer, if any.
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        self.stream.tls_info()
+    }
+}
+
+#[cfg(all(test, feature = "replay"))]
+mod tests {
+    use url::Url;
+
+    use super::ImapSession;
+    use crate::{
+        sasl::{Sasl, SaslLogin, SaslMechanism, SaslPlain},
+        stream::{Replay, Stream, Tcp, Tls},
+    };
+
+    // tags differ between runs, hence the wildcards
+    const LOGIN: &str = r"
+S: * OK mail.example.com ready\r\n
+C: \? CAPABILITY\r\n
+S: * CAPABILITY IMAP4rev1 AUTH=PLAIN\r\n\? OK CAPABILITY completed\r\n
+C: \? LOGIN alice secret\r\n
+S: \? OK [CAPABILITY IMAP4rev1 IDLE] LOGIN completed\r\n
+";
+
+    const AUTHENTICATE_PLAIN: &str = r"
+S: * OK mail.example.com ready\r\n
+C: \? CAPABILITY\r\n
+S: * CAPABILITY IMAP4rev1 SASL-IR AUTH=PLAIN\r\n\? OK CAPABILITY completed\r\n
+C: \? AUTHENTICATE PLAIN AGFsaWNlAHNlY3JldA==\r\n
+S: \? OK [CAPABILITY IMAP4rev1 IDLE] AUTHENTICATE completed\r\n
+";
+
+    fn replay(transcript: &str, sasl: Sasl) -> ImapSession {
+        let tcp = Tcp {
+            replay: Some(Replay::parse(transcript).unwrap()),
+            ..Default::default()
+        };
+
+        let url = Url::parse("imap://mail.example.com").unwrap();
+        ImapSession::new_with_tcp(url, tcp, Tls::default(), false, sasl).unwrap()
+    }
+
+    #[test]
+    fn replay_login() {
+        let sasl = Sasl {
+            mechanism: Some(SaslMechanism::Login),
+            login: Some(SaslLogin {
+                username: "alice".into(),
+                password: "secret".to_owned().into(),
+            }),
+            ..Default::default()
+        };
+
+        let session = replay(LOGIN, sasl);
+        assert!(session.context.authenticated);
+
+        let Stream::Replay(stream) = session.stream else {
+            panic!("expected replay stream");
+        };
+
+        stream.finish().unwrap();
+    }
+
+    #[test]
+    fn replay_authenticate_plain() {
+        let sasl = Sasl {
+            plain: Some(SaslPlain {
+                authzid: None,
+                authcid: "alice".into(),
+                passwd: "secret".to_owned().into(),
+            }),
+            ..Default::default()
+        };
+
+        let session = replay(AUTHENTICATE_PLAIN, sasl);
+        assert!(session.context.authenticated);
+
+        let Stream::Replay(stream) = session.stream else {
+            panic!("expected replay stream");
+        };
+
+        stream.finish().unwrap();
     }
 }
@@ -1,8 +1,4 @@
-use std::{
-    io::{Read, Write},
-    net::TcpStream,
-    sync::Arc,
-};
+use std::io::Write;
 
 use anyhow::{bail, Result};
 use base64::{prelude::BASE64_STANDARD, Engine};
@@ -11,14 +7,10 @@ use io_jmap::rfc8620::{
     session_get::{JmapSessionGet, JmapSessionGetResult},
 };
 use log::info;
-#[cfg(feature = "native-tls")]
-use native_tls::TlsConnector;
-#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-use rustls::{ClientConnection, StreamOwned};
 use secrecy::{ExposeSecret, SecretString};
 use url::Url;
 
-use crate::stream::{Stream, Tls, TlsProvider};
+use crate::stream::{HandshakeDeadline, ReadBuffer, ReadFraming, Stream, Tcp, Tls, TlsInfo};
 
 const READ_BUFFER_SIZE: usize = 16 * 1024;
 
@@ -64,36 +56,11 @@ impl From<JmapAuth> for SecretString {
 pub struct JmapSession {
     pub session: IoJmapSession,
     pub stream: Stream,
+    pub buffer: ReadBuffer,
     pub http_auth: SecretString,
-}
-
-fn new_tls_stream(host: &str, tcp: TcpStream, tls: &Tls) -> Result<Stream> {
-    let stream = match tls.provider()? {
-        #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-        TlsProvider::Rustls => {
-            let config = tls.build_rustls_client_config()?;
-            let server_name = host.to_string().try_into()?;
-            let conn = ClientConnection::new(Arc::new(config), server_name)?;
-            Stream::Rustls(StreamOwned::new(conn, tcp))
-        }
-        #[cfg(feature = "native-tls")]
-        TlsProvider::NativeTls => {
-            let mut builder = TlsConnector::builder();
-
-            if let Some(pem_path) = &tls.cert {
-                let pem = std::fs::read(pem_path)?;
-                let cert = native_tls::Certificate::from_pem(&pem)?;
-                builder.add_root_certificate(cert);
-            }
-
-            let connector = builder.build()?;
-            Stream::NativeTls(connector.connect(host, tcp)?)
-        }
-        #[allow(unreachable_patterns)]
-        _ => unreachable!(),
-    };
-
-    Ok(stream)
+    /// TCP options the session was established with, reused for
+    /// new connections.
+    pub tcp: Tcp,
 }
 
 fn use_tls(scheme: &str) -> bool {
@@ -108,16 +75,17 @@ fn default_port(scheme: &str) -> u16 {
     }
 }
 
-fn connect(url: &Url, tls: &Tls) -> Result<Stream> {
+fn connect(url: &Url, tcp: &Tcp, tls: &Tls, deadline: &HandshakeDeadline) -> Result<Stream> {
     let host = url.host_str().unwrap_or("localhost");
     let port = url.port().unwrap_or_else(|| default_port(url.scheme()));
-    let tcp = TcpStream::connect((host, port))?;
+    let mut stream = Stream::connect_tcp(tcp, url.scheme(), host, port)?;
+    deadline.watch(&stream)?;
 
     if use_tls(url.scheme()) {
-        new_tls_stream(host, tcp, tls)
-    } else {
-        Ok(Stream::Tcp(tcp))
+        stream = stream.upgrade_tls(host, port, tls, None)?;
     }
+
+    stream.with_transcript(tcp.transcript.as_ref())
 }
 
 impl JmapSession {
@@ -135,8 +103,10 @@ impl JmapSession {
 
         let host = url.host_str().unwrap_or("localhost");
         let port = url.port_or_known_default().unwrap_or(443);
-        let tcp = TcpStream::connect((host, port))?;
-        Ok(Some(new_tls_stream(host, tcp, tls)?))
+        let tcp = &self.tcp;
+        let stream = Stream::connect_tcp(tcp, url.scheme(), host, port)?;
+        let stream = stream.upgrade_tls(host, port, tls, None)?;
+        Ok(Some(stream.with_transcript(tcp.transcript.as_ref())?))
     }
 
     /// Establishes a JMAP session.
@@ -148,6 +118,23 @@ impl JmapSession {
     ///
     /// Supported schemes: `https`, `jmaps` (TLS); `http`, `jmap` (plain).
     pub fn new(server: String, tls: Tls, auth: JmapAuth) -> Result<Self> {
+        Self::new_with_tcp(server, Tcp::default(), tls, auth)
+    }
+
+    /// Same as [`Self::new`], connecting with the given TCP options.
+    pub fn new_with_tcp(server: String, tcp: Tcp, tls: Tls, auth: JmapAuth) -> Result<Self> {
+        let deadline = HandshakeDeadline::new(tcp.handshake_timeout);
+        let session = Self::handshake(server, tcp, tls, auth, &deadline);
+        deadline.finish(session)
+    }
+
+    fn handshake(
+        server: String,
+        tcp: Tcp,
+        tls: Tls,
+        auth: JmapAuth,
+        deadline: &HandshakeDeadline,
+    ) -> Result<Self> {
         let url = match Url::parse(&server) {
             Ok(url) => url,
             Err(url::ParseError::RelativeUrlWithoutBase) => {
@@ -164,26 +151,26 @@ impl JmapSession {
             scheme => bail!("unsupported JMAP scheme `{scheme}`, expected http/https/jmap/jmaps"),
         }
 
-        let mut stream = connect(&url, &tls)?;
+        let mut stream = connect(&url, &tcp, &tls, deadline)?;
+        let mut buffer = ReadBuffer::with_capacity(ReadFraming::None, READ_BUFFER_SIZE);
 
         let http_auth: SecretString = auth.into();
         let mut coroutine = JmapSessionGet::new(&http_auth, &url);
-        let mut buf = [0u8; READ_BUFFER_SIZE];
         let mut arg: Option<&[u8]> = None;
 
         let session = loop {
             match coroutine.resume(arg.take()) {
                 JmapSessionGetResult::Ok { session, .. } => break session,
                 JmapSessionGetResult::WantsRead => {
-                    let n = stream.read(&mut buf)?;
-                    arg = Some(&buf[..n]);
+                    arg = Some(buffer.read(&mut stream)?);
                 }
                 JmapSessionGetResult::WantsWrite(bytes) => {
                     stream.write_all(&bytes)?;
                     arg = None;
                 }
                 JmapSessionGetResult::WantsRedirect { url: new_url, .. } => {
-                    stream = connect(&new_url, &tls)?;
+                    stream = connect(&new_url, &tcp, &tls, deadline)?;
+                    buffer = ReadBuffer::with_capacity(ReadFraming::None, READ_BUFFER_SIZE);
                     coroutine = JmapSessionGet::new(&http_auth, &new_url);
                     arg = None;
                 }
@@ -194,7 +181,69 @@ impl JmapSession {
         Ok(Self {
             session,
             stream,
+            buffer,
             http_auth,
+            tcp,
         })
     }
+
+    /// Returns the details negotiated by the TLS layer, if any.
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        self.stream.tls_info()
+    }
+}
+
+#[cfg(all(test, feature = "replay"))]
+mod tests {
+    use secrecy::ExposeSecret;
+
+    use super::{JmapAuth, JmapSession};
+    use crate::stream::{Replay, Stream, Tcp, Tls};
+
+    // request headers are left to the I/O crate, hence the wildcard
+    const TRANSCRIPT: &str = r#"
+C: GET /.well-known/jmap HTTP/1.1\r\n\?\r\n\r\n
+S: HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 796\r\n\r\n
+S: {"capabilities":{"urn:ietf:params:jmap:core":{"maxSizeUpload":50000000,
+S: "maxConcurrentUpload":4,"maxSizeRequest":10000000,"maxConcurrentRequests":4,
+S: "maxCallsInRequest":16,"maxObjectsInGet":500,"maxObjectsInSet":500,
+S: "collationAlgorithms":[]},"urn:ietf:params:jmap:mail":{}},
+S: "accounts":{"A1":{"name":"alice@example.com","isPersonal":true,"isReadOnly":false,
+S: "accountCapabilities":{"urn:ietf:params:jmap:mail":{}}}},
+S: "primaryAccounts":{"urn:ietf:params:jmap:mail":"A1"},
+S: "username":"alice@example.com",
+S: "apiUrl":"http://jmap.example.com/api/",
+S: "downloadUrl":"http://jmap.example.com/download/{accountId}/{blobId}/{name}?type={type}",
+S: "uploadUrl":"http://jmap.example.com/upload/{accountId}/",
+S: "eventSourceUrl":"http://jmap.example.com/events/?types={types}&closeafter={closeafter}&ping={ping}",
+S: "state":"s1"}
+"#;
+
+    #[test]
+    fn replay_session() {
+        let tcp = Tcp {
+            replay: Some(Replay::parse(TRANSCRIPT).unwrap()),
+            ..Default::default()
+        };
+
+        let auth = JmapAuth::Basic {
+            username: "alice".into(),
+            password: "secret".to_owned().into(),
+        };
+
+        let server = "http://jmap.example.com/.well-known/jmap".to_owned();
+        let session = JmapSession::new_with_tcp(server, tcp, Tls::default(), auth).unwrap();
+
+        assert_eq!(
+            session.session.api_url.as_str(),
+            "http://jmap.example.com/api/"
+        );
+        assert_eq!(session.http_auth.expose_secret(), "Basic YWxpY2U6c2VjcmV0");
+
+        let Stream::Replay(stream) = session.stream else {
+            panic!("expected replay stream");
+        };
+
+        stream.finish().unwrap();
+    }
 }
@@ -0,0 +1,519 @@
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use std::net::IpAddr;
+use std::{
+    error, fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use dirs::{data_local_dir, state_dir};
+use log::{debug, warn};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, OtherError, SignatureScheme,
+};
+
+use super::TlsFingerprints;
+
+/// Callback asking the user whether the certificate presented by a
+/// host seen for the first time should be trusted, given as
+/// `host:port`.
+pub type TlsKnownHostsConfirm = Arc<dyn Fn(&str, &TlsFingerprints) -> bool + Send + Sync>;
+
+/// Trust-on-first-use store of TLS certificate fingerprints, à la
+/// SSH `known_hosts`.
+///
+/// Only consulted when the server certificate fails platform
+/// verification: a host seen for the first time is recorded once
+/// confirmed, then accepted as long as it presents the same
+/// certificate.
+///
+/// Entries are keyed by `[host]:port`, like OpenSSH does for
+/// non-default ports, so that services of the same host can present
+/// different certificates. Entries without port match any port.
+#[derive(Clone)]
+pub struct TlsKnownHosts {
+    /// Path to the store file.
+    pub path: PathBuf,
+    /// Confirmation callback. When unset, unknown hosts are
+    /// rejected.
+    ///
+    /// The callback runs in the middle of the TLS handshake: with the
+    /// tokio API, it blocks the runtime worker until it returns, so
+    /// it should not wait for anything handled by the same runtime.
+    pub confirm: Option<TlsKnownHostsConfirm>,
+}
+
+impl fmt::Debug for TlsKnownHosts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsKnownHosts")
+            .field("path", &self.path)
+            .field("confirm", &self.confirm.is_some())
+            .finish()
+    }
+}
+
+impl TlsKnownHosts {
+    /// Creates a store located in the XDG state directory of the
+    /// given project, `$XDG_STATE_HOME/<project>/known_hosts`.
+    pub fn new(project: &str) -> Result<Self> {
+        let Some(dir) = state_dir().or_else(data_local_dir) else {
+            bail!("Get XDG state directory error");
+        };
+
+        Ok(Self {
+            path: dir.join(project).join("known_hosts"),
+            confirm: None,
+        })
+    }
+
+    pub fn with_confirm(
+        mut self,
+        confirm: impl Fn(&str, &TlsFingerprints) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.confirm = Some(Arc::new(confirm));
+        self
+    }
+
+    /// Returns the certificate fingerprint recorded for the given
+    /// host and port, with its line number.
+    ///
+    /// An entry for the port takes precedence over an entry for the
+    /// whole host.
+    pub fn get(&self, host: &str, port: u16) -> Result<Option<(usize, String)>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                let path = self.path.display();
+                return Err(err).context(format!("Read TLS known hosts at {path} error"));
+            }
+        };
+
+        let key = entry_key(host, port);
+        let mut host_entry = None;
+
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+
+            let (Some(entry_host), Some("sha256"), Some(fingerprint)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                warn!("skip invalid TLS known hosts entry at line {}", i + 1);
+                continue;
+            };
+
+            if entry_host.eq_ignore_ascii_case(&key) {
+                return Ok(Some((i + 1, fingerprint.to_owned())));
+            }
+
+            if host_entry.is_none() && entry_host.eq_ignore_ascii_case(host) {
+                host_entry = Some((i + 1, fingerprint.to_owned()));
+            }
+        }
+
+        Ok(host_entry)
+    }
+
+    /// Records the certificate fingerprint of the given host and
+    /// port.
+    pub fn insert(&self, host: &str, port: u16, fingerprints: &TlsFingerprints) -> Result<()> {
+        let path = self.path.display();
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Create TLS known hosts directory at {path} error"))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Open TLS known hosts at {path} error"))?;
+
+        let key = entry_key(host, port);
+
+        writeln!(file, "{key} sha256 {}", fingerprints.cert_hex())
+            .with_context(|| format!("Write TLS known hosts at {path} error"))?;
+
+        debug!("added {key} to TLS known hosts at {path}");
+        Ok(())
+    }
+
+    /// Decides whether a certificate that failed platform
+    /// verification can be trusted.
+    pub fn verify(&self, host: &str, port: u16, fingerprints: &TlsFingerprints) -> Result<()> {
+        let addr = format!("{host}:{port}");
+
+        match self.get(host, port)? {
+            Some((_, known)) if known.eq_ignore_ascii_case(&fingerprints.cert_hex()) => {
+                debug!("accepting TLS cert of {addr} from known hosts");
+                Ok(())
+            }
+            Some((line, known)) => Err(TlsKnownHostMismatch {
+                host: addr,
+                path: self.path.clone(),
+                line,
+                known,
+                presented: fingerprints.cert_hex(),
+            }
+            .into()),
+            None => {
+                let confirmed = match &self.confirm {
+                    Some(confirm) => confirm(&addr, fingerprints),
+                    None => false,
+                };
+
+                if !confirmed {
+                    bail!(
+                        "untrusted TLS cert for {addr} (SHA-256: {}) was not confirmed",
+                        fingerprints.cert_hex()
+                    );
+                }
+
+                self.insert(host, port, fingerprints)
+            }
+        }
+    }
+}
+
+/// Returns the known hosts key of the given host and port.
+fn entry_key(host: &str, port: u16) -> String {
+    format!("[{}]:{port}", host.trim_matches(['[', ']']))
+}
+
+/// Error raised when a known host presents a certificate different
+/// from the recorded one.
+#[derive(Clone, Debug)]
+pub struct TlsKnownHostMismatch {
+    /// Host and port, as `host:port`.
+    pub host: String,
+    pub path: PathBuf,
+    pub line: usize,
+    pub known: String,
+    pub presented: String,
+}
+
+impl fmt::Display for TlsKnownHostMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "TLS certificate of {} has CHANGED!", self.host)?;
+        writeln!(f, "Someone could be eavesdropping on you right now, or the certificate has just been renewed.")?;
+        writeln!(f, "Known SHA-256 fingerprint: {}", self.known)?;
+        writeln!(f, "Presented SHA-256 fingerprint: {}", self.presented)?;
+        write!(
+            f,
+            "If the change is expected, remove line {} from {}",
+            self.line,
+            self.path.display()
+        )
+    }
+}
+
+impl error::Error for TlsKnownHostMismatch {}
+
+/// Rustls verifier falling back to the known hosts store when the
+/// inner verifier rejects the server certificate.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+#[derive(Debug)]
+pub(crate) struct KnownHostsVerifier {
+    known_hosts: TlsKnownHosts,
+    /// Port of the server, which rustls does not know about.
+    port: u16,
+    inner: Arc<dyn ServerCertVerifier>,
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl KnownHostsVerifier {
+    pub(crate) fn new(
+        known_hosts: TlsKnownHosts,
+        port: u16,
+        inner: Arc<dyn ServerCertVerifier>,
+    ) -> Self {
+        Self {
+            known_hosts,
+            port,
+            inner,
+        }
+    }
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl ServerCertVerifier for KnownHostsVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let err = match self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            Ok(verified) => return Ok(verified),
+            // revocation errors are not overridable
+            Err(
+                err @ rustls::Error::InvalidCertificate(
+                    CertificateError::Revoked
+                    | CertificateError::UnknownRevocationStatus
+                    | CertificateError::ExpiredRevocationList
+                    | CertificateError::ExpiredRevocationListContext { .. },
+                ),
+            ) => return Err(err),
+            Err(err @ rustls::Error::InvalidCertificate(_)) => err,
+            Err(err) => return Err(err),
+        };
+
+        debug!("TLS cert rejected by platform verifier ({err}), checking known hosts");
+
+        let Ok(fingerprints) = TlsFingerprints::from_der(end_entity) else {
+            return Err(CertificateError::BadEncoding.into());
+        };
+
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_owned(),
+            ServerName::IpAddress(ip) => IpAddr::from(*ip).to_string(),
+            _ => return Err(err),
+        };
+
+        match self.known_hosts.verify(&host, self.port, &fingerprints) {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(known_hosts_err) => {
+                let err = match known_hosts_err.downcast::<TlsKnownHostMismatch>() {
+                    Ok(mismatch) => OtherError(Arc::new(mismatch)),
+                    Err(known_hosts_err) => {
+                        debug!("{known_hosts_err:?}");
+                        OtherError(Arc::new(KnownHostsError(format!("{known_hosts_err:#}"))))
+                    }
+                };
+
+                Err(CertificateError::Other(err).into())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+#[derive(Debug)]
+struct KnownHostsError(String);
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl fmt::Display for KnownHostsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl error::Error for KnownHostsError {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs, process,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::{TlsKnownHostMismatch, TlsKnownHosts};
+    use crate::stream::TlsFingerprints;
+
+    const LEAF: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/leaf.der"
+    ));
+    const OTHER: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/ca.der"
+    ));
+
+    /// Store in a temporary directory, removed on drop.
+    struct Store(TlsKnownHosts);
+
+    impl Store {
+        fn new(name: &str) -> Self {
+            let dir = env::temp_dir().join(format!("known-hosts-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&dir);
+
+            Self(TlsKnownHosts {
+                path: dir.join("nested").join("known_hosts"),
+                confirm: None,
+            })
+        }
+
+        fn read(&self) -> String {
+            fs::read_to_string(&self.0.path).unwrap()
+        }
+
+        fn write(&self, content: &str) {
+            fs::create_dir_all(self.0.path.parent().unwrap()).unwrap();
+            fs::write(&self.0.path, content).unwrap();
+        }
+    }
+
+    impl Drop for Store {
+        fn drop(&mut self) {
+            let dir = self.0.path.parent().unwrap().parent().unwrap();
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    fn leaf() -> TlsFingerprints {
+        TlsFingerprints::from_der(LEAF).unwrap()
+    }
+
+    fn other() -> TlsFingerprints {
+        TlsFingerprints::from_der(OTHER).unwrap()
+    }
+
+    #[test]
+    fn keys() {
+        let store = Store::new("keys");
+        let leaf = leaf().cert_hex();
+        let other = other().cert_hex();
+
+        assert_eq!(store.0.get("mail.example.com", 993).unwrap(), None);
+
+        store.write(&format!(
+            "# comment\n\ninvalid line\nmail.example.com sha256 {other}\n\
+             [MAIL.example.com]:993 sha256 {leaf}\n[::1]:993 sha256 {leaf}\n"
+        ));
+
+        // the port entry takes precedence over the host entry
+        let entry = store.0.get("mail.example.com", 993).unwrap();
+        assert_eq!(entry, Some((5, leaf.clone())));
+
+        let entry = store.0.get("mail.example.com", 465).unwrap();
+        assert_eq!(entry, Some((4, other)));
+
+        let entry = store.0.get("[::1]", 993).unwrap();
+        assert_eq!(entry, Some((6, leaf)));
+        assert_eq!(store.0.get("::1", 465).unwrap(), None);
+    }
+
+    #[test]
+    fn first_use_without_confirm() {
+        let store = Store::new("unconfirmed");
+
+        let err = store
+            .0
+            .verify("mail.example.com", 993, &leaf())
+            .unwrap_err();
+        assert!(err.to_string().contains("was not confirmed"));
+        assert!(!store.0.path.exists());
+    }
+
+    #[test]
+    fn first_use_with_confirm() {
+        let mut store = Store::new("confirmed");
+        let calls = Arc::new(AtomicUsize::new(0));
+
+        store.0 = store.0.clone().with_confirm({
+            let calls = calls.clone();
+            move |addr, fingerprints| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                addr == "mail.example.com:993" && *fingerprints == leaf()
+            }
+        });
+
+        store.0.verify("mail.example.com", 993, &leaf()).unwrap();
+        assert_eq!(
+            store.read(),
+            format!("[mail.example.com]:993 sha256 {}\n", leaf().cert_hex())
+        );
+
+        // known from now on, without asking again
+        store.0.verify("mail.example.com", 993, &leaf()).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // declined for another port
+        assert!(store.0.verify("mail.example.com", 465, &other()).is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(store.read().lines().count(), 1);
+    }
+
+    #[test]
+    fn mismatch() {
+        let mut store = Store::new("mismatch");
+        store.0 = store.0.clone().with_confirm(|_, _| true);
+        store.write(&format!(
+            "[mail.example.com]:993 sha256 {}\n",
+            leaf().cert_hex()
+        ));
+
+        let err = store
+            .0
+            .verify("mail.example.com", 993, &other())
+            .unwrap_err();
+        let mismatch = err.downcast_ref::<TlsKnownHostMismatch>().unwrap();
+
+        assert_eq!(mismatch.host, "mail.example.com:993");
+        assert_eq!(mismatch.line, 1);
+        assert_eq!(mismatch.known, leaf().cert_hex());
+        assert_eq!(mismatch.presented, other().cert_hex());
+        assert!(mismatch.to_string().contains("remove line 1 from"));
+
+        // the confirmation is not a way around a changed certificate
+        assert_eq!(store.read().lines().count(), 1);
+    }
+
+    #[test]
+    fn reload() {
+        let store = Store::new("reload");
+        store.write(&format!(
+            "[mail.example.com]:993 sha256 {}\n",
+            leaf().cert_hex()
+        ));
+        store.0.verify("mail.example.com", 993, &leaf()).unwrap();
+
+        // the file is read again on every check
+        store.write(&format!(
+            "[mail.example.com]:993 sha256 {}\n",
+            other().cert_hex()
+        ));
+        assert!(store.0.verify("mail.example.com", 993, &leaf()).is_err());
+        store.0.verify("mail.example.com", 993, &other()).unwrap();
+
+        store.write("");
+        assert!(store.0.verify("mail.example.com", 993, &other()).is_err());
+    }
+}
@@ -1,12 +1,63 @@
+#[cfg(any(feature = "imap", feature = "smtp"))]
+mod authenticate;
+mod buffer;
+mod command;
+mod deadline;
+#[cfg(feature = "discover")]
+mod discover;
 #[cfg(feature = "http")]
 pub mod http;
 #[cfg(feature = "imap")]
 pub mod imap;
 #[cfg(feature = "jmap")]
 pub mod jmap;
+mod known_hosts;
+mod pin;
+mod proxy;
+#[cfg(feature = "replay")]
+mod replay;
+mod resolver;
+mod resumption;
+mod revocation;
+mod roots;
 #[cfg(feature = "smtp")]
 pub mod smtp;
 mod stream;
+mod tcp;
+mod tls_info;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+mod transcript;
 
+#[doc(inline)]
+pub use buffer::*;
+#[doc(inline)]
+pub use command::*;
+#[doc(inline)]
+pub use deadline::*;
+#[cfg(feature = "discover")]
+#[doc(inline)]
+pub use discover::*;
+#[doc(inline)]
+pub use known_hosts::*;
+#[doc(inline)]
+pub use pin::*;
+#[doc(inline)]
+pub use proxy::*;
+#[cfg(feature = "replay")]
+#[doc(inline)]
+pub use replay::*;
+#[doc(inline)]
+pub use resolver::*;
+#[doc(inline)]
+pub use resumption::*;
+#[doc(inline)]
+pub use revocation::*;
 #[doc(inline)]
 pub use stream::*;
+#[doc(inline)]
+pub use tcp::*;
+#[doc(inline)]
+pub use tls_info::*;
+#[doc(inline)]
+pub use transcript::*;
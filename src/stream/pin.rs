@@ -0,0 +1,471 @@
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use std::sync::Arc;
+use std::{error, fmt, net::IpAddr};
+
+use anyhow::{bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, OtherError, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, parse_x509_certificate};
+
+/// Expected SHA-256 fingerprint of the server certificate.
+///
+/// Fingerprints are either hex-encoded, case-insensitive, and may be
+/// separated by colons (`AB:CD:…`) like OpenSSL prints them, or
+/// base64-encoded like curl's `--pinnedpubkey`. They may be prefixed
+/// by their algorithm (`sha256:`, `sha256/` or `sha256//`).
+#[derive(Clone, Debug)]
+pub enum TlsPin {
+    /// Fingerprint of the whole leaf certificate (DER).
+    Cert(String),
+    /// Fingerprint of the leaf certificate public key (DER
+    /// `SubjectPublicKeyInfo`), which survives certificate renewals
+    /// that keep the same key.
+    Spki(String),
+}
+
+impl TlsPin {
+    /// Decodes the fingerprint of the pin.
+    pub fn fingerprint(&self) -> Result<[u8; 32]> {
+        let (Self::Cert(pin) | Self::Spki(pin)) = self;
+        parse_fingerprint(pin).with_context(|| format!("Invalid TLS pin {pin}"))
+    }
+
+    fn matches(&self, fingerprints: &TlsFingerprints) -> bool {
+        let actual = match self {
+            Self::Cert(_) => &fingerprints.cert,
+            Self::Spki(_) => &fingerprints.spki,
+        };
+
+        matches!(self.fingerprint(), Ok(expected) if expected == *actual)
+    }
+}
+
+fn parse_fingerprint(pin: &str) -> Result<[u8; 32]> {
+    let pin = pin.trim();
+
+    let fingerprint = match pin.split_once([':', '/']) {
+        Some((algorithm, fingerprint)) if is_algorithm(algorithm) => {
+            if !algorithm.eq_ignore_ascii_case("sha256") {
+                bail!("unsupported algorithm {algorithm}, expected sha256");
+            }
+
+            fingerprint.trim_start_matches('/')
+        }
+        _ => pin,
+    };
+
+    let hex = fingerprint.replace(':', "");
+
+    if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        let mut bytes = [0; 32];
+
+        for (byte, digits) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let digits = std::str::from_utf8(digits)?;
+            *byte = u8::from_str_radix(digits, 16)?;
+        }
+
+        return Ok(bytes);
+    }
+
+    match BASE64_STANDARD.decode(fingerprint) {
+        Ok(bytes) => match bytes.try_into() {
+            Ok(bytes) => Ok(bytes),
+            Err(_) => bail!("expected a SHA-256 fingerprint of 32 bytes"),
+        },
+        Err(_) => bail!("expected 64 hex digits or 44 base64 characters"),
+    }
+}
+
+/// Returns whether the prefix of a pin names an algorithm, rather
+/// than being part of the fingerprint.
+fn is_algorithm(prefix: &str) -> bool {
+    let prefix = prefix.to_ascii_lowercase();
+    match prefix.strip_prefix("sha") {
+        Some(bits) => bits.bytes().all(|b| b.is_ascii_digit()),
+        None => prefix == "md5",
+    }
+}
+
+/// SHA-256 fingerprints of a certificate.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TlsFingerprints {
+    pub cert: [u8; 32],
+    pub spki: [u8; 32],
+}
+
+impl TlsFingerprints {
+    /// Computes the fingerprints of a DER-encoded certificate.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let Ok((_, cert)) = parse_x509_certificate(der) else {
+            bail!("Parse X.509 certificate error");
+        };
+
+        Ok(Self {
+            cert: Sha256::digest(der).into(),
+            spki: Sha256::digest(cert.public_key().raw).into(),
+        })
+    }
+
+    /// Returns the certificate fingerprint, colon-separated.
+    pub fn cert_hex(&self) -> String {
+        colon_hex(&self.cert)
+    }
+
+    /// Returns the public key fingerprint, colon-separated.
+    pub fn spki_hex(&self) -> String {
+        colon_hex(&self.spki)
+    }
+}
+
+/// Error raised when the presented certificate matches none of the
+/// configured pins.
+#[derive(Clone, Debug)]
+pub struct TlsPinMismatch(pub TlsFingerprints);
+
+impl fmt::Display for TlsPinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "certificate matches no pinned fingerprint (presented cert SHA-256: {}, public key SHA-256: {})",
+            self.0.cert_hex(),
+            self.0.spki_hex(),
+        )
+    }
+}
+
+impl error::Error for TlsPinMismatch {}
+
+/// Error raised when a pinned certificate cannot be used for the
+/// server, whatever its fingerprint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TlsPinnedCertError {
+    /// The certificate is expired, or not valid yet.
+    Expired,
+    /// The certificate is not valid for the server name.
+    NotValidForName,
+}
+
+impl fmt::Display for TlsPinnedCertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expired => write!(f, "pinned certificate is expired or not valid yet"),
+            Self::NotValidForName => {
+                write!(f, "pinned certificate is not valid for the server name")
+            }
+        }
+    }
+}
+
+impl error::Error for TlsPinnedCertError {}
+
+/// Checks a DER-encoded leaf certificate against the given pins,
+/// then checks that it is valid for `server_name` right now.
+///
+/// The certificate chain is not validated against any trust root,
+/// which is the point of pinning self-signed certificates.
+pub fn verify_pins(pins: &[TlsPin], der: &[u8], server_name: &str) -> Result<()> {
+    let fingerprints = TlsFingerprints::from_der(der)?;
+
+    if !pins.iter().any(|pin| pin.matches(&fingerprints)) {
+        return Err(TlsPinMismatch(fingerprints).into());
+    }
+
+    let Ok((_, cert)) = parse_x509_certificate(der) else {
+        bail!("Parse X.509 certificate error");
+    };
+
+    check_pinned_cert(&cert, server_name)?;
+    Ok(())
+}
+
+fn check_pinned_cert(
+    cert: &X509Certificate<'_>,
+    server_name: &str,
+) -> Result<(), TlsPinnedCertError> {
+    if !cert.validity().is_valid() {
+        return Err(TlsPinnedCertError::Expired);
+    }
+
+    if !is_valid_for_name(cert, server_name) {
+        return Err(TlsPinnedCertError::NotValidForName);
+    }
+
+    Ok(())
+}
+
+/// Matches the server name against the subject alternative names of
+/// the certificate, or its common names when it has none.
+fn is_valid_for_name(cert: &X509Certificate<'_>, server_name: &str) -> bool {
+    let name = server_name.trim_matches(['[', ']']);
+    let ip = name.parse::<IpAddr>().ok();
+    let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+    match cert.subject_alternative_name() {
+        Ok(Some(san)) => {
+            san.value
+                .general_names
+                .iter()
+                .any(|general_name| match (general_name, ip) {
+                    (GeneralName::DNSName(pattern), None) => dns_name_matches(pattern, &name),
+                    (GeneralName::IPAddress(bytes), Some(IpAddr::V4(ip))) => *bytes == ip.octets(),
+                    (GeneralName::IPAddress(bytes), Some(IpAddr::V6(ip))) => *bytes == ip.octets(),
+                    _ => false,
+                })
+        }
+        Ok(None) if ip.is_none() => cert
+            .subject()
+            .iter_common_name()
+            .filter_map(|cn| cn.as_str().ok())
+            .any(|cn| dns_name_matches(cn, &name)),
+        _ => false,
+    }
+}
+
+/// Matches a DNS name against a pattern, whose leftmost label may be
+/// a wildcard (RFC 6125).
+fn dns_name_matches(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => match name.split_once('.') {
+            Some((label, rest)) => !label.is_empty() && rest == suffix,
+            None => false,
+        },
+        None => pattern == name,
+    }
+}
+
+/// Rustls verifier accepting only certificates matching one of the
+/// pins, valid for the server name and not expired.
+///
+/// The certificate chain is not validated against any trust root,
+/// which is the point of pinning self-signed certificates. Handshake
+/// signatures are still verified by the inner verifier.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+#[derive(Debug)]
+pub(crate) struct PinnedVerifier {
+    pins: Vec<TlsPin>,
+    inner: Arc<dyn ServerCertVerifier>,
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl PinnedVerifier {
+    pub(crate) fn new(pins: Vec<TlsPin>, inner: Arc<dyn ServerCertVerifier>) -> Self {
+        Self { pins, inner }
+    }
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let Ok(fingerprints) = TlsFingerprints::from_der(end_entity) else {
+            return Err(CertificateError::BadEncoding.into());
+        };
+
+        if !self.pins.iter().any(|pin| pin.matches(&fingerprints)) {
+            let err = OtherError(Arc::new(TlsPinMismatch(fingerprints)));
+            return Err(CertificateError::Other(err).into());
+        }
+
+        let Ok((_, cert)) = parse_x509_certificate(end_entity) else {
+            return Err(CertificateError::BadEncoding.into());
+        };
+
+        match check_pinned_cert(&cert, &server_name.to_str()) {
+            Ok(()) => Ok(ServerCertVerified::assertion()),
+            Err(TlsPinnedCertError::Expired) => Err(CertificateError::Expired.into()),
+            Err(TlsPinnedCertError::NotValidForName) => {
+                Err(CertificateError::NotValidForName.into())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+fn colon_hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dns_name_matches, parse_fingerprint, verify_pins, TlsFingerprints, TlsPin, TlsPinMismatch,
+        TlsPinnedCertError,
+    };
+
+    const LEAF: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/leaf.der"
+    ));
+    const EXPIRED: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/expired.der"
+    ));
+
+    const LEAF_CERT: &str = "71:F7:FB:28:DE:B1:3C:E1:94:74:B7:9A:C9:6B:AA:43:4E:46:34:87:B1:D7:45:8F:3D:EA:DB:1F:7C:C9:E5:AD";
+    const LEAF_SPKI: &str = "971b1d2f1a1ab0bb51c1832919497dc5d8d41a49dd6331bd7359c3ae44d2d08f";
+    const LEAF_SPKI_BASE64: &str = "lxsdLxoasLtRwYMpGUl9xdjUGkndYzG9c1nDrkTS0I8=";
+
+    fn colon_separated(hex: &str) -> String {
+        let digits: Vec<_> = hex.as_bytes().chunks(2).collect();
+        String::from_utf8(digits.join(&b':')).unwrap()
+    }
+
+    fn pinned_cert_error(pins: &[TlsPin], der: &[u8], server_name: &str) -> TlsPinnedCertError {
+        let err = verify_pins(pins, der, server_name).unwrap_err();
+        *err.downcast_ref::<TlsPinnedCertError>().unwrap()
+    }
+
+    #[test]
+    fn fingerprint_formats() {
+        let expected = parse_fingerprint(LEAF_SPKI).unwrap();
+
+        let pins = [
+            LEAF_SPKI.to_uppercase(),
+            format!("  {LEAF_SPKI}\n"),
+            format!("sha256:{LEAF_SPKI}"),
+            format!("SHA256/{LEAF_SPKI}"),
+            LEAF_SPKI_BASE64.to_owned(),
+            format!("sha256//{LEAF_SPKI_BASE64}"),
+            colon_separated(LEAF_SPKI),
+        ];
+
+        for pin in pins {
+            assert_eq!(parse_fingerprint(&pin).unwrap(), expected, "{pin}");
+        }
+    }
+
+    #[test]
+    fn invalid_fingerprints() {
+        assert!(parse_fingerprint("sha1:AB:CD").is_err());
+        assert!(parse_fingerprint(&LEAF_SPKI[2..]).is_err());
+        assert!(parse_fingerprint("bm90IDMyIGJ5dGVz").is_err());
+        assert!(parse_fingerprint("not a fingerprint").is_err());
+        assert!(TlsPin::Cert("zz".into()).fingerprint().is_err());
+    }
+
+    #[test]
+    fn fingerprints() {
+        let fingerprints = TlsFingerprints::from_der(LEAF).unwrap();
+
+        assert_eq!(fingerprints.cert_hex(), LEAF_CERT);
+        assert_eq!(
+            fingerprints.spki_hex(),
+            colon_separated(&LEAF_SPKI.to_uppercase())
+        );
+
+        assert!(TlsFingerprints::from_der(b"not a cert").is_err());
+    }
+
+    #[test]
+    fn cert_and_spki_pins() {
+        let name = "mail.example.com";
+
+        verify_pins(&[TlsPin::Cert(LEAF_CERT.into())], LEAF, name).unwrap();
+        verify_pins(&[TlsPin::Spki(LEAF_SPKI.into())], LEAF, name).unwrap();
+
+        // a fingerprint only matches the kind it was pinned as
+        let pins = [
+            TlsPin::Cert(LEAF_SPKI.into()),
+            TlsPin::Spki(LEAF_CERT.into()),
+        ];
+        let err = verify_pins(&pins, LEAF, name).unwrap_err();
+        let mismatch = err.downcast_ref::<TlsPinMismatch>().unwrap();
+        assert_eq!(mismatch.0, TlsFingerprints::from_der(LEAF).unwrap());
+
+        // invalid pins are ignored rather than matching anything
+        let pins = [
+            TlsPin::Cert("invalid".into()),
+            TlsPin::Spki(LEAF_SPKI.into()),
+        ];
+        verify_pins(&pins, LEAF, name).unwrap();
+    }
+
+    #[test]
+    fn pinned_cert_names() {
+        let pins = [TlsPin::Cert(LEAF_CERT.into())];
+
+        for name in [
+            "mail.example.com",
+            "MAIL.example.com.",
+            "imap.example.org",
+            "127.0.0.1",
+            "[127.0.0.1]",
+        ] {
+            verify_pins(&pins, LEAF, name).unwrap();
+        }
+
+        for name in [
+            "example.com",
+            "example.org",
+            "a.b.example.org",
+            "127.0.0.2",
+            "::1",
+        ] {
+            assert_eq!(
+                pinned_cert_error(&pins, LEAF, name),
+                TlsPinnedCertError::NotValidForName,
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn pinned_cert_expired() {
+        let fingerprints = TlsFingerprints::from_der(EXPIRED).unwrap();
+        let pins = [TlsPin::Cert(fingerprints.cert_hex())];
+
+        assert_eq!(
+            pinned_cert_error(&pins, EXPIRED, "mail.example.com"),
+            TlsPinnedCertError::Expired
+        );
+    }
+
+    #[test]
+    fn wildcard_names() {
+        assert!(dns_name_matches("*.example.org", "mail.example.org"));
+        assert!(dns_name_matches("*.Example.org.", "mail.example.org"));
+        assert!(!dns_name_matches("*.example.org", "example.org"));
+        assert!(!dns_name_matches("*.example.org", ".example.org"));
+        assert!(!dns_name_matches("*.example.org", "a.mail.example.org"));
+        assert!(!dns_name_matches("mail.*.org", "mail.example.org"));
+    }
+}
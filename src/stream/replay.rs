@@ -0,0 +1,512 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+};
+#[cfg(feature = "tokio")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Context as _, Result};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Recorded exchange between a client and a server, replayed by
+/// [`ReplayStream`] in place of a real connection.
+///
+/// The text format is the one produced by
+/// [`Transcript::Record`](super::Transcript::Record): one entry per
+/// line, `C: ` for bytes sent by the client and `S: ` for bytes sent
+/// by the server, followed by the bytes escaped like Rust strings
+/// (`\r`, `\n`, `\\`, `\xNN`). Blank lines and lines starting with
+/// `#` are ignored, so transcripts can also be written by hand:
+///
+/// ```text
+/// S: * OK IMAP4rev1 ready\r\n
+/// C: A1 CAPABILITY\r\n
+/// S: * CAPABILITY IMAP4rev1 AUTH=PLAIN\r\nA1 OK done\r\n
+/// ```
+///
+/// In client bytes, `\?` matches whatever the client writes until
+/// the bytes following it, like IMAP tags or HTTP headers which vary
+/// between runs. In server bytes, `\?` is replaced by what the first
+/// `\?` of the previous client bytes matched:
+///
+/// ```text
+/// C: \? NOOP\r\n
+/// S: \? OK done\r\n
+/// ```
+///
+/// Exchanges depending on random data cannot be replayed, like SCRAM
+/// authentication whose client nonce changes on every run.
+#[derive(Clone, Debug, Default)]
+pub struct Replay {
+    exchanges: Vec<(ReplayDirection, Vec<ReplayToken>)>,
+}
+
+/// Recorded byte, or `\?` wildcard.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ReplayToken {
+    Byte(u8),
+    Any,
+}
+
+/// Returns the bytes following the token at `index`, up to the next
+/// wildcard.
+fn literal_after(tokens: &[ReplayToken], index: usize) -> Vec<u8> {
+    let mut literal = Vec::new();
+
+    for token in &tokens[index + 1..] {
+        match token {
+            ReplayToken::Byte(b) => literal.push(*b),
+            ReplayToken::Any => break,
+        }
+    }
+
+    literal
+}
+
+/// Direction of a recorded exchange.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplayDirection {
+    Client,
+    Server,
+}
+
+impl Replay {
+    /// Parses a recorded transcript.
+    ///
+    /// Consecutive entries of the same direction are merged, so that
+    /// replays do not depend on how bytes were chunked when recorded.
+    pub fn parse(transcript: &str) -> Result<Self> {
+        let mut exchanges: Vec<(ReplayDirection, Vec<ReplayToken>)> = Vec::new();
+
+        for (i, line) in transcript.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (direction, bytes) = if let Some(bytes) = line.strip_prefix("C: ") {
+                (ReplayDirection::Client, bytes)
+            } else if let Some(bytes) = line.strip_prefix("S: ") {
+                (ReplayDirection::Server, bytes)
+            } else {
+                bail!("Invalid replay entry at line {}, expected C: or S:", i + 1);
+            };
+
+            let bytes = unescape(bytes)
+                .with_context(|| format!("Invalid replay entry at line {}", i + 1))?;
+
+            if bytes.is_empty() {
+                continue;
+            }
+
+            match exchanges.last_mut() {
+                Some((last, prev)) if *last == direction => prev.extend(bytes),
+                _ => exchanges.push((direction, bytes)),
+            }
+        }
+
+        for (i, (direction, tokens)) in exchanges.iter().enumerate() {
+            if *direction != ReplayDirection::Client {
+                continue;
+            }
+
+            for (j, token) in tokens.iter().enumerate() {
+                if *token == ReplayToken::Any && literal_after(tokens, j).is_empty() {
+                    bail!(
+                        "Invalid replay exchange #{}, \\? must be followed by bytes",
+                        i + 1
+                    );
+                }
+            }
+        }
+
+        Ok(Self { exchanges })
+    }
+
+    /// Reads and parses the recorded transcript at the given path.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let transcript = fs::read_to_string(path)
+            .with_context(|| format!("Read replay at {} error", path.display()))?;
+        Self::parse(&transcript)
+    }
+
+    /// Returns a new stream replaying the transcript from the start.
+    pub fn stream(&self) -> ReplayStream {
+        ReplayStream {
+            exchanges: self.exchanges.iter().cloned().collect(),
+            index: 0,
+            offset: 0,
+            matched: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+/// Stream serving the server side of a [`Replay`], and checking that
+/// the client writes exactly the recorded bytes.
+///
+/// Writing unexpected bytes fails with an error showing the expected
+/// and actual bytes.
+#[derive(Debug)]
+pub struct ReplayStream {
+    exchanges: VecDeque<(ReplayDirection, Vec<ReplayToken>)>,
+    /// Index of the current exchange in the transcript.
+    index: usize,
+    /// Number of tokens of the current client exchange, or of bytes
+    /// of the current server exchange, already consumed.
+    offset: usize,
+    /// Bytes matched by the wildcards of the last client exchange,
+    /// the one in progress included.
+    matched: Vec<Vec<u8>>,
+    /// Bytes matched by the wildcard in progress.
+    pending: Vec<u8>,
+}
+
+impl ReplayStream {
+    /// Checks that the whole transcript has been replayed.
+    pub fn finish(&self) -> Result<()> {
+        match self.exchanges.front() {
+            None => Ok(()),
+            Some((ReplayDirection::Client, tokens)) => bail!(
+                "replay ended early at exchange #{}, still expecting C: {}",
+                self.index + 1,
+                escape_tokens(&tokens[self.offset..]),
+            ),
+            Some((ReplayDirection::Server, tokens)) => bail!(
+                "replay ended early at exchange #{}, still expecting S: {}",
+                self.index + 1,
+                escape(&self.expand(tokens)[self.offset..]),
+            ),
+        }
+    }
+
+    /// Returns the bytes of a server exchange, wildcards replaced.
+    fn expand(&self, tokens: &[ReplayToken]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(tokens.len());
+
+        for token in tokens {
+            match token {
+                ReplayToken::Byte(b) => bytes.push(*b),
+                ReplayToken::Any => bytes.extend(self.matched.first().into_iter().flatten()),
+            }
+        }
+
+        bytes
+    }
+
+    fn advance(&mut self, offset: usize, len: usize) {
+        self.offset = offset;
+
+        if self.offset == len {
+            self.exchanges.pop_front();
+            self.index += 1;
+            self.offset = 0;
+        }
+    }
+
+    fn replay_read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (n, len) = match self.exchanges.front() {
+            None => return Ok(0),
+            Some((ReplayDirection::Server, tokens)) => {
+                let bytes = self.expand(tokens);
+                let rest = &bytes[self.offset..];
+                let n = rest.len().min(buf.len());
+                buf[..n].copy_from_slice(&rest[..n]);
+                (n, bytes.len())
+            }
+            Some((ReplayDirection::Client, tokens)) => {
+                let err = format!(
+                    "replay mismatch at exchange #{}: client reads, but is expected to write C: {}",
+                    self.index + 1,
+                    escape_tokens(&tokens[self.offset..]),
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        };
+
+        self.advance(self.offset + n, len);
+        Ok(n)
+    }
+
+    fn replay_write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let tokens = match self.exchanges.front() {
+            Some((ReplayDirection::Client, tokens)) => tokens,
+            Some((ReplayDirection::Server, tokens)) => {
+                let err = format!(
+                    "replay mismatch at exchange #{}: client writes C: {}, but is expected to read S: {}",
+                    self.index + 1,
+                    escape(buf),
+                    escape(&self.expand(tokens)[self.offset..]),
+                );
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+            None => {
+                let err = format!("replay ended, but client writes C: {}", escape(buf));
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        };
+
+        // changes are only kept if the bytes match
+        let mut matched = if self.offset == 0 && self.pending.is_empty() {
+            Vec::new()
+        } else {
+            self.matched.clone()
+        };
+        let mut pending = self.pending.clone();
+        let mut offset = self.offset;
+        let mut n = 0;
+
+        while n < buf.len() && offset < tokens.len() {
+            match tokens[offset] {
+                ReplayToken::Byte(b) if b == buf[n] => {
+                    offset += 1;
+                    n += 1;
+                }
+                ReplayToken::Byte(_) => {
+                    let err = mismatch(
+                        self.index,
+                        &tokens[self.offset..],
+                        buf,
+                        offset - self.offset,
+                    );
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+                ReplayToken::Any => {
+                    // the wildcard ends with the first occurrence of
+                    // the bytes following it
+                    let literal = literal_after(tokens, offset);
+                    pending.push(buf[n]);
+                    n += 1;
+
+                    if pending.ends_with(&literal) {
+                        pending.truncate(pending.len() - literal.len());
+                        matched.push(pending.split_off(0));
+                        offset += 1 + literal.len();
+                    }
+                }
+            }
+        }
+
+        let len = tokens.len();
+        self.matched = matched;
+        self.pending = pending;
+        self.advance(offset, len);
+        Ok(n)
+    }
+}
+
+impl Read for ReplayStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.replay_read(buf)
+    }
+}
+
+impl Write for ReplayStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.replay_write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncRead for ReplayStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let n = self.get_mut().replay_read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncWrite for ReplayStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.get_mut().replay_write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Describes where the bytes written by the client diverge from the
+/// recorded ones, the first `at` expected tokens being matched.
+fn mismatch(index: usize, expected: &[ReplayToken], actual: &[u8], at: usize) -> String {
+    format!(
+        "replay mismatch at exchange #{}:\nexpected C: {}\n  actual C: {}\n            {}^",
+        index + 1,
+        escape_tokens(expected),
+        escape(actual),
+        " ".repeat(escape_tokens(&expected[..at]).len()),
+    )
+}
+
+/// Escapes recorded tokens into the transcript text format.
+fn escape_tokens(tokens: &[ReplayToken]) -> String {
+    let mut escaped = String::with_capacity(tokens.len());
+
+    for token in tokens {
+        match token {
+            ReplayToken::Byte(b) => escaped.push_str(&escape(&[*b])),
+            ReplayToken::Any => escaped.push_str("\\?"),
+        }
+    }
+
+    escaped
+}
+
+/// Escapes bytes into the transcript text format.
+pub(crate) fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+
+    for b in bytes {
+        match b {
+            b'\r' => escaped.push_str("\\r"),
+            b'\n' => escaped.push_str("\\n"),
+            b'\t' => escaped.push_str("\\t"),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(*b as char),
+            b => {
+                let _ = write!(escaped, "\\x{b:02x}");
+            }
+        }
+    }
+
+    escaped
+}
+
+fn unescape(escaped: &str) -> Result<Vec<ReplayToken>> {
+    let mut bytes = Vec::with_capacity(escaped.len());
+    let mut chars = escaped.bytes();
+
+    while let Some(b) = chars.next() {
+        if b != b'\\' {
+            bytes.push(ReplayToken::Byte(b));
+            continue;
+        }
+
+        match chars.next() {
+            Some(b'r') => bytes.push(ReplayToken::Byte(b'\r')),
+            Some(b'n') => bytes.push(ReplayToken::Byte(b'\n')),
+            Some(b't') => bytes.push(ReplayToken::Byte(b'\t')),
+            Some(b'\\') => bytes.push(ReplayToken::Byte(b'\\')),
+            Some(b'?') => bytes.push(ReplayToken::Any),
+            Some(b'x') => {
+                let hex = [chars.next(), chars.next()];
+                let Some(hex) = hex.iter().copied().collect::<Option<Vec<u8>>>() else {
+                    bail!("truncated \\x escape");
+                };
+                let Ok(b) = u8::from_str_radix(&String::from_utf8_lossy(&hex), 16) else {
+                    bail!("invalid \\x escape");
+                };
+                bytes.push(ReplayToken::Byte(b));
+            }
+            Some(b) => bail!("unknown escape \\{}", b as char),
+            None => bail!("trailing backslash"),
+        }
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+
+    use super::Replay;
+
+    const TRANSCRIPT: &str = r"
+# greeting
+S: * OK ready\r\n
+C: A1 NOOP\r\n
+S: A1 OK done\r\n
+";
+
+    #[test]
+    fn replay() {
+        let mut stream = Replay::parse(TRANSCRIPT).unwrap().stream();
+        let mut buf = [0; 64];
+
+        let n = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"* OK ready\r\n");
+        assert!(stream.finish().is_err());
+
+        stream.write_all(b"A1 NOOP\r\n").unwrap();
+
+        let n = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"A1 OK done\r\n");
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        stream.finish().unwrap();
+    }
+
+    #[test]
+    fn replay_mismatch() {
+        let mut stream = Replay::parse(TRANSCRIPT).unwrap().stream();
+
+        let err = stream.write(b"A1 NOOP\r\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let n = stream.read(&mut [0; 64]).unwrap();
+        assert_eq!(n, 12);
+        let err = stream.write(b"A1 NOPE\r\n").unwrap_err();
+        assert!(err.to_string().contains("expected C: A1 NOOP\\r\\n"));
+    }
+
+    #[test]
+    fn replay_wildcard() {
+        const TRANSCRIPT: &str = r"
+C: \? NOOP\r\n
+C: \?\r\n\r\n
+S: \? OK done\r\n
+";
+
+        let mut stream = Replay::parse(TRANSCRIPT).unwrap().stream();
+        let mut buf = [0; 64];
+
+        stream.write_all(b"0.").unwrap();
+        stream.write_all(b"12 NO").unwrap();
+        stream
+            .write_all(b"OP\r\nHost: a\r\nAccept: *\r\n\r\n")
+            .unwrap();
+
+        let n = stream.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"0.12 OK done\r\n");
+        stream.finish().unwrap();
+    }
+
+    #[test]
+    fn replay_wildcard_mismatch() {
+        let replay = Replay::parse(r"C: \? NOOP\r\n").unwrap();
+        let mut stream = replay.stream();
+
+        stream.write_all(b"A1 NOPE\r\n").unwrap();
+
+        let err = stream.read(&mut [0; 64]).unwrap_err();
+        let err = err.to_string();
+        assert!(err.ends_with(r"expected to write C: \? NOOP\r\n"), "{err}");
+
+        let err = Replay::parse(r"C: A1 \?").unwrap_err();
+        assert!(format!("{err:#}").contains("must be followed by bytes"));
+    }
+}
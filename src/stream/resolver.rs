@@ -0,0 +1,289 @@
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+};
+
+use anyhow::{Context, Result};
+use log::debug;
+
+/// Function resolving a host name into IP addresses.
+pub type ResolverLookup = Arc<dyn Fn(&str) -> io::Result<Vec<IpAddr>> + Send + Sync>;
+
+/// Function answering SRV lookups, given the full record name (for
+/// example `_imaps._tcp.example.com`).
+pub type ResolverSrvLookup = Arc<dyn Fn(&str) -> io::Result<Vec<SrvRecord>> + Send + Sync>;
+
+/// Resolver of host names, used to connect to servers.
+///
+/// Host names are looked up in the static overrides first, then
+/// given to the custom lookup function if any, otherwise to the
+/// system resolver. IP addresses are never resolved.
+#[derive(Clone, Default)]
+pub struct Resolver {
+    /// Static addresses of host names, like curl's `--resolve`.
+    /// Host names are matched case-insensitively, whatever the port.
+    pub overrides: HashMap<String, Vec<IpAddr>>,
+    /// Resolves host names instead of the system resolver.
+    pub lookup: Option<ResolverLookup>,
+    /// Answers SRV lookups instead of DNS, which requires the `dns`
+    /// cargo feature.
+    pub srv_lookup: Option<ResolverSrvLookup>,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("overrides", &self.overrides)
+            .field("lookup", &self.lookup.is_some())
+            .field("srv_lookup", &self.srv_lookup.is_some())
+            .finish()
+    }
+}
+
+impl Resolver {
+    /// Resolves `host` to the given addresses, whatever the port.
+    pub fn with_override(mut self, host: impl ToString, addrs: Vec<IpAddr>) -> Self {
+        let host = host.to_string();
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.overrides.insert(host, addrs);
+        self
+    }
+
+    pub fn with_lookup(
+        mut self,
+        lookup: impl Fn(&str) -> io::Result<Vec<IpAddr>> + Send + Sync + 'static,
+    ) -> Self {
+        self.lookup = Some(Arc::new(lookup));
+        self
+    }
+
+    pub fn with_srv_lookup(
+        mut self,
+        lookup: impl Fn(&str) -> io::Result<Vec<SrvRecord>> + Send + Sync + 'static,
+    ) -> Self {
+        self.srv_lookup = Some(Arc::new(lookup));
+        self
+    }
+
+    /// Resolves `host:port` into socket addresses, in the order
+    /// given by the resolver.
+    pub fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let name = host.trim_matches(['[', ']']);
+
+        if let Ok(ip) = name.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+
+        if let Some(ips) = self.overrides.get(&name) {
+            debug!("resolving {host} from overrides");
+            return Ok(socket_addrs(ips, port));
+        }
+
+        if let Some(lookup) = &self.lookup {
+            debug!("resolving {host} with custom lookup");
+            let ips = lookup(&name).with_context(|| format!("Resolve {host} error"))?;
+            return Ok(socket_addrs(&ips, port));
+        }
+
+        let addrs = (host, port)
+            .to_socket_addrs()
+            .with_context(|| format!("Resolve {host} error"))?
+            .collect();
+
+        Ok(addrs)
+    }
+
+    /// Looks up the SRV records of the given name (for example
+    /// `_imaps._tcp.example.com`), ordered by priority then weight.
+    ///
+    /// Returns an empty list when the name has no record, or when the
+    /// service is decidedly not available (RFC 2782).
+    pub fn resolve_srv(&self, name: &str) -> Result<Vec<SrvRecord>> {
+        let mut records = match &self.srv_lookup {
+            Some(lookup) => {
+                debug!("resolving SRV {name} with custom lookup");
+                lookup(name).with_context(|| format!("Resolve SRV {name} error"))?
+            }
+            None => dns_srv_lookup(name)?,
+        };
+
+        if let [record] = records.as_slice() {
+            if record.target == "." || record.target.is_empty() {
+                debug!("service {name} not available");
+                return Ok(Vec::new());
+            }
+        }
+
+        // RFC 2782 picks randomly among records of equal priority,
+        // proportionally to their weight: heavier records come first
+        // here, for reproducible results
+        records.sort_by(|a, b| {
+            a.priority
+                .cmp(&b.priority)
+                .then_with(|| b.weight.cmp(&a.weight))
+        });
+
+        Ok(records)
+    }
+}
+
+/// Record of a DNS SRV lookup, designating a server providing a
+/// service.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// Host name of the server, without trailing dot.
+    pub target: String,
+}
+
+fn socket_addrs(ips: &[IpAddr], port: u16) -> Vec<SocketAddr> {
+    ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect()
+}
+
+#[cfg(feature = "dns")]
+fn dns_srv_lookup(name: &str) -> Result<Vec<SrvRecord>> {
+    use hickory_resolver::error::ResolveErrorKind;
+
+    debug!("resolving SRV {name}");
+
+    let resolver = hickory_resolver::Resolver::from_system_conf()
+        .context("Read system DNS configuration error")?;
+
+    let lookup = match resolver.srv_lookup(name) {
+        Ok(lookup) => lookup,
+        Err(err) if matches!(err.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+            return Ok(Vec::new())
+        }
+        Err(err) => return Err(err).with_context(|| format!("Resolve SRV {name} error")),
+    };
+
+    let records = lookup
+        .iter()
+        .map(|srv| SrvRecord {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            port: srv.port(),
+            target: srv.target().to_utf8().trim_end_matches('.').to_owned(),
+        })
+        .collect();
+
+    Ok(records)
+}
+
+#[cfg(not(feature = "dns"))]
+fn dns_srv_lookup(name: &str) -> Result<Vec<SrvRecord>> {
+    anyhow::bail!("Resolve SRV {name} error: missing cargo feature `dns`");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    };
+
+    use super::{Resolver, SrvRecord};
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const OTHER_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    fn srv(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 993,
+            target: target.to_owned(),
+        }
+    }
+
+    #[test]
+    fn overrides_first() {
+        let resolver = Resolver::default()
+            .with_override("mail.example.com", vec![IP])
+            .with_lookup(|_| Ok(vec![OTHER_IP]));
+
+        let addrs = resolver.resolve("mail.example.com", 993).unwrap();
+        assert_eq!(addrs, vec![SocketAddr::new(IP, 993)]);
+
+        let addrs = resolver.resolve("smtp.example.com", 465).unwrap();
+        assert_eq!(addrs, vec![SocketAddr::new(OTHER_IP, 465)]);
+    }
+
+    #[test]
+    fn normalized_names() {
+        let resolver = Resolver::default().with_override("Mail.Example.COM.", vec![IP]);
+
+        for host in ["mail.example.com", "MAIL.example.com", "mail.example.com."] {
+            let addrs = resolver.resolve(host, 143).unwrap();
+            assert_eq!(addrs, vec![SocketAddr::new(IP, 143)], "{host}");
+        }
+
+        let resolver = Resolver::default().with_lookup(|name| {
+            assert_eq!(name, "mail.example.com");
+            Ok(vec![IP])
+        });
+
+        resolver.resolve("Mail.Example.com.", 143).unwrap();
+    }
+
+    #[test]
+    fn ip_literals() {
+        let resolver = Resolver::default()
+            .with_override("192.0.2.1", vec![OTHER_IP])
+            .with_lookup(|name| Err(io::Error::other(format!("unexpected lookup of {name}"))));
+
+        let addrs = resolver.resolve("192.0.2.1", 993).unwrap();
+        assert_eq!(addrs, vec![SocketAddr::new(IP, 993)]);
+
+        let ip = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let addrs = resolver.resolve("[::1]", 993).unwrap();
+        assert_eq!(addrs, vec![SocketAddr::new(ip, 993)]);
+    }
+
+    #[test]
+    fn lookup_error() {
+        let resolver = Resolver::default().with_lookup(|_| Err(io::ErrorKind::NotFound.into()));
+        let err = resolver.resolve("mail.example.com", 993).unwrap_err();
+        assert_eq!(err.to_string(), "Resolve mail.example.com error");
+    }
+
+    #[test]
+    fn srv_not_available() {
+        let resolver = Resolver::default().with_srv_lookup(|_| Ok(vec![srv(0, 0, ".")]));
+        let records = resolver.resolve_srv("_imaps._tcp.example.com").unwrap();
+        assert!(records.is_empty());
+
+        let resolver = Resolver::default().with_srv_lookup(|_| Ok(Vec::new()));
+        let records = resolver.resolve_srv("_imaps._tcp.example.com").unwrap();
+        assert!(records.is_empty());
+    }
+
+    #[test]
+    fn srv_order() {
+        let resolver = Resolver::default().with_srv_lookup(|name| {
+            assert_eq!(name, "_imaps._tcp.example.com");
+            Ok(vec![
+                srv(20, 0, "backup.example.com"),
+                srv(10, 10, "light.example.com"),
+                srv(10, 60, "heavy.example.com"),
+            ])
+        });
+
+        let records = resolver.resolve_srv("_imaps._tcp.example.com").unwrap();
+        let targets: Vec<_> = records.iter().map(|r| r.target.as_str()).collect();
+        assert_eq!(
+            targets,
+            [
+                "heavy.example.com",
+                "light.example.com",
+                "backup.example.com"
+            ]
+        );
+    }
+}
@@ -0,0 +1,236 @@
+use std::path::PathBuf;
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use log::{debug, warn};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use rustls::{
+    client::{
+        ClientSessionMemoryCache, ClientSessionStore, Resumption, Tls12ClientSessionValue,
+        Tls13ClientSessionValue,
+    },
+    pki_types::ServerName,
+    NamedGroup,
+};
+
+/// Default number of servers whose sessions are kept.
+const DEFAULT_SIZE: usize = 256;
+
+/// Cache of TLS sessions, so that connections opened with the same
+/// [`Tls`](super::Tls) value, or its clones, resume previous sessions
+/// (session IDs, tickets or TLS 1.3 PSK) instead of doing full
+/// handshakes.
+///
+/// Sessions are only kept in memory, for the lifetime of the
+/// process: rustls does not expose a way to serialize them. A
+/// [persistent](Self::persistent) cache also saves on disk the key
+/// exchange group picked by each server, so that the first handshake
+/// of the next runs offers it right away. Only the rustls provider
+/// supports resumption, native-tls does not give control over
+/// sessions.
+#[derive(Clone, Debug)]
+pub struct TlsSessionCache {
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    store: Option<Arc<dyn ClientSessionStore>>,
+}
+
+impl TlsSessionCache {
+    /// Creates a cache keeping the sessions of at most `size`
+    /// servers.
+    #[allow(unused_variables)]
+    pub fn new(size: usize) -> Self {
+        Self {
+            #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+            store: Some(Arc::new(ClientSessionMemoryCache::new(size))),
+        }
+    }
+
+    /// Creates a cache like the default one, also keeping the key
+    /// exchange groups of servers in the file at `path`, created if
+    /// missing.
+    #[allow(unused_variables)]
+    pub fn persistent(path: impl Into<PathBuf>) -> Self {
+        Self {
+            #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+            store: Some(Arc::new(PersistentSessionStore::open(
+                path.into(),
+                DEFAULT_SIZE,
+            ))),
+        }
+    }
+
+    /// Creates a cache keeping nothing: every connection does a full
+    /// handshake.
+    pub fn disabled() -> Self {
+        Self {
+            #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+            store: None,
+        }
+    }
+
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    pub(crate) fn rustls_resumption(&self) -> Resumption {
+        match &self.store {
+            Some(store) => Resumption::store(store.clone()),
+            None => Resumption::disabled(),
+        }
+    }
+}
+
+impl Default for TlsSessionCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_SIZE)
+    }
+}
+
+/// Session store keeping sessions in memory, and key exchange hints
+/// in a file, one `<server name> <group>` line per server.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+#[derive(Debug)]
+struct PersistentSessionStore {
+    memory: ClientSessionMemoryCache,
+    path: PathBuf,
+    hints: Mutex<BTreeMap<String, u16>>,
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl PersistentSessionStore {
+    fn open(path: PathBuf, size: usize) -> Self {
+        let mut hints = BTreeMap::new();
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let Some((name, group)) = line.trim().split_once(' ') else {
+                        continue;
+                    };
+
+                    match group.parse() {
+                        Ok(group) => {
+                            hints.insert(name.to_owned(), group);
+                        }
+                        Err(_) => warn!("invalid TLS key exchange hint for {name}: {group}"),
+                    }
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+            Err(err) => warn!("cannot read TLS session cache {}: {err}", path.display()),
+        }
+
+        debug!("loaded {} TLS key exchange hints", hints.len());
+
+        Self {
+            memory: ClientSessionMemoryCache::new(size),
+            path,
+            hints: Mutex::new(hints),
+        }
+    }
+
+    fn save(&self, hints: &BTreeMap<String, u16>) {
+        let mut contents = String::new();
+
+        for (name, group) in hints {
+            let _ = writeln!(contents, "{name} {group}");
+        }
+
+        if let Some(dir) = self.path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+
+        if let Err(err) = fs::write(&self.path, contents) {
+            warn!(
+                "cannot write TLS session cache {}: {err}",
+                self.path.display()
+            );
+        }
+    }
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl ClientSessionStore for PersistentSessionStore {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.memory.set_kx_hint(server_name.clone(), group);
+
+        let mut hints = self.hints.lock().unwrap_or_else(PoisonError::into_inner);
+        let group = u16::from(group);
+
+        if hints.insert(server_name.to_str().into_owned(), group) != Some(group) {
+            self.save(&hints);
+        }
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        if let Some(group) = self.memory.kx_hint(server_name) {
+            return Some(group);
+        }
+
+        let hints = self.hints.lock().unwrap_or_else(PoisonError::into_inner);
+        hints
+            .get(server_name.to_str().as_ref())
+            .copied()
+            .map(NamedGroup::from)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.memory.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        self.memory.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.memory.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: ServerName<'static>,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.memory.insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &ServerName<'static>,
+    ) -> Option<Tls13ClientSessionValue> {
+        self.memory.take_tls13_ticket(server_name)
+    }
+}
+
+#[cfg(all(test, any(feature = "rustls-aws", feature = "rustls-ring")))]
+mod tests {
+    use std::{env, fs, process};
+
+    use rustls::{client::ClientSessionStore, pki_types::ServerName, NamedGroup};
+
+    use super::PersistentSessionStore;
+
+    #[test]
+    fn persistent_kx_hints() {
+        let path = env::temp_dir()
+            .join(format!("session-cache-{}", process::id()))
+            .join("hints");
+        let name = ServerName::try_from("mail.example.com").unwrap();
+
+        let store = PersistentSessionStore::open(path.clone(), 8);
+        assert_eq!(store.kx_hint(&name), None);
+        store.set_kx_hint(name.clone(), NamedGroup::secp256r1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "mail.example.com 23\n");
+
+        let store = PersistentSessionStore::open(path.clone(), 8);
+        assert_eq!(store.kx_hint(&name), Some(NamedGroup::secp256r1));
+
+        let other = ServerName::try_from("other.example.com").unwrap();
+        assert_eq!(store.kx_hint(&other), None);
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,861 @@
+use std::path::PathBuf;
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use std::{error, fmt, fs, sync::Arc};
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use anyhow::{Context, Result};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use log::{debug, warn};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, CertificateRevocationListDer, ServerName, UnixTime},
+    CertificateError, DigitallySignedStruct, OtherError, SignatureScheme,
+};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use sha1::Sha1;
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use sha2::{Digest, Sha256, Sha384, Sha512};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use x509_parser::{
+    asn1_rs::{Any, Class, FromDer, Tag},
+    parse_x509_certificate,
+    pem::Pem,
+    time::ASN1Time,
+};
+
+/// Tolerated clock skew when checking OCSP response validity, in
+/// seconds.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+const OCSP_CLOCK_SKEW: u64 = 5 * 60;
+
+/// Maximum age of OCSP responses without next update, in seconds.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+const OCSP_MAX_AGE: u64 = 7 * 24 * 60 * 60;
+
+/// DER-encoded OIDs of the hash algorithms supported in OCSP cert
+/// IDs.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+const OID_SHA256: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01];
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+const OID_SHA384: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x02];
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+const OID_SHA512: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x03];
+
+/// Certificate revocation checking, only supported by the `rustls`
+/// provider.
+///
+/// Revoked certificates are always rejected. The policy decides what
+/// happens when the revocation status cannot be determined. Pinned
+/// certificates (see [`Tls::pins`](super::Tls::pins)) skip chain
+/// validation, hence CRL checks.
+#[derive(Clone, Debug, Default)]
+pub struct TlsRevocation {
+    /// Certificate revocation lists, PEM or DER encoded.
+    ///
+    /// Since CRLs are checked by the WebPKI verifier instead of the
+    /// platform one, certificates are then validated against the
+    /// system trust store as found by `rustls-native-certs`.
+    pub crls: Vec<PathBuf>,
+    /// Checks the OCSP response stapled by the server.
+    pub ocsp: bool,
+    /// Only checks the revocation status of the server certificate,
+    /// not the one of intermediate certificates. Only applies to
+    /// CRLs.
+    pub end_entity_only: bool,
+    pub policy: TlsRevocationPolicy,
+}
+
+/// What to do when the revocation status of a certificate cannot be
+/// determined: no CRL from its issuer, expired CRL, missing or
+/// invalid OCSP response.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TlsRevocationPolicy {
+    /// Rejects the certificate.
+    #[default]
+    HardFail,
+    /// Accepts the certificate, logging a warning.
+    SoftFail,
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl TlsRevocation {
+    /// Reads the configured CRLs.
+    pub(crate) fn read_crls(&self) -> Result<Vec<CertificateRevocationListDer<'static>>> {
+        let mut crls = Vec::new();
+
+        for path in &self.crls {
+            let bytes = fs::read(path)
+                .with_context(|| format!("Read TLS CRL at {} error", path.display()))?;
+
+            if !bytes.starts_with(b"-----") {
+                crls.push(bytes.into());
+                continue;
+            }
+
+            for block in Pem::iter_from_buffer(&bytes) {
+                let block =
+                    block.with_context(|| format!("Parse TLS CRL at {} error", path.display()))?;
+
+                if block.label == "X509 CRL" {
+                    crls.push(block.contents.into());
+                }
+            }
+        }
+
+        debug!("using {} TLS CRL(s)", crls.len());
+        Ok(crls)
+    }
+}
+
+/// Rustls verifier checking the OCSP response stapled by the server,
+/// once the inner verifier accepted the certificate.
+///
+/// The response must be signed by the issuer of the server
+/// certificate, or by a responder the issuer delegated OCSP signing
+/// to.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+#[derive(Debug)]
+pub(crate) struct OcspVerifier {
+    policy: TlsRevocationPolicy,
+    provider: Arc<CryptoProvider>,
+    inner: Arc<dyn ServerCertVerifier>,
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl OcspVerifier {
+    pub(crate) fn new(
+        policy: TlsRevocationPolicy,
+        provider: Arc<CryptoProvider>,
+        inner: Arc<dyn ServerCertVerifier>,
+    ) -> Self {
+        Self {
+            policy,
+            provider,
+            inner,
+        }
+    }
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl ServerCertVerifier for OcspVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+
+        let checker = OcspChecker {
+            provider: &self.provider,
+            now: now.as_secs(),
+        };
+
+        let err = match checker.check(end_entity, intermediates, ocsp_response) {
+            Ok(OcspStatus::Good) => return Ok(verified),
+            Ok(OcspStatus::Revoked) => return Err(CertificateError::Revoked.into()),
+            Ok(OcspStatus::Unknown) => OcspError("certificate unknown to OCSP responder".into()),
+            Err(err) => err,
+        };
+
+        match self.policy {
+            TlsRevocationPolicy::SoftFail => {
+                warn!("cannot check TLS cert revocation status, accepting anyway: {err}");
+                Ok(verified)
+            }
+            TlsRevocationPolicy::HardFail => {
+                Err(CertificateError::Other(OtherError(Arc::new(err))).into())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+#[derive(Debug, Eq, PartialEq)]
+enum OcspStatus {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+/// Error raised when an OCSP response cannot be used.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+#[derive(Debug)]
+struct OcspError(String);
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl fmt::Display for OcspError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid OCSP response: {}", self.0)
+    }
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl error::Error for OcspError {}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl From<&str> for OcspError {
+    fn from(err: &str) -> Self {
+        Self(err.to_owned())
+    }
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+type OcspResult<T> = std::result::Result<T, OcspError>;
+
+/// DER structure made of data, a signature algorithm and a signature
+/// over the data, like certificates and OCSP responses.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+struct Signed<'a> {
+    /// The signed data, with its DER header.
+    data: &'a [u8],
+    /// Contents of the signature `AlgorithmIdentifier`.
+    algorithm: &'a [u8],
+    signature: &'a [u8],
+}
+
+/// Identifies the server certificate in OCSP responses.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+struct OcspCertId<'a> {
+    /// DER-encoded name of the issuer.
+    issuer_name: &'a [u8],
+    /// Public key of the issuer, without the `BIT STRING` header.
+    issuer_key: &'a [u8],
+    serial: &'a [u8],
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+struct OcspChecker<'a> {
+    provider: &'a CryptoProvider,
+    now: u64,
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+impl OcspChecker<'_> {
+    fn check(
+        &self,
+        end_entity: &[u8],
+        intermediates: &[CertificateDer<'_>],
+        response: &[u8],
+    ) -> OcspResult<OcspStatus> {
+        if response.is_empty() {
+            return Err("no OCSP response stapled by the server".into());
+        }
+
+        let Ok((_, cert)) = parse_x509_certificate(end_entity) else {
+            return Err("cannot parse server certificate".into());
+        };
+
+        // the issuer needs to be presented by the server to check
+        // the response signature. Names can be forged: the issuer is
+        // the certificate whose key signed the server certificate.
+        let signed_cert = signed_parts(&sequence(end_entity)?)?;
+        let issuer = intermediates
+            .iter()
+            .filter(|der| match parse_x509_certificate(der) {
+                Ok((_, c)) => c.subject().as_raw() == cert.issuer().as_raw(),
+                Err(_) => false,
+            })
+            .find(|der| match spki(der) {
+                Ok(spki) => self.verify(spki, &signed_cert).is_ok(),
+                Err(_) => false,
+            })
+            .ok_or("issuer certificate not presented by the server")?;
+
+        // OCSPResponse ::= SEQUENCE {
+        //     responseStatus ENUMERATED,
+        //     responseBytes  [0] EXPLICIT SEQUENCE {
+        //         responseType OBJECT IDENTIFIER,
+        //         response     OCTET STRING } OPTIONAL }
+        let items = sequence(response)?;
+
+        match items.first() {
+            Some((any, _)) if any.tag() == Tag::Enumerated && any.data == [0] => (),
+            _ => return Err("unsuccessful OCSP response status".into()),
+        }
+
+        let bytes = items.get(1).ok_or("missing OCSP response bytes")?;
+        let bytes = sequence(explicit(&bytes.0, 0)?)?;
+        let basic = match bytes.get(1) {
+            Some((any, _)) if any.tag() == Tag::OctetString => any.data,
+            _ => return Err("missing basic OCSP response".into()),
+        };
+
+        // BasicOCSPResponse ::= SEQUENCE {
+        //     tbsResponseData    ResponseData,
+        //     signatureAlgorithm AlgorithmIdentifier,
+        //     signature          BIT STRING,
+        //     certs              [0] EXPLICIT SEQUENCE OF Certificate OPTIONAL }
+        let items = sequence(basic)?;
+        let signed = signed_parts(&items)?;
+
+        let certs = match items.get(3) {
+            Some((any, _)) => sequence(explicit(any, 0)?)?,
+            None => Vec::new(),
+        };
+
+        self.verify_responder(issuer, &certs, &signed)?;
+
+        // ResponseData ::= SEQUENCE {
+        //     version            [0] EXPLICIT Version DEFAULT v1,
+        //     responderID        ResponderID,
+        //     producedAt         GeneralizedTime,
+        //     responses          SEQUENCE OF SingleResponse,
+        //     responseExtensions [1] EXPLICIT Extensions OPTIONAL }
+        let data = sequence_items(items[0].0.data)?;
+        let skip = match data.first() {
+            Some((any, _)) if is_context(any, 0) => 1,
+            _ => 0,
+        };
+
+        let responses = data.get(skip + 2).ok_or("missing OCSP single responses")?;
+
+        let issuer_key = match sequence(spki(issuer)?)?.get(1) {
+            Some((key, _)) => bit_string(key)?,
+            None => return Err("invalid issuer public key".into()),
+        };
+
+        let cert_id = OcspCertId {
+            issuer_name: cert.issuer().as_raw(),
+            issuer_key,
+            serial: cert.raw_serial(),
+        };
+
+        for (response, _) in sequence_items(responses.0.data)? {
+            if let Some(status) = check_single(response.data, &cert_id, self.now)? {
+                return Ok(status);
+            }
+        }
+
+        Err("no OCSP response for the server certificate".into())
+    }
+
+    /// Checks that the response is signed by the issuer, or by a
+    /// responder certificate issued by the issuer for OCSP signing.
+    fn verify_responder(
+        &self,
+        issuer: &[u8],
+        certs: &[(Any<'_>, &[u8])],
+        signed: &Signed<'_>,
+    ) -> OcspResult<()> {
+        let issuer_spki = spki(issuer)?;
+
+        if self.verify(issuer_spki, signed).is_ok() {
+            return Ok(());
+        }
+
+        for (_, der) in certs {
+            let Ok((_, responder)) = parse_x509_certificate(der) else {
+                continue;
+            };
+
+            let ocsp_signing = match responder.extended_key_usage() {
+                Ok(Some(eku)) => eku.value.ocsp_signing,
+                _ => false,
+            };
+
+            let validity = responder.validity();
+            let valid = validity.not_before.timestamp() <= self.now as i64
+                && self.now as i64 <= validity.not_after.timestamp();
+
+            if !ocsp_signing || !valid {
+                continue;
+            }
+
+            let responder_signed = signed_parts(&sequence(der)?)?;
+
+            if self.verify(issuer_spki, &responder_signed).is_err() {
+                continue;
+            }
+
+            if self.verify(responder.public_key().raw, signed).is_ok() {
+                return Ok(());
+            }
+        }
+
+        Err("signature does not match the issuer nor a delegated responder".into())
+    }
+
+    /// Verifies a signature with the given DER-encoded
+    /// `SubjectPublicKeyInfo`, using the algorithms of the crypto
+    /// provider.
+    fn verify(&self, spki: &[u8], signed: &Signed<'_>) -> OcspResult<()> {
+        // SubjectPublicKeyInfo ::= SEQUENCE {
+        //     algorithm        AlgorithmIdentifier,
+        //     subjectPublicKey BIT STRING }
+        let items = sequence(spki)?;
+
+        let (Some((key_algorithm, _)), Some((key, _))) = (items.first(), items.get(1)) else {
+            return Err("invalid public key".into());
+        };
+
+        let key = bit_string(key)?;
+
+        let algorithm = self
+            .provider
+            .signature_verification_algorithms
+            .all
+            .iter()
+            .find(|alg| {
+                alg.public_key_alg_id().as_ref() == key_algorithm.data
+                    && alg.signature_alg_id().as_ref() == signed.algorithm
+            })
+            .ok_or("unsupported signature algorithm")?;
+
+        algorithm
+            .verify_signature(key, signed.data, signed.signature)
+            .map_err(|_| "bad signature".into())
+    }
+}
+
+/// Returns the status of the given single response, or `None` if
+/// it does not concern the certificate with the given ID.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn check_single(
+    response: &[u8],
+    cert_id: &OcspCertId<'_>,
+    now: u64,
+) -> OcspResult<Option<OcspStatus>> {
+    // SingleResponse ::= SEQUENCE {
+    //     certID           CertID,
+    //     certStatus       CertStatus,
+    //     thisUpdate       GeneralizedTime,
+    //     nextUpdate       [0] EXPLICIT GeneralizedTime OPTIONAL,
+    //     singleExtensions [1] EXPLICIT Extensions OPTIONAL }
+    let items = sequence_items(response)?;
+
+    // CertID ::= SEQUENCE {
+    //     hashAlgorithm  AlgorithmIdentifier,
+    //     issuerNameHash OCTET STRING,
+    //     issuerKeyHash  OCTET STRING,
+    //     serialNumber   CertificateSerialNumber }
+    let id = items.first().ok_or("missing OCSP cert ID")?;
+    let id = sequence_items(id.0.data)?;
+
+    let (Some((algorithm, _)), Some((name_hash, _)), Some((key_hash, _)), Some((serial, _))) =
+        (id.first(), id.get(1), id.get(2), id.get(3))
+    else {
+        return Err("invalid OCSP cert ID".into());
+    };
+
+    if serial.tag() != Tag::Integer || serial.data != cert_id.serial {
+        return Ok(None);
+    }
+
+    let algorithm = match sequence_items(algorithm.data)?.first() {
+        Some((oid, _)) if oid.tag() == Tag::Oid => oid.data,
+        _ => return Err("invalid OCSP cert ID hash algorithm".into()),
+    };
+
+    let hash = |data: &[u8]| match algorithm {
+        OID_SHA1 => Ok(Sha1::digest(data).to_vec()),
+        OID_SHA256 => Ok(Sha256::digest(data).to_vec()),
+        OID_SHA384 => Ok(Sha384::digest(data).to_vec()),
+        OID_SHA512 => Ok(Sha512::digest(data).to_vec()),
+        _ => Err(OcspError::from("unsupported OCSP cert ID hash algorithm")),
+    };
+
+    // same serial, but from another issuer
+    if name_hash.tag() != Tag::OctetString
+        || key_hash.tag() != Tag::OctetString
+        || name_hash.data != hash(cert_id.issuer_name)?
+        || key_hash.data != hash(cert_id.issuer_key)?
+    {
+        return Ok(None);
+    }
+
+    let this_update = items.get(2).ok_or("missing OCSP this update")?;
+    let this_update = timestamp(this_update.1)?;
+
+    if this_update > now + OCSP_CLOCK_SKEW {
+        return Err("OCSP response not valid yet".into());
+    }
+
+    match items.get(3).filter(|(any, _)| is_context(any, 0)) {
+        Some((any, _)) => {
+            let next_update = timestamp(any.data)?;
+
+            if next_update + OCSP_CLOCK_SKEW < now {
+                return Err("OCSP response expired".into());
+            }
+        }
+        // without next update, newer information is always
+        // available: old responses could be replayed
+        None if this_update + OCSP_MAX_AGE + OCSP_CLOCK_SKEW < now => {
+            return Err("OCSP response too old".into());
+        }
+        None => (),
+    }
+
+    // CertStatus ::= CHOICE {
+    //     good    [0] IMPLICIT NULL,
+    //     revoked [1] IMPLICIT RevokedInfo,
+    //     unknown [2] IMPLICIT UnknownInfo }
+    let status = match items.get(1) {
+        Some((any, _)) if is_context(any, 0) => OcspStatus::Good,
+        Some((any, _)) if is_context(any, 1) => OcspStatus::Revoked,
+        Some((any, _)) if is_context(any, 2) => OcspStatus::Unknown,
+        _ => return Err("invalid OCSP cert status".into()),
+    };
+
+    Ok(Some(status))
+}
+
+/// Extracts the signed data, signature algorithm and signature from
+/// the items of a signed structure.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn signed_parts<'a>(items: &[(Any<'a>, &'a [u8])]) -> OcspResult<Signed<'a>> {
+    let (Some((_, data)), Some((algorithm, _)), Some((signature, _))) =
+        (items.first(), items.get(1), items.get(2))
+    else {
+        return Err("invalid signed structure".into());
+    };
+
+    Ok(Signed {
+        data,
+        algorithm: algorithm.data,
+        signature: bit_string(signature)?,
+    })
+}
+
+/// Returns the DER-encoded `SubjectPublicKeyInfo` of the given
+/// certificate.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn spki(cert: &[u8]) -> OcspResult<&[u8]> {
+    match parse_x509_certificate(cert) {
+        Ok((_, cert)) => Ok(cert.tbs_certificate.subject_pki.raw),
+        Err(_) => Err("cannot parse issuer certificate".into()),
+    }
+}
+
+/// Parses the given DER-encoded `SEQUENCE`, then returns its items.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn sequence(der: &[u8]) -> OcspResult<Vec<(Any<'_>, &[u8])>> {
+    match Any::from_der(der) {
+        Ok((_, any)) if any.tag() == Tag::Sequence => sequence_items(any.data),
+        _ => Err("expected DER sequence".into()),
+    }
+}
+
+/// Parses the contents of a `SEQUENCE`, returning each item with its
+/// DER encoding.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn sequence_items(mut data: &[u8]) -> OcspResult<Vec<(Any<'_>, &[u8])>> {
+    let mut items = Vec::new();
+
+    while !data.is_empty() {
+        let Ok((rem, any)) = Any::from_der(data) else {
+            return Err("invalid DER item".into());
+        };
+
+        items.push((any, &data[..data.len() - rem.len()]));
+        data = rem;
+    }
+
+    Ok(items)
+}
+
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn is_context(any: &Any<'_>, tag: u32) -> bool {
+    any.class() == Class::ContextSpecific && any.tag() == Tag(tag)
+}
+
+/// Returns the contents of an `[n] EXPLICIT` tagged item.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn explicit<'a>(any: &Any<'a>, tag: u32) -> OcspResult<&'a [u8]> {
+    if !is_context(any, tag) {
+        return Err("unexpected DER tag".into());
+    }
+
+    Ok(any.data)
+}
+
+/// Returns the contents of a `BIT STRING` without unused bits.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn bit_string<'a>(any: &Any<'a>) -> OcspResult<&'a [u8]> {
+    match any.data.split_first() {
+        Some((0, bits)) if any.tag() == Tag::BitString => Ok(bits),
+        _ => Err("invalid DER bit string".into()),
+    }
+}
+
+/// Parses a DER-encoded `GeneralizedTime` into a Unix timestamp.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn timestamp(der: &[u8]) -> OcspResult<u64> {
+    match ASN1Time::from_der(der) {
+        Ok((_, time)) => Ok(time.timestamp().max(0) as u64),
+        Err(_) => Err("invalid DER time".into()),
+    }
+}
+
+#[cfg(all(test, any(feature = "rustls-aws", feature = "rustls-ring")))]
+mod tests {
+    use rustls::{crypto::CryptoProvider, pki_types::CertificateDer};
+    use sha1::{Digest, Sha1};
+
+    use super::{
+        check_single, sequence, sequence_items, signed_parts, OcspCertId, OcspChecker, OcspResult,
+        OcspStatus,
+    };
+
+    macro_rules! fixture {
+        ($name:literal) => {
+            include_bytes!(concat!(
+                env!("CARGO_MANIFEST_DIR"),
+                "/tests/fixtures/",
+                $name
+            ))
+        };
+    }
+
+    const CA: &[u8] = fixture!("ca.der");
+    const FORGED: &[u8] = fixture!("forged.der");
+    const LEAF: &[u8] = fixture!("leaf.der");
+    const OCSP_GOOD: &[u8] = fixture!("ocsp-good.der");
+    const OCSP_REVOKED: &[u8] = fixture!("ocsp-revoked.der");
+    const OCSP_FORGED: &[u8] = fixture!("ocsp-forged.der");
+    const OCSP_DELEGATED: &[u8] = fixture!("ocsp-delegated.der");
+    const OCSP_ROGUE: &[u8] = fixture!("ocsp-rogue.der");
+
+    /// 2030-01-01T00:00:00Z, between the updates of the fixture OCSP
+    /// responses.
+    const FIXTURE_NOW: u64 = 1893456000;
+
+    const ISSUER_NAME: &[u8] = b"issuer name";
+    const ISSUER_KEY: &[u8] = b"issuer key";
+    const SERIAL: &[u8] = &[0x01, 0x02, 0x03];
+
+    /// 2026-10-18T00:00:00Z
+    const NOW: u64 = 1792281600;
+
+    fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut der = vec![tag];
+
+        match content.len() {
+            len @ 0..=0x7f => der.push(len as u8),
+            len => {
+                let len = (len as u16).to_be_bytes();
+                der.extend([0x82, len[0], len[1]]);
+            }
+        }
+
+        der.extend(content);
+        der
+    }
+
+    fn time(time: &str) -> Vec<u8> {
+        tlv(0x18, time.as_bytes())
+    }
+
+    /// Builds a SingleResponse with a SHA-1 cert ID.
+    fn single(issuer_key: &[u8], status: Vec<u8>, next_update: Option<&str>) -> Vec<u8> {
+        let algorithm = [tlv(0x06, &[0x2b, 0x0e, 0x03, 0x02, 0x1a]), tlv(0x05, &[])].concat();
+
+        let cert_id = [
+            tlv(0x30, &algorithm),
+            tlv(0x04, &Sha1::digest(ISSUER_NAME)),
+            tlv(0x04, &Sha1::digest(issuer_key)),
+            tlv(0x02, SERIAL),
+        ]
+        .concat();
+
+        let mut items = [tlv(0x30, &cert_id), status, time("20261017000000Z")].concat();
+
+        if let Some(next_update) = next_update {
+            items.extend(tlv(0xa0, &time(next_update)));
+        }
+
+        items
+    }
+
+    fn cert_id() -> OcspCertId<'static> {
+        OcspCertId {
+            issuer_name: ISSUER_NAME,
+            issuer_key: ISSUER_KEY,
+            serial: SERIAL,
+        }
+    }
+
+    fn good() -> Vec<u8> {
+        tlv(0x80, &[])
+    }
+
+    #[test]
+    fn good_response() {
+        let response = single(ISSUER_KEY, good(), Some("20261025000000Z"));
+        let status = check_single(&response, &cert_id(), NOW).unwrap();
+        assert_eq!(status, Some(OcspStatus::Good));
+    }
+
+    #[test]
+    fn revoked_response() {
+        let status = tlv(0xa1, &time("20261001000000Z"));
+        let response = single(ISSUER_KEY, status, Some("20261025000000Z"));
+        let status = check_single(&response, &cert_id(), NOW).unwrap();
+        assert_eq!(status, Some(OcspStatus::Revoked));
+    }
+
+    #[test]
+    fn unknown_response() {
+        let response = single(ISSUER_KEY, tlv(0x82, &[]), Some("20261025000000Z"));
+        let status = check_single(&response, &cert_id(), NOW).unwrap();
+        assert_eq!(status, Some(OcspStatus::Unknown));
+    }
+
+    #[test]
+    fn wrong_issuer_response() {
+        let response = single(b"other key", good(), Some("20261025000000Z"));
+        let status = check_single(&response, &cert_id(), NOW).unwrap();
+        assert_eq!(status, None);
+    }
+
+    #[test]
+    fn expired_response() {
+        let response = single(ISSUER_KEY, good(), Some("20261017120000Z"));
+        assert!(check_single(&response, &cert_id(), NOW).is_err());
+    }
+
+    #[test]
+    fn response_without_next_update() {
+        let response = single(ISSUER_KEY, good(), None);
+        let status = check_single(&response, &cert_id(), NOW).unwrap();
+        assert_eq!(status, Some(OcspStatus::Good));
+
+        let now = NOW + 30 * 24 * 60 * 60;
+        assert!(check_single(&response, &cert_id(), now).is_err());
+    }
+
+    fn provider() -> CryptoProvider {
+        #[cfg(feature = "rustls-ring")]
+        return rustls::crypto::ring::default_provider();
+        #[cfg(not(feature = "rustls-ring"))]
+        return rustls::crypto::aws_lc_rs::default_provider();
+    }
+
+    fn check(intermediates: &[&'static [u8]], response: &[u8], now: u64) -> OcspResult<OcspStatus> {
+        let provider = provider();
+        let checker = OcspChecker {
+            provider: &provider,
+            now,
+        };
+
+        let intermediates: Vec<_> = intermediates
+            .iter()
+            .map(|der| CertificateDer::from(*der))
+            .collect();
+
+        checker.check(LEAF, &intermediates, response)
+    }
+
+    fn check_err(intermediates: &[&'static [u8]], response: &[u8]) -> String {
+        check(intermediates, response, FIXTURE_NOW).unwrap_err().0
+    }
+
+    #[test]
+    fn check_issuer_response() {
+        let status = check(&[CA], OCSP_GOOD, FIXTURE_NOW).unwrap();
+        assert_eq!(status, OcspStatus::Good);
+
+        let status = check(&[CA], OCSP_REVOKED, FIXTURE_NOW).unwrap();
+        assert_eq!(status, OcspStatus::Revoked);
+
+        // 2200-01-01, after the next update
+        let err = check(&[CA], OCSP_GOOD, 7258118400).unwrap_err();
+        assert_eq!(err.0, "OCSP response expired");
+    }
+
+    #[test]
+    fn check_delegated_response() {
+        let status = check(&[CA], OCSP_DELEGATED, FIXTURE_NOW).unwrap();
+        assert_eq!(status, OcspStatus::Good);
+    }
+
+    #[test]
+    fn check_rogue_responder() {
+        // issued by the CA, but not for OCSP signing
+        let err = check_err(&[CA], OCSP_ROGUE);
+        assert_eq!(
+            err,
+            "signature does not match the issuer nor a delegated responder"
+        );
+    }
+
+    #[test]
+    fn check_forged_issuer() {
+        // same name as the issuer, but did not sign the certificate
+        let err = check_err(&[FORGED], OCSP_FORGED);
+        assert_eq!(err, "issuer certificate not presented by the server");
+
+        let err = check_err(&[FORGED, CA], OCSP_FORGED);
+        assert_eq!(
+            err,
+            "signature does not match the issuer nor a delegated responder"
+        );
+
+        let status = check(&[FORGED, CA], OCSP_GOOD, FIXTURE_NOW).unwrap();
+        assert_eq!(status, OcspStatus::Good);
+    }
+
+    #[test]
+    fn check_missing() {
+        let err = check_err(&[CA], &[]);
+        assert_eq!(err, "no OCSP response stapled by the server");
+
+        let err = check_err(&[], OCSP_GOOD);
+        assert_eq!(err, "issuer certificate not presented by the server");
+    }
+
+    #[test]
+    fn verify_responder() {
+        let provider = provider();
+        let checker = OcspChecker {
+            provider: &provider,
+            now: FIXTURE_NOW,
+        };
+
+        // the leaf is signed by the CA
+        let items = sequence(LEAF).unwrap();
+        let signed = signed_parts(&items).unwrap();
+
+        checker.verify_responder(CA, &[], &signed).unwrap();
+        assert!(checker.verify_responder(FORGED, &[], &signed).is_err());
+
+        // certificates without OCSP signing usage are not responders
+        let certs = sequence_items(CA).unwrap();
+        assert!(checker.verify_responder(FORGED, &certs, &signed).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+use std::{env, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use x509_parser::pem::Pem;
+
+/// Parses every certificate of a PEM bundle, returned DER-encoded.
+///
+/// Blocks other than `CERTIFICATE` (keys, CRLs…) are skipped.
+pub(crate) fn parse_pem_certs(pem: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut certs = Vec::new();
+
+    for block in Pem::iter_from_buffer(pem) {
+        let block = block.context("Parse PEM block error")?;
+
+        if block.label == "CERTIFICATE" || block.label == "TRUSTED CERTIFICATE" {
+            certs.push(block.contents);
+        }
+    }
+
+    Ok(certs)
+}
+
+/// Reads the certificates of a PEM bundle, or of every PEM file of a
+/// directory (like the ones prepared by OpenSSL `c_rehash`).
+pub(crate) fn read_pem_certs(path: &Path) -> Result<Vec<Vec<u8>>> {
+    if !path.is_dir() {
+        let pem = fs::read(path)
+            .with_context(|| format!("Read TLS certs at {} error", path.display()))?;
+        let certs = parse_pem_certs(&pem)
+            .with_context(|| format!("Parse TLS certs at {} error", path.display()))?;
+
+        if certs.is_empty() {
+            bail!("No TLS cert found at {}", path.display());
+        }
+
+        return Ok(certs);
+    }
+
+    let entries = fs::read_dir(path)
+        .with_context(|| format!("Read TLS certs directory at {} error", path.display()))?;
+
+    let mut certs = Vec::new();
+
+    for entry in entries {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            continue;
+        }
+
+        // directories may contain anything besides certs (READMEs,
+        // CRLs in DER…), so unreadable files are only skipped
+        match fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|pem| parse_pem_certs(&pem))
+        {
+            Ok(found) => certs.extend(found),
+            Err(err) => debug!("skip TLS certs at {}: {err:#}", path.display()),
+        }
+    }
+
+    if certs.is_empty() {
+        bail!("No TLS cert found in directory {}", path.display());
+    }
+
+    Ok(certs)
+}
+
+/// Reads the certificates designated by the OpenSSL `SSL_CERT_FILE`
+/// and `SSL_CERT_DIR` environment variables.
+///
+/// `SSL_CERT_DIR` may hold several directories, separated like
+/// `PATH` entries.
+pub(crate) fn read_env_certs() -> Vec<Vec<u8>> {
+    let mut certs = Vec::new();
+
+    if let Some(file) = env::var_os("SSL_CERT_FILE").filter(|v| !v.is_empty()) {
+        match read_pem_certs(Path::new(&file)) {
+            Ok(found) => {
+                debug!("using TLS certs from SSL_CERT_FILE");
+                certs.extend(found)
+            }
+            Err(err) => warn!("cannot use SSL_CERT_FILE: {err:#}"),
+        }
+    }
+
+    if let Some(dirs) = env::var_os("SSL_CERT_DIR") {
+        for dir in env::split_paths(&dirs).filter(|d| !d.as_os_str().is_empty()) {
+            match read_pem_certs(&dir) {
+                Ok(found) => {
+                    debug!("using TLS certs from SSL_CERT_DIR {}", dir.display());
+                    certs.extend(found)
+                }
+                Err(err) => warn!("cannot use SSL_CERT_DIR: {err:#}"),
+            }
+        }
+    }
+
+    certs
+}
@@ -2,8 +2,7 @@
 use std::os::unix::net::UnixStream;
 use std::{
     io::{Read, Write},
-    net::{Ipv4Addr, TcpStream},
-    sync::Arc,
+    net::Ipv4Addr,
 };
 
 use anyhow::{bail, Result};
@@ -18,17 +17,16 @@ use io_smtp::{
     },
 };
 use log::info;
-#[cfg(feature = "native-tls")]
-use native_tls::TlsConnector;
-#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-use rustls::{ClientConnection, StreamOwned};
 #[cfg(windows)]
 use uds_windows::UnixStream;
 use url::Url;
 
 use crate::{
-    sasl::{Sasl, SaslMechanism},
-    stream::{Stream, Tls, TlsProvider},
+    sasl::{Sasl, SaslClient},
+    stream::{
+        authenticate::{smtp_ehlo_mechanisms, AuthenticateResult, SaslAuth, SmtpAuthenticate},
+        CommandStream, HandshakeDeadline, ReadBuffer, ReadFraming, Stream, Tcp, Tls, TlsInfo,
+    },
 };
 
 const READ_BUFFER_SIZE: usize = 8 * 1024;
@@ -36,38 +34,10 @@ const READ_BUFFER_SIZE: usize = 8 * 1024;
 #[derive(Debug)]
 pub struct SmtpSession {
     pub stream: Stream,
+    pub buffer: ReadBuffer,
 }
 
-fn upgrade_tls(host: &str, tcp: TcpStream, tls: &Tls) -> Result<Stream> {
-    match tls.provider()? {
-        #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-        TlsProvider::Rustls => {
-            let mut config = tls.build_rustls_client_config()?;
-            config.alpn_protocols = vec![b"smtp".to_vec()];
-            let server_name = host.to_string().try_into()?;
-            let conn = ClientConnection::new(Arc::new(config), server_name)?;
-            Ok(Stream::Rustls(StreamOwned::new(conn, tcp)))
-        }
-        #[cfg(feature = "native-tls")]
-        TlsProvider::NativeTls => {
-            let mut builder = TlsConnector::builder();
-
-            if let Some(pem_path) = &tls.cert {
-                let pem = std::fs::read(pem_path)?;
-                let cert = native_tls::Certificate::from_pem(&pem)?;
-                builder.add_root_certificate(cert);
-            }
-
-            let connector = builder.build()?;
-            Ok(Stream::NativeTls(connector.connect(host, tcp)?))
-        }
-        #[allow(unreachable_patterns)]
-        _ => unreachable!(),
-    }
-}
-
-fn drive_greeting<S: Read + Write>(stream: &mut S) -> Result<()> {
-    let mut buf = [0u8; READ_BUFFER_SIZE];
+fn drive_greeting<S: Read + Write>(stream: &mut S, buffer: &mut ReadBuffer) -> Result<()> {
     let mut coroutine = GetSmtpGreeting::new();
     let mut arg: Option<&[u8]> = None;
 
@@ -75,25 +45,35 @@ fn drive_greeting<S: Read + Write>(stream: &mut S) -> Result<()> {
         match coroutine.resume(arg.take()) {
             GetSmtpGreetingResult::Ok { .. } => return Ok(()),
             GetSmtpGreetingResult::WantsRead => {
-                let n = stream.read(&mut buf)?;
-                arg = Some(&buf[..n]);
+                arg = Some(buffer.read(stream)?);
             }
             GetSmtpGreetingResult::Err(err) => bail!(err),
         }
     }
 }
 
-fn drive_ehlo<S: Read + Write>(stream: &mut S, domain: EhloDomain<'_>) -> Result<()> {
-    let mut buf = [0u8; READ_BUFFER_SIZE];
+/// Sends `EHLO`, returning the SASL mechanisms advertised by the
+/// server.
+fn drive_ehlo<S: Read + Write>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    domain: EhloDomain<'_>,
+) -> Result<Vec<String>> {
     let mut coroutine = SmtpEhlo::new(domain);
+    let mut mechanisms = Vec::new();
     let mut arg: Option<&[u8]> = None;
 
     loop {
         match coroutine.resume(arg.take()) {
-            SmtpEhloResult::Ok { .. } => return Ok(()),
+            SmtpEhloResult::Ok { .. } => return Ok(mechanisms),
             SmtpEhloResult::WantsRead => {
-                let n = stream.read(&mut buf)?;
-                arg = Some(&buf[..n]);
+                let bytes = buffer.read(stream)?;
+
+                if let Some(auth) = smtp_ehlo_mechanisms(bytes) {
+                    mechanisms = auth;
+                }
+
+                arg = Some(bytes);
             }
             SmtpEhloResult::WantsWrite(bytes) => {
                 stream.write_all(&bytes)?;
@@ -104,8 +84,7 @@ fn drive_ehlo<S: Read + Write>(stream: &mut S, domain: EhloDomain<'_>) -> Result
     }
 }
 
-fn drive_starttls<S: Read + Write>(stream: &mut S) -> Result<()> {
-    let mut buf = [0u8; READ_BUFFER_SIZE];
+fn drive_starttls<S: Read + Write>(stream: &mut S, buffer: &mut ReadBuffer) -> Result<()> {
     let mut coroutine = SmtpStartTls::new();
     let mut arg: Option<&[u8]> = None;
 
@@ -113,8 +92,7 @@ fn drive_starttls<S: Read + Write>(stream: &mut S) -> Result<()> {
         match coroutine.resume(arg.take()) {
             SmtpStartTlsResult::WantsStartTls(_) => return Ok(()),
             SmtpStartTlsResult::WantsRead => {
-                let n = stream.read(&mut buf)?;
-                arg = Some(&buf[..n]);
+                arg = Some(buffer.read(stream)?);
             }
             SmtpStartTlsResult::WantsWrite(bytes) => {
                 stream.write_all(&bytes)?;
@@ -125,71 +103,131 @@ fn drive_starttls<S: Read + Write>(stream: &mut S) -> Result<()> {
     }
 }
 
+/// Authenticates with a SASL mechanism the I/O crate does not
+/// support.
+fn drive_authenticate<S: Read + Write>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    client: Box<dyn SaslClient>,
+) -> Result<()> {
+    let mut coroutine = SmtpAuthenticate::new(client);
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            AuthenticateResult::Ok => return Ok(()),
+            AuthenticateResult::WantsRead => {
+                arg = Some(buffer.read(stream)?);
+            }
+            AuthenticateResult::WantsWrite(bytes) => {
+                stream.write_all(&bytes)?;
+                arg = None;
+            }
+            AuthenticateResult::Err(err) => return Err(err),
+        }
+    }
+}
+
 impl SmtpSession {
-    pub fn new(url: Url, tls: Tls, starttls: bool, mut sasl: Sasl) -> Result<Self> {
+    /// Connects and authenticates to the SMTP server at `url`.
+    ///
+    /// Supported schemes: `smtp` (plain TCP), `smtps` (TLS over TCP),
+    /// `unix` and `unixs` (plain and TLS over a Unix socket, whose
+    /// path is the URL path), `cmd` and `cmds` (plain and TLS over
+    /// the standard I/O of a command spawned through the shell, for
+    /// example `cmd:ssh mailhost nc localhost 25`). For TLS
+    /// schemes, `starttls` selects STARTTLS instead of implicit TLS.
+    pub fn new(url: Url, tls: Tls, starttls: bool, sasl: Sasl) -> Result<Self> {
+        Self::new_with_tcp(url, Tcp::default(), tls, starttls, sasl)
+    }
+
+    /// Same as [`Self::new`], connecting with the given TCP options.
+    pub fn new_with_tcp(url: Url, tcp: Tcp, tls: Tls, starttls: bool, sasl: Sasl) -> Result<Self> {
+        let deadline = HandshakeDeadline::new(tcp.handshake_timeout);
+        let session = Self::handshake(url, tcp, tls, starttls, sasl, &deadline);
+        deadline.finish(session)
+    }
+
+    fn handshake(
+        url: Url,
+        tcp: Tcp,
+        tls: Tls,
+        starttls: bool,
+        sasl: Sasl,
+        deadline: &HandshakeDeadline,
+    ) -> Result<Self> {
         info!("connecting to SMTP server using {url}");
 
         let host = url.host_str().unwrap_or("127.0.0.1");
         let domain: EhloDomain<'static> = Ipv4Addr::new(127, 0, 0, 1).into();
 
-        let mut stream = match url.scheme() {
+        // secure schemes use TLS, either implicit or via STARTTLS,
+        // whatever the transport is
+        let (stream, secure) = match url.scheme() {
             scheme if scheme.eq_ignore_ascii_case("smtp") => {
                 let port = url.port().unwrap_or(25);
-                let mut tcp = TcpStream::connect((host, port))?;
-
-                drive_greeting(&mut tcp)?;
-                drive_ehlo(&mut tcp, domain.clone())?;
-
-                Stream::Tcp(tcp)
+                (Stream::connect_tcp(&tcp, scheme, host, port)?, false)
             }
             scheme if scheme.eq_ignore_ascii_case("smtps") => {
                 let default_port = if starttls { 587 } else { 465 };
                 let port = url.port().unwrap_or(default_port);
-                let mut tcp = TcpStream::connect((host, port))?;
-
-                if starttls {
-                    drive_greeting(&mut tcp)?;
-                    drive_ehlo(&mut tcp, domain.clone())?;
-                    drive_starttls(&mut tcp)?;
-                }
+                (Stream::connect_tcp(&tcp, scheme, host, port)?, true)
+            }
+            scheme if scheme.eq_ignore_ascii_case("unix") => {
+                (Stream::Unix(UnixStream::connect(url.path())?), false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("unixs") => {
+                (Stream::Unix(UnixStream::connect(url.path())?), true)
+            }
+            scheme if scheme.eq_ignore_ascii_case("cmd") => {
+                (Stream::Command(CommandStream::spawn_url(&url)?), false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("cmds") => {
+                (Stream::Command(CommandStream::spawn_url(&url)?), true)
+            }
+            scheme => {
+                bail!("Unknown scheme {scheme}, expected smtp, smtps, unix, unixs, cmd or cmds");
+            }
+        };
 
-                let mut stream = upgrade_tls(host, tcp, &tls)?;
+        let mut stream = stream.with_transcript(tcp.transcript.as_ref())?;
+        deadline.watch(&stream)?;
 
-                if !starttls {
-                    drive_greeting(&mut stream)?;
-                }
+        let mut buffer = ReadBuffer::with_capacity(ReadFraming::Lines, READ_BUFFER_SIZE);
 
-                drive_ehlo(&mut stream, domain.clone())?;
+        let mechanisms = if !secure {
+            drive_greeting(&mut stream, &mut buffer)?;
+            drive_ehlo(&mut stream, &mut buffer, domain.clone())?
+        } else {
+            if starttls {
+                drive_greeting(&mut stream, &mut buffer)?;
+                drive_ehlo(&mut stream, &mut buffer, domain.clone())?;
+                drive_starttls(&mut stream, &mut buffer)?;
+            }
 
-                stream
+            // bytes received in plaintext after STARTTLS could have
+            // been injected by an attacker
+            if !buffer.pending().is_empty() {
+                bail!("unexpected plaintext data received before TLS handshake");
             }
-            scheme if scheme.eq_ignore_ascii_case("unix") => {
-                let sock_path = url.path();
-                let mut unix = UnixStream::connect(sock_path)?;
 
-                drive_greeting(&mut unix)?;
-                drive_ehlo(&mut unix, domain.clone())?;
+            // identifies the server in the known hosts, whatever the
+            // transport is
+            let port = url.port().unwrap_or(if starttls { 587 } else { 465 });
+            stream = stream.upgrade_tls(host, port, &tls, Some(b"smtp"))?;
 
-                Stream::Unix(unix)
-            }
-            scheme => {
-                bail!("Unknown scheme {scheme}, expected smtp, smtps or unix");
+            if !starttls {
+                drive_greeting(&mut stream, &mut buffer)?;
             }
-        };
 
-        let mechanism = sasl
-            .mechanism
-            .or(Some(SaslMechanism::Plain).filter(|_| sasl.plain.is_some()))
-            .or(Some(SaslMechanism::Login).filter(|_| sasl.login.is_some()));
+            // capabilities advertised before TLS cannot be trusted
+            drive_ehlo(&mut stream, &mut buffer, domain.clone())?
+        };
 
-        match mechanism {
-            None => bail!("no SASL mechanism configured"),
-            Some(SaslMechanism::Login) => {
-                let Some(auth) = sasl.login.take() else {
-                    bail!("missing SASL LOGIN configuration");
-                };
+        let channel_binding = || Ok(stream.tls_channel_binding()?.map(Into::into));
 
-                let mut buf = [0u8; READ_BUFFER_SIZE];
+        match SaslAuth::new(sasl, &mechanisms, channel_binding)? {
+            SaslAuth::Login(auth) => {
                 let mut coroutine = SmtpLogin::new(&auth.username, &auth.password, domain.clone());
                 let mut arg: Option<&[u8]> = None;
 
@@ -197,8 +235,7 @@ impl SmtpSession {
                     match coroutine.resume(arg.take()) {
                         SmtpLoginResult::Ok => break,
                         SmtpLoginResult::WantsRead => {
-                            let n = stream.read(&mut buf)?;
-                            arg = Some(&buf[..n]);
+                            arg = Some(buffer.read(&mut stream)?);
                         }
                         SmtpLoginResult::WantsWrite(bytes) => {
                             stream.write_all(&bytes)?;
@@ -208,12 +245,7 @@ impl SmtpSession {
                     }
                 }
             }
-            Some(SaslMechanism::Plain) => {
-                let Some(auth) = sasl.plain.take() else {
-                    bail!("missing SASL PLAIN configuration");
-                };
-
-                let mut buf = [0u8; READ_BUFFER_SIZE];
+            SaslAuth::Plain(auth) => {
                 let mut coroutine = SmtpPlain::new(&auth.authcid, &auth.passwd, domain.clone());
                 let mut arg: Option<&[u8]> = None;
 
@@ -221,8 +253,7 @@ impl SmtpSession {
                     match coroutine.resume(arg.take()) {
                         SmtpPlainResult::Ok => break,
                         SmtpPlainResult::WantsRead => {
-                            let n = stream.read(&mut buf)?;
-                            arg = Some(&buf[..n]);
+                            arg = Some(buffer.read(&mut stream)?);
                         }
                         SmtpPlainResult::WantsWrite(bytes) => {
                             stream.write_all(&bytes)?;
@@ -232,11 +263,62 @@ impl SmtpSession {
                     }
                 }
             }
-            Some(SaslMechanism::Anonymous) => {
-                unimplemented!("ANONYMOUS SASL mechanism not yet implemented")
+            SaslAuth::Client(client) => {
+                drive_authenticate(&mut stream, &mut buffer, client)?;
             }
         }
 
-        Ok(Self { stream })
+        Ok(Self { stream, buffer })
+    }
+
+    /// Returns the details negotiated by the TLS layer, if any.
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        self.stream.tls_info()
+    }
+}
+
+#[cfg(all(test, feature = "replay"))]
+mod tests {
+    use url::Url;
+
+    use super::SmtpSession;
+    use crate::{
+        sasl::{Sasl, SaslCramMd5},
+        stream::{Replay, Stream, Tcp, Tls},
+    };
+
+    const TRANSCRIPT: &str = r"
+S: 220 mail.example.com ESMTP ready\r\n
+C: EHLO [127.0.0.1]\r\n
+S: 250-mail.example.com\r\n250-AUTH CRAM-MD5\r\n250 8BITMIME\r\n
+C: AUTH CRAM-MD5\r\n
+S: 334 PDE4OTYuNjk3MTcwOTUyQHBvc3RvZmZpY2UucmVzdG9uLm1jaS5uZXQ+\r\n
+C: dGltIGI5MTNhNjAyYzdlZGE3YTQ5NWI0ZTZlNzMzNGQzODkw\r\n
+S: 235 2.7.0 Authentication successful\r\n
+";
+
+    #[test]
+    fn replay_cram_md5() {
+        let tcp = Tcp {
+            replay: Some(Replay::parse(TRANSCRIPT).unwrap()),
+            ..Default::default()
+        };
+
+        let sasl = Sasl {
+            cram_md5: Some(SaslCramMd5 {
+                username: "tim".into(),
+                password: "tanstaaftanstaaf".to_owned().into(),
+            }),
+            ..Default::default()
+        };
+
+        let url = Url::parse("smtp://mail.example.com").unwrap();
+        let session = SmtpSession::new_with_tcp(url, tcp, Tls::default(), false, sasl).unwrap();
+
+        let Stream::Replay(stream) = session.stream else {
+            panic!("expected replay stream");
+        };
+
+        stream.finish().unwrap();
     }
 }
@@ -1,48 +1,243 @@
+#[cfg(feature = "native-tls")]
+use std::fs;
 #[cfg(unix)]
 use std::os::unix::net::UnixStream;
 use std::{
-    fs,
+    collections::HashSet,
     io::{self, Read, Write},
-    net::TcpStream,
+    net::{IpAddr, TcpStream},
     path::PathBuf,
     sync::Arc,
     time::Duration,
 };
 
-use anyhow::{bail, Result};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+};
+
+use anyhow::{bail, Context, Result};
 use log::debug;
+#[cfg(feature = "native-tls")]
+use native_tls::{Identity, TlsConnector};
 #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
 use rustls::{
+    client::{danger::ServerCertVerifier, WebPkiServerVerifier},
     crypto::{self, CryptoProvider},
-    pki_types::{pem::PemObject, CertificateDer},
-    ClientConfig, ClientConnection, StreamOwned,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+    version, ClientConfig, ClientConnection, RootCertStore, StreamOwned, SupportedCipherSuite,
+    SupportedProtocolVersion,
 };
 #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-use rustls_platform_verifier::{ConfigVerifierExt, Verifier};
+use rustls_platform_verifier::Verifier;
+#[cfg(feature = "native-tls")]
+use secrecy::ExposeSecret;
+use secrecy::SecretString;
 #[cfg(windows)]
 use uds_windows::UnixStream;
 
+#[cfg(feature = "native-tls")]
+use super::verify_pins;
+#[cfg(feature = "replay")]
+use super::ReplayStream;
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use super::{
+    known_hosts::KnownHostsVerifier, pin::PinnedVerifier, revocation::OcspVerifier,
+    TlsRevocationPolicy,
+};
+use super::{
+    roots::{parse_pem_certs, read_env_certs, read_pem_certs},
+    CommandStream, Tcp, TlsChannelBinding, TlsInfo, TlsKnownHosts, TlsPin, TlsRevocation,
+    TlsSessionCache, Transcript, TranscriptStream,
+};
+
+/// Connection to a server.
+///
+/// TLS variants wrap another stream, so that TLS can be layered on
+/// top of any transport (TCP, Unix socket, spawned command, proxy
+/// tunnel…).
 #[derive(Debug)]
 pub enum Stream {
     Tcp(TcpStream),
     Unix(UnixStream),
+    Command(CommandStream),
+    /// TLS stream, with the name sent with SNI, if any.
     #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-    Rustls(StreamOwned<ClientConnection, TcpStream>),
+    Rustls(StreamOwned<ClientConnection, Box<Stream>>, Option<String>),
+    /// TLS stream, with the name sent with SNI, if any.
     #[cfg(feature = "native-tls")]
-    NativeTls(native_tls::TlsStream<TcpStream>),
+    NativeTls(native_tls::TlsStream<Box<Stream>>, Option<String>),
+    Transcript(TranscriptStream<Box<Stream>>),
+    #[cfg(feature = "replay")]
+    Replay(ReplayStream),
 }
 
 impl Stream {
+    /// Opens a TCP connection to `host:port` (see [`Tcp::connect`]).
+    ///
+    /// When [`Tcp::replay`] is set, no connection is opened and the
+    /// recorded transcript is replayed instead.
+    pub fn connect_tcp(tcp: &Tcp, scheme: &str, host: &str, port: u16) -> Result<Self> {
+        #[cfg(feature = "replay")]
+        if let Some(replay) = &tcp.replay {
+            debug!("replaying transcript instead of connecting to {host}:{port}");
+            return Ok(Self::Replay(replay.stream()));
+        }
+
+        Ok(Self::Tcp(tcp.connect(scheme, host, port)?))
+    }
+
+    /// Returns the TCP connection underneath the stream, if any.
+    pub fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(s) => Some(s),
+            #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+            Self::Rustls(s, _) => s.sock.tcp(),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(s, _) => s.get_ref().tcp(),
+            Self::Transcript(s) => s.get_ref().tcp(),
+            _ => None,
+        }
+    }
+
+    /// Returns the details negotiated by the TLS layer, if any.
+    ///
+    /// With rustls, the handshake only happens on the first read or
+    /// write: negotiated details are missing before.
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        match self {
+            #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+            Self::Rustls(s, sni) => Ok(Some(TlsInfo::from_rustls(&s.conn, sni.clone())?)),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(s, sni) => Ok(Some(TlsInfo::from_native_tls(s, sni.clone())?)),
+            Self::Transcript(s) => s.get_ref().tls_info(),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the channel binding of the TLS layer, if any.
+    pub fn tls_channel_binding(&self) -> Result<Option<TlsChannelBinding>> {
+        match self {
+            #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+            Self::Rustls(s, _) => TlsChannelBinding::from_rustls(&s.conn),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(s, _) => TlsChannelBinding::from_native_tls(s),
+            Self::Transcript(s) => s.get_ref().tls_channel_binding(),
+            _ => Ok(None),
+        }
+    }
+
     pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
         match self {
             Self::Tcp(s) => s.set_read_timeout(timeout),
             Self::Unix(s) => s.set_read_timeout(timeout),
+            Self::Command(_) => {
+                debug!("read timeout not supported by stream commands, ignoring");
+                Ok(())
+            }
             #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-            Self::Rustls(s) => s.sock.set_read_timeout(timeout),
+            Self::Rustls(s, _) => s.sock.set_read_timeout(timeout),
             #[cfg(feature = "native-tls")]
-            Self::NativeTls(s) => s.get_ref().set_read_timeout(timeout),
+            Self::NativeTls(s, _) => s.get_ref().set_read_timeout(timeout),
+            Self::Transcript(s) => s.get_ref().set_read_timeout(timeout),
+            #[cfg(feature = "replay")]
+            Self::Replay(_) => Ok(()),
         }
     }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(s) => s.set_write_timeout(timeout),
+            Self::Unix(s) => s.set_write_timeout(timeout),
+            Self::Command(_) => {
+                debug!("write timeout not supported by stream commands, ignoring");
+                Ok(())
+            }
+            #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+            Self::Rustls(s, _) => s.sock.set_write_timeout(timeout),
+            #[cfg(feature = "native-tls")]
+            Self::NativeTls(s, _) => s.get_ref().set_write_timeout(timeout),
+            Self::Transcript(s) => s.get_ref().set_write_timeout(timeout),
+            #[cfg(feature = "replay")]
+            Self::Replay(_) => Ok(()),
+        }
+    }
+
+    /// Wraps the stream in the given transcript, if any.
+    pub fn with_transcript(self, transcript: Option<&Transcript>) -> Result<Self> {
+        match transcript {
+            Some(transcript) => {
+                let log = transcript.open()?;
+                Ok(Self::Transcript(TranscriptStream::new(Box::new(self), log)))
+            }
+            None => Ok(self),
+        }
+    }
+
+    /// Applies `f` to the transport underneath the transcript, if
+    /// any, so that the transcript keeps logging plaintext after a
+    /// TLS upgrade.
+    ///
+    /// Replays are left untouched, since transcripts are recorded
+    /// above TLS.
+    pub fn map_transport(self, f: impl FnOnce(Stream) -> Result<Stream>) -> Result<Self> {
+        match self {
+            #[cfg(feature = "replay")]
+            Self::Replay(s) => Ok(Self::Replay(s)),
+            Self::Transcript(s) => {
+                let (stream, log) = s.into_parts();
+                let stream = (*stream).map_transport(f)?;
+                Ok(Self::Transcript(TranscriptStream::new(
+                    Box::new(stream),
+                    log,
+                )))
+            }
+            stream => f(stream),
+        }
+    }
+
+    /// Upgrades the transport to TLS (see [`Self::map_transport`]),
+    /// identifying the server by `host` and `port` and negotiating
+    /// the given ALPN protocol, if any.
+    ///
+    /// The port and the ALPN protocol are only used by rustls.
+    #[cfg_attr(
+        not(any(feature = "rustls-aws", feature = "rustls-ring")),
+        allow(unused_variables)
+    )]
+    pub fn upgrade_tls(
+        self,
+        host: &str,
+        port: u16,
+        tls: &Tls,
+        alpn: Option<&[u8]>,
+    ) -> Result<Self> {
+        self.map_transport(|stream| match tls.provider()? {
+            #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+            TlsProvider::Rustls => {
+                let config = tls.rustls_client_config(port, alpn)?;
+                let server_name = tls.rustls_server_name(host)?;
+                let conn = ClientConnection::new(config, server_name)?;
+                let mut stream = StreamOwned::new(conn, Box::new(stream));
+
+                // rustls handshakes lazily on the first read or write:
+                // completes it now, within the handshake deadline
+                while stream.conn.is_handshaking() {
+                    stream.conn.complete_io(&mut stream.sock)?;
+                }
+
+                Ok(Self::Rustls(stream, tls.sni_for(host)))
+            }
+            #[cfg(feature = "native-tls")]
+            TlsProvider::NativeTls => Ok(Self::NativeTls(
+                tls.connect_native_tls(host, stream)?,
+                tls.sni_for(host),
+            )),
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        })
+    }
 }
 
 impl Read for Stream {
@@ -50,10 +245,14 @@ impl Read for Stream {
         match self {
             Self::Tcp(s) => s.read(buf),
             Self::Unix(s) => s.read(buf),
+            Self::Command(s) => s.read(buf),
             #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-            Self::Rustls(s) => s.read(buf),
+            Self::Rustls(s, _) => s.read(buf),
             #[cfg(feature = "native-tls")]
-            Self::NativeTls(s) => s.read(buf),
+            Self::NativeTls(s, _) => s.read(buf),
+            Self::Transcript(s) => s.read(buf),
+            #[cfg(feature = "replay")]
+            Self::Replay(s) => s.read(buf),
         }
     }
 }
@@ -63,10 +262,14 @@ impl Write for Stream {
         match self {
             Self::Tcp(s) => s.write(buf),
             Self::Unix(s) => s.write(buf),
+            Self::Command(s) => s.write(buf),
             #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-            Self::Rustls(s) => s.write(buf),
+            Self::Rustls(s, _) => s.write(buf),
             #[cfg(feature = "native-tls")]
-            Self::NativeTls(s) => s.write(buf),
+            Self::NativeTls(s, _) => s.write(buf),
+            Self::Transcript(s) => s.write(buf),
+            #[cfg(feature = "replay")]
+            Self::Replay(s) => s.write(buf),
         }
     }
 
@@ -74,10 +277,14 @@ impl Write for Stream {
         match self {
             Self::Tcp(s) => s.flush(),
             Self::Unix(s) => s.flush(),
+            Self::Command(s) => s.flush(),
             #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-            Self::Rustls(s) => s.flush(),
+            Self::Rustls(s, _) => s.flush(),
             #[cfg(feature = "native-tls")]
-            Self::NativeTls(s) => s.flush(),
+            Self::NativeTls(s, _) => s.flush(),
+            Self::Transcript(s) => s.flush(),
+            #[cfg(feature = "replay")]
+            Self::Replay(s) => s.flush(),
         }
     }
 }
@@ -86,10 +293,174 @@ impl Write for Stream {
 pub struct Tls {
     pub provider: Option<TlsProvider>,
     pub rustls: Rustls,
+    /// Extra trust roots: a PEM bundle, possibly holding several
+    /// certificates, or a directory of PEM files (OpenSSL `c_rehash`
+    /// style).
     pub cert: Option<PathBuf>,
+    /// Extra trust roots given inline, as a PEM bundle.
+    pub cert_pem: Option<String>,
+    /// Trusts only the given roots (see [`Tls::root_certs`]) instead
+    /// of adding them to the platform trust store.
+    pub cert_only: bool,
+    /// Also trusts the certificates designated by the OpenSSL
+    /// `SSL_CERT_FILE` and `SSL_CERT_DIR` environment variables.
+    /// Ignored with [`Tls::cert_only`], which trusts the given roots
+    /// only.
+    pub env_certs: bool,
+    /// Lowest TLS version accepted. Defaults to the lowest version
+    /// supported by the provider.
+    pub min_version: Option<TlsVersion>,
+    /// Highest TLS version accepted. Defaults to the highest version
+    /// supported by the provider.
+    pub max_version: Option<TlsVersion>,
+    /// Cipher suites allowed, named like rustls does (IANA names,
+    /// with a `TLS13_` prefix for TLS 1.3 suites, for example
+    /// `TLS13_AES_256_GCM_SHA384`). All the suites of the provider
+    /// are allowed when empty. Only supported by the `rustls`
+    /// provider.
+    pub cipher_suites: Vec<String>,
+    /// Name sent with SNI and used to verify the server certificate,
+    /// instead of the host connected to. Useful when connecting by
+    /// IP address, through an SSH tunnel or a port forwarding.
+    pub server_name: Option<String>,
+    /// Disables the Server Name Indication extension, for old
+    /// servers failing on it. Certificates are still verified.
+    pub disable_sni: bool,
+    /// Client identity presented to servers requiring mutual TLS.
+    pub client_auth: Option<TlsClientAuth>,
+    /// Accept only server certificates matching one of these
+    /// fingerprints, instead of validating them against trust
+    /// roots.
+    pub pins: Vec<TlsPin>,
+    /// Trust-on-first-use store consulted when the server
+    /// certificate fails platform verification.
+    pub known_hosts: Option<TlsKnownHosts>,
+    /// Certificate revocation checking. Only supported by the
+    /// `rustls` provider.
+    pub revocation: Option<TlsRevocation>,
+    /// Cache of TLS sessions, shared by the clones of this value.
+    /// Only used by the `rustls` provider.
+    pub session_cache: TlsSessionCache,
+    /// Cache of the configurations built for the `rustls` provider.
+    /// Clones of this value start with an empty cache, since their
+    /// options may differ. Options changed after a first connection
+    /// only apply with a new cache.
+    pub config_cache: TlsConfigCache,
 }
 
 impl Tls {
+    /// Collects the DER-encoded extra trust roots: [`Tls::cert`],
+    /// [`Tls::cert_pem`], then the certificates designated by the
+    /// OpenSSL environment variables when [`Tls::env_certs`] is set
+    /// (and [`Tls::cert_only`] is not).
+    pub fn root_certs(&self) -> Result<Vec<Vec<u8>>> {
+        let mut certs = Vec::new();
+
+        if let Some(path) = &self.cert {
+            debug!("using TLS certs at {}", path.display());
+            certs.extend(read_pem_certs(path)?);
+        }
+
+        if let Some(pem) = &self.cert_pem {
+            debug!("using inline TLS certs");
+            let found = parse_pem_certs(pem.as_bytes()).context("Parse inline TLS certs error")?;
+
+            if found.is_empty() {
+                bail!("No TLS cert found in inline PEM");
+            }
+
+            certs.extend(found);
+        }
+
+        if self.env_certs && !self.cert_only {
+            certs.extend(read_env_certs());
+        }
+
+        // bundles and c_rehash directories often contain duplicates
+        let mut seen = HashSet::new();
+        certs.retain(|cert| seen.insert(cert.clone()));
+
+        if self.cert_only && certs.is_empty() {
+            bail!("No TLS cert found to trust exclusively");
+        }
+
+        Ok(certs)
+    }
+
+    /// Returns the name used for SNI and to verify the server
+    /// certificate of `host`: [`Tls::server_name`] if set, `host`
+    /// otherwise.
+    pub fn server_name_for<'a>(&'a self, host: &'a str) -> &'a str {
+        match &self.server_name {
+            Some(name) => {
+                debug!("using TLS server name {name} for {host}");
+                name
+            }
+            // URLs wrap IPv6 addresses in brackets
+            None => host.trim_start_matches('[').trim_end_matches(']'),
+        }
+    }
+
+    /// Returns the name sent with SNI when connecting to `host`, if
+    /// any. SNI is not sent when disabled, nor for IP addresses.
+    pub fn sni_for(&self, host: &str) -> Option<String> {
+        if self.disable_sni {
+            return None;
+        }
+
+        let name = self.server_name_for(host);
+
+        if name.parse::<IpAddr>().is_ok() {
+            return None;
+        }
+
+        Some(name.to_owned())
+    }
+
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    pub fn rustls_server_name(&self, host: &str) -> Result<ServerName<'static>> {
+        let name = self.server_name_for(host);
+        ServerName::try_from(name.to_owned())
+            .with_context(|| format!("Invalid TLS server name {name}"))
+    }
+
+    /// Checks that the TLS version bounds are consistent.
+    pub fn check_versions(&self) -> Result<()> {
+        if let (Some(min), Some(max)) = (self.min_version, self.max_version) {
+            if min > max {
+                bail!("Invalid TLS versions: minimum {min:?} is greater than maximum {max:?}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the pins are well-formed, and not combined with
+    /// known hosts.
+    pub fn check_pins(&self) -> Result<()> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+
+        if self.known_hosts.is_some() {
+            bail!("TLS pins and known hosts cannot be used together");
+        }
+
+        for pin in &self.pins {
+            pin.fingerprint()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the given TLS version is within the
+    /// configured bounds.
+    pub fn allows_version(&self, version: TlsVersion) -> bool {
+        let above_min = self.min_version.map_or(true, |min| version >= min);
+        let below_max = self.max_version.map_or(true, |max| version <= max);
+        above_min && below_max
+    }
+
     pub fn provider(&self) -> Result<TlsProvider> {
         let provider = match &self.provider {
             #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
@@ -122,32 +493,44 @@ impl Tls {
         Ok(provider)
     }
 
+    /// Returns the rustls configuration used to connect to a server
+    /// listening on `port`, negotiating the `alpn` protocol if any.
+    ///
+    /// The configuration is built once, then taken from
+    /// [`Tls::config_cache`].
     #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
-    pub fn build_rustls_client_config(&self) -> Result<ClientConfig> {
-        let crypto_provider = match &self.rustls.crypto {
-            #[cfg(feature = "rustls-aws")]
-            Some(RustlsCrypto::Aws) => RustlsCrypto::Aws,
-            #[cfg(not(feature = "rustls-aws"))]
-            Some(RustlsCrypto::Aws) => {
-                bail!("Missing cargo feature: `rustls-aws`");
-            }
-            #[cfg(feature = "rustls-ring")]
-            Some(RustlsCrypto::Ring) => RustlsCrypto::Ring,
-            #[cfg(not(feature = "rustls-ring"))]
-            Some(RustlsCrypto::Ring) => {
-                bail!("Missing cargo feature: `rustls-ring`");
-            }
-            #[cfg(feature = "rustls-ring")]
-            None => RustlsCrypto::Ring,
-            #[cfg(not(feature = "rustls-ring"))]
-            #[cfg(feature = "rustls-aws")]
-            None => RustlsCrypto::Aws,
-            #[cfg(not(feature = "rustls-aws"))]
-            #[cfg(not(feature = "rustls-ring"))]
-            None => {
-                bail!("Missing cargo feature: `rustls-aws` or `rustls-ring`");
-            }
-        };
+    pub fn rustls_client_config(
+        &self,
+        port: u16,
+        alpn: Option<&[u8]>,
+    ) -> Result<Arc<ClientConfig>> {
+        let key = (port, alpn.map(ToOwned::to_owned));
+        let mut configs = self
+            .config_cache
+            .configs
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(config) = configs.get(&key) {
+            return Ok(config.clone());
+        }
+
+        let mut config = self.build_rustls_client_config(port)?;
+
+        if let Some(alpn) = alpn {
+            config.alpn_protocols = vec![alpn.to_vec()];
+        }
+
+        let config = Arc::new(config);
+        configs.insert(key, config.clone());
+        Ok(config)
+    }
+
+    /// Builds the rustls configuration used to connect to a server
+    /// listening on `port`, which identifies it in the known hosts.
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    pub fn build_rustls_client_config(&self, port: u16) -> Result<ClientConfig> {
+        let crypto_provider = self.rustls.crypto()?;
 
         debug!("using rustls crypto provider: {crypto_provider:?}");
 
@@ -160,32 +543,356 @@ impl Tls {
             _ => unreachable!(),
         };
 
-        let crypto_provider = match crypto_provider.install_default() {
+        let mut crypto_provider = match crypto_provider.install_default() {
             Ok(()) => CryptoProvider::get_default().unwrap().clone(),
             Err(crypto_provider) => crypto_provider,
         };
 
-        let config = if let Some(pem_path) = &self.cert {
-            debug!("using TLS cert at {}", pem_path.display());
-            let pem = fs::read(pem_path)?;
+        if !self.cipher_suites.is_empty() {
+            let mut provider = (*crypto_provider).clone();
 
-            let Some(cert) = CertificateDer::pem_slice_iter(&pem).next() else {
-                bail!("empty TLS cert at {}", pem_path.display())
-            };
+            for name in &self.cipher_suites {
+                let supported = provider
+                    .cipher_suites
+                    .iter()
+                    .any(|suite| cipher_suite_is(suite, name));
 
-            let verifier = Verifier::new_with_extra_roots(vec![cert?], crypto_provider)?;
+                if !supported {
+                    let names: Vec<_> = provider
+                        .cipher_suites
+                        .iter()
+                        .filter_map(|suite| suite.suite().as_str())
+                        .collect();
+                    bail!(
+                        "TLS cipher suite {name} not supported by rustls, expected one of: {}",
+                        names.join(", ")
+                    );
+                }
+            }
 
-            ClientConfig::builder()
-                .dangerous()
-                .with_custom_certificate_verifier(Arc::new(verifier))
-                .with_no_client_auth()
-        } else {
+            provider.cipher_suites.retain(|suite| {
+                self.cipher_suites
+                    .iter()
+                    .any(|name| cipher_suite_is(suite, name))
+            });
+
+            debug!("using TLS cipher suites: {}", self.cipher_suites.join(", "));
+            crypto_provider = Arc::new(provider);
+        }
+
+        self.check_versions()?;
+        self.check_pins()?;
+
+        let mut versions: Vec<&'static SupportedProtocolVersion> = Vec::new();
+
+        if self.allows_version(TlsVersion::Tls12) {
+            versions.push(&version::TLS12);
+        }
+
+        if self.allows_version(TlsVersion::Tls13) {
+            versions.push(&version::TLS13);
+        }
+
+        if versions.is_empty() {
+            bail!("Invalid TLS versions: rustls only supports TLS 1.2 and TLS 1.3");
+        }
+
+        debug!("using TLS versions: {versions:?}");
+
+        let certs: Vec<CertificateDer<'static>> =
+            self.root_certs()?.into_iter().map(Into::into).collect();
+
+        let crls = match &self.revocation {
+            Some(revocation) => revocation.read_crls()?,
+            None => Vec::new(),
+        };
+
+        // CRLs are only supported by the WebPKI verifier, which then
+        // needs the OS trust roots to be loaded explicitly
+        let verifier: Arc<dyn ServerCertVerifier> = if self.cert_only || !crls.is_empty() {
+            let mut roots = RootCertStore::empty();
+
+            if self.cert_only {
+                debug!("using only the {} given TLS cert(s)", certs.len());
+            } else {
+                let native = rustls_native_certs::load_native_certs();
+
+                for err in native.errors {
+                    debug!("skipping OS TLS certs: {err}");
+                }
+
+                debug!("using OS TLS certs and {} extra cert(s)", certs.len());
+                roots.add_parsable_certificates(native.certs);
+            }
+
+            for cert in certs {
+                roots.add(cert)?;
+            }
+
+            let mut builder = WebPkiServerVerifier::builder_with_provider(
+                Arc::new(roots),
+                crypto_provider.clone(),
+            );
+
+            if let Some(revocation) = self.revocation.as_ref().filter(|_| !crls.is_empty()) {
+                builder = builder.with_crls(crls);
+
+                if revocation.end_entity_only {
+                    builder = builder.only_check_end_entity_revocation();
+                }
+
+                builder = match revocation.policy {
+                    TlsRevocationPolicy::HardFail => builder.enforce_revocation_expiration(),
+                    TlsRevocationPolicy::SoftFail => builder.allow_unknown_revocation_status(),
+                };
+            }
+
+            builder.build()?
+        } else if certs.is_empty() {
             debug!("using OS TLS certs");
-            ClientConfig::with_platform_verifier()?
+            Arc::new(Verifier::new(crypto_provider.clone())?)
+        } else {
+            debug!("using OS TLS certs and {} extra cert(s)", certs.len());
+            Arc::new(Verifier::new_with_extra_roots(
+                certs,
+                crypto_provider.clone(),
+            )?)
+        };
+
+        let verifier: Arc<dyn ServerCertVerifier> = if !self.pins.is_empty() {
+            debug!("using {} pinned TLS cert fingerprint(s)", self.pins.len());
+            Arc::new(PinnedVerifier::new(self.pins.clone(), verifier))
+        } else if let Some(known_hosts) = &self.known_hosts {
+            debug!("using TLS known hosts at {}", known_hosts.path.display());
+            Arc::new(KnownHostsVerifier::new(known_hosts.clone(), port, verifier))
+        } else {
+            verifier
+        };
+
+        // wraps pins and known hosts, so that stapled OCSP responses
+        // are checked whatever validated the certificate
+        let verifier: Arc<dyn ServerCertVerifier> = match &self.revocation {
+            Some(revocation) if revocation.ocsp => {
+                debug!("checking stapled OCSP responses");
+                Arc::new(OcspVerifier::new(
+                    revocation.policy,
+                    crypto_provider.clone(),
+                    verifier,
+                ))
+            }
+            _ => verifier,
+        };
+
+        let builder = ClientConfig::builder_with_provider(crypto_provider)
+            .with_protocol_versions(&versions)
+            .context("Invalid TLS versions and cipher suites combination")?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier);
+
+        let mut config = match &self.client_auth {
+            None => builder.with_no_client_auth(),
+            Some(TlsClientAuth::Pem { cert, key }) => {
+                debug!("using TLS client cert at {}", cert.display());
+
+                let certs = CertificateDer::pem_file_iter(cert)
+                    .with_context(|| format!("Read TLS client cert at {} error", cert.display()))?
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                if certs.is_empty() {
+                    bail!("empty TLS client cert at {}", cert.display())
+                }
+
+                let key = PrivateKeyDer::from_pem_file(key)
+                    .with_context(|| format!("Read TLS client key at {} error", key.display()))?;
+
+                builder.with_client_auth_cert(certs, key)?
+            }
+            Some(TlsClientAuth::Pkcs12 { path, .. }) => {
+                bail!(
+                    "PKCS#12 TLS client identity at {} requires the `native-tls` provider",
+                    path.display()
+                )
+            }
         };
 
+        if self.disable_sni {
+            debug!("disabling TLS SNI");
+            config.enable_sni = false;
+        }
+
+        config.resumption = self.session_cache.rustls_resumption();
+
         Ok(config)
     }
+
+    #[cfg(feature = "native-tls")]
+    pub fn build_native_tls_connector(&self) -> Result<TlsConnector> {
+        if self.known_hosts.is_some() {
+            bail!("TLS known hosts require the `rustls` provider");
+        }
+
+        if !self.cipher_suites.is_empty() {
+            bail!("TLS cipher suites require the `rustls` provider");
+        }
+
+        if self.revocation.is_some() {
+            bail!("TLS revocation checking requires the `rustls` provider");
+        }
+
+        let mut builder = TlsConnector::builder();
+
+        if self.disable_sni {
+            debug!("disabling TLS SNI");
+            builder.use_sni(false);
+        }
+
+        self.check_versions()?;
+        self.check_pins()?;
+        builder.min_protocol_version(self.min_version.map(TlsVersion::to_native_tls));
+        builder.max_protocol_version(self.max_version.map(TlsVersion::to_native_tls));
+
+        for cert in self.root_certs()? {
+            builder.add_root_certificate(native_tls::Certificate::from_der(&cert)?);
+        }
+
+        if self.cert_only {
+            debug!("using only the given TLS certs");
+            builder.disable_built_in_roots(true);
+        }
+
+        match &self.client_auth {
+            None => (),
+            Some(TlsClientAuth::Pem { cert, key }) => {
+                debug!("using TLS client cert at {}", cert.display());
+                let cert = fs::read(cert)
+                    .with_context(|| format!("Read TLS client cert at {} error", cert.display()))?;
+                let key = fs::read(key)
+                    .with_context(|| format!("Read TLS client key at {} error", key.display()))?;
+                builder.identity(Identity::from_pkcs8(&cert, &key)?);
+            }
+            Some(TlsClientAuth::Pkcs12 { path, password }) => {
+                debug!("using TLS client identity at {}", path.display());
+                let der = fs::read(path).with_context(|| {
+                    format!("Read TLS client identity at {} error", path.display())
+                })?;
+                let password = match password {
+                    Some(password) => password.expose_secret(),
+                    None => "",
+                };
+                builder.identity(Identity::from_pkcs12(&der, password)?);
+            }
+        }
+
+        if !self.pins.is_empty() {
+            // pins are checked after the handshake, see
+            // `connect_native_tls`
+            builder.danger_accept_invalid_certs(true);
+        }
+
+        Ok(builder.build()?)
+    }
+
+    #[cfg(feature = "native-tls")]
+    pub fn connect_native_tls(
+        &self,
+        host: &str,
+        stream: Stream,
+    ) -> Result<native_tls::TlsStream<Box<Stream>>> {
+        // native-tls gives no control over sessions, which the
+        // platform library may or may not resume on its own
+        let connector = self.build_native_tls_connector()?;
+        let stream = connector.connect(self.server_name_for(host), Box::new(stream))?;
+        self.verify_native_tls_peer(host, stream.peer_certificate()?)?;
+        Ok(stream)
+    }
+
+    /// Checks the certificate presented by `host` during a
+    /// native-tls handshake against the pins, if any.
+    #[cfg(feature = "native-tls")]
+    pub fn verify_native_tls_peer(
+        &self,
+        host: &str,
+        cert: Option<native_tls::Certificate>,
+    ) -> Result<()> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+
+        let Some(cert) = cert else {
+            bail!("missing TLS peer certificate");
+        };
+
+        verify_pins(&self.pins, &cert.to_der()?, self.server_name_for(host))
+    }
+}
+
+/// Rustls configurations built by [`Tls::rustls_client_config`].
+///
+/// The configurations hold the verifiers built from the options of
+/// the [`Tls`] value they were built for, so the cache is not shared
+/// with clones: cloning gives an empty cache.
+#[derive(Debug, Default)]
+pub struct TlsConfigCache {
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    configs: Mutex<HashMap<TlsConfigKey, Arc<ClientConfig>>>,
+}
+
+impl Clone for TlsConfigCache {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// Port and ALPN protocol a rustls configuration was built for.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+type TlsConfigKey = (u16, Option<Vec<u8>>);
+
+/// TLS client identity.
+#[derive(Clone, Debug)]
+pub enum TlsClientAuth {
+    /// PEM certificate chain (leaf first) and PKCS#8 PEM private
+    /// key, supported by all providers.
+    Pem { cert: PathBuf, key: PathBuf },
+    /// PKCS#12 archive, only supported by the `native-tls`
+    /// provider.
+    Pkcs12 {
+        path: PathBuf,
+        password: Option<SecretString>,
+    },
+}
+
+/// TLS protocol version.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum TlsVersion {
+    /// TLS 1.0, deprecated by RFC 8996. Not supported by rustls.
+    Tls10,
+    /// TLS 1.1, deprecated by RFC 8996. Not supported by rustls.
+    Tls11,
+    Tls12,
+    /// TLS 1.3. Not supported by native-tls on Apple platforms.
+    Tls13,
+}
+
+impl TlsVersion {
+    #[cfg(feature = "native-tls")]
+    fn to_native_tls(self) -> native_tls::Protocol {
+        match self {
+            Self::Tls10 => native_tls::Protocol::Tlsv10,
+            Self::Tls11 => native_tls::Protocol::Tlsv11,
+            Self::Tls12 => native_tls::Protocol::Tlsv12,
+            Self::Tls13 => native_tls::Protocol::Tlsv13,
+        }
+    }
+}
+
+/// Returns whether the given rustls cipher suite has the given name,
+/// ignoring case.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn cipher_suite_is(suite: &SupportedCipherSuite, name: &str) -> bool {
+    match suite.suite().as_str() {
+        Some(suite) => suite.eq_ignore_ascii_case(name),
+        None => false,
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -199,8 +906,119 @@ pub struct Rustls {
     pub crypto: Option<RustlsCrypto>,
 }
 
+impl Rustls {
+    /// Returns the crypto provider to use, failing if it was not
+    /// compiled in.
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    pub fn crypto(&self) -> Result<RustlsCrypto> {
+        let crypto = match &self.crypto {
+            #[cfg(feature = "rustls-aws")]
+            Some(RustlsCrypto::Aws) => RustlsCrypto::Aws,
+            #[cfg(not(feature = "rustls-aws"))]
+            Some(RustlsCrypto::Aws) => {
+                bail!("Missing cargo feature: `rustls-aws`");
+            }
+            #[cfg(feature = "rustls-ring")]
+            Some(RustlsCrypto::Ring) => RustlsCrypto::Ring,
+            #[cfg(not(feature = "rustls-ring"))]
+            Some(RustlsCrypto::Ring) => {
+                bail!("Missing cargo feature: `rustls-ring`");
+            }
+            #[cfg(feature = "rustls-ring")]
+            None => RustlsCrypto::Ring,
+            #[cfg(not(feature = "rustls-ring"))]
+            #[cfg(feature = "rustls-aws")]
+            None => RustlsCrypto::Aws,
+        };
+
+        Ok(crypto)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum RustlsCrypto {
     Aws,
     Ring,
 }
+
+#[cfg(test)]
+mod tests {
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    use std::sync::Arc;
+    use std::{env, fs, process};
+
+    use base64::{prelude::BASE64_STANDARD, Engine};
+
+    use super::Tls;
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    use super::TlsPin;
+
+    const CA: &[u8] = include_bytes!(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/ca.der"
+    ));
+
+    #[test]
+    fn root_certs_env() {
+        let pem = BASE64_STANDARD.encode(CA).into_bytes();
+        let pem: Vec<&str> = pem
+            .chunks(64)
+            .map(|line| std::str::from_utf8(line).unwrap())
+            .collect();
+        let pem = format!(
+            "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+            pem.join("\n")
+        );
+
+        let path = env::temp_dir().join(format!("root-certs-{}.pem", process::id()));
+        fs::write(&path, pem).unwrap();
+        env::set_var("SSL_CERT_FILE", &path);
+
+        let tls = Tls::default();
+        assert!(tls.root_certs().unwrap().is_empty());
+
+        let tls = Tls {
+            env_certs: true,
+            ..Default::default()
+        };
+        // SSL_CERT_DIR may add more
+        assert_eq!(tls.root_certs().unwrap()[0], CA);
+
+        let tls = Tls {
+            env_certs: true,
+            cert_only: true,
+            ..Default::default()
+        };
+        let err = tls.root_certs().unwrap_err();
+        assert_eq!(err.to_string(), "No TLS cert found to trust exclusively");
+
+        env::remove_var("SSL_CERT_FILE");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    #[test]
+    fn rustls_client_config_cache() {
+        let tls = Tls::default();
+
+        let config = tls.rustls_client_config(993, Some(b"imap")).unwrap();
+        assert_eq!(config.alpn_protocols, vec![b"imap".to_vec()]);
+
+        let cached = tls.rustls_client_config(993, Some(b"imap")).unwrap();
+        assert!(Arc::ptr_eq(&config, &cached));
+
+        // clones may change verifier options, like pins
+        let mut clone = tls.clone();
+        clone.pins = vec![TlsPin::Spki(
+            "lxsdLxoasLtRwYMpGUl9xdjUGkndYzG9c1nDrkTS0I8=".into(),
+        )];
+        let pinned = clone.rustls_client_config(993, Some(b"imap")).unwrap();
+        assert!(!Arc::ptr_eq(&config, &pinned));
+
+        let other_port = tls.rustls_client_config(143, Some(b"imap")).unwrap();
+        assert!(!Arc::ptr_eq(&config, &other_port));
+
+        let no_alpn = tls.rustls_client_config(993, None).unwrap();
+        assert!(no_alpn.alpn_protocols.is_empty());
+    }
+}
@@ -0,0 +1,369 @@
+use std::{
+    net::{IpAddr, Shutdown, SocketAddr, TcpStream},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use log::debug;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+
+#[cfg(feature = "replay")]
+use super::Replay;
+use super::{Proxy, Resolver, Transcript};
+
+/// Delay before starting the next connection attempt while the
+/// previous one is still pending, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Maximum number of connection attempts in flight at once, each one
+/// running in its own thread.
+const MAX_PENDING_ATTEMPTS: usize = 4;
+
+/// Options used to establish the TCP connection underneath a
+/// session.
+#[derive(Clone, Debug, Default)]
+pub struct Tcp {
+    /// Proxy to connect through.
+    pub proxy: Option<Proxy>,
+    /// Reads the proxy from the environment when none is set (see
+    /// [`Proxy::from_env`]).
+    pub proxy_from_env: bool,
+    /// Resolver of host names, also used to resolve the proxy host
+    /// and, unless the proxy resolves them, target hosts.
+    pub resolver: Resolver,
+    /// Transcript of the plaintext exchanged with the server, for
+    /// debugging. Also applies to Unix socket and command
+    /// transports.
+    pub transcript: Option<Transcript>,
+    /// Recorded transcript replayed instead of opening TCP
+    /// connections, for tests.
+    #[cfg(feature = "replay")]
+    pub replay: Option<Replay>,
+    /// Timeout of each connection attempt.
+    pub connect_timeout: Option<Duration>,
+    /// Timeout of reads on the socket. Only applies to the blocking
+    /// API.
+    pub read_timeout: Option<Duration>,
+    /// Timeout of writes on the socket. Only applies to the blocking
+    /// API.
+    pub write_timeout: Option<Duration>,
+    /// Deadline of the whole session setup: connection, TLS
+    /// handshake, greeting and authentication.
+    pub handshake_timeout: Option<Duration>,
+    /// Idle time before TCP keepalive probes are sent. Keepalive is
+    /// disabled when unset.
+    pub keepalive: Option<Duration>,
+    /// Disables Nagle's algorithm (`TCP_NODELAY`).
+    pub nodelay: bool,
+    /// Local address or network interface to connect from.
+    pub bind: Option<TcpBind>,
+    /// Address family of the connection.
+    pub family: TcpFamily,
+}
+
+/// Local end of a TCP connection.
+#[derive(Clone, Debug)]
+pub enum TcpBind {
+    /// Connects from the given local address. Only addresses of the
+    /// same family are tried.
+    Address(IpAddr),
+    /// Connects through the given network interface (`SO_BINDTODEVICE`,
+    /// Linux only).
+    Interface(String),
+}
+
+/// Address family used to connect to a host resolving to both IPv4
+/// and IPv6 addresses.
+///
+/// Whatever the preference, addresses of both families are tried in
+/// turn, as described by Happy Eyeballs (RFC 8305), unless the
+/// family is restricted.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum TcpFamily {
+    /// Starts with the family of the first resolved address.
+    #[default]
+    Any,
+    PreferIpv4,
+    PreferIpv6,
+    /// Only connects over IPv4.
+    Ipv4,
+    /// Only connects over IPv6.
+    Ipv6,
+}
+
+impl Tcp {
+    /// Returns default options connecting through the proxy set in
+    /// the environment, if any.
+    pub fn from_env() -> Self {
+        Self {
+            proxy_from_env: true,
+            ..Self::default()
+        }
+    }
+
+    /// Opens a TCP connection to `host:port`, through a proxy if
+    /// one is configured.
+    ///
+    /// `scheme` is the scheme of the URL being connected to, used to
+    /// pick the right proxy environment variable.
+    pub fn connect(&self, scheme: &str, host: &str, port: u16) -> Result<TcpStream> {
+        let proxy = match &self.proxy {
+            Some(proxy) => Some(proxy.clone()),
+            None if self.proxy_from_env => Proxy::from_env(scheme, host, port)?,
+            None => None,
+        };
+
+        match proxy {
+            Some(proxy) => proxy.connect(self, host, port),
+            None => self.connect_direct(host, port),
+        }
+    }
+
+    /// Opens a TCP connection to `host:port`, without proxy.
+    ///
+    /// All resolved addresses are tried: a new attempt starts every
+    /// 250 ms, or as soon as the previous one fails, alternating
+    /// address families, and the first established connection wins
+    /// (Happy Eyeballs, RFC 8305). At most four attempts are pending
+    /// at once, and the others are aborted once a connection is
+    /// established.
+    pub fn connect_direct(&self, host: &str, port: u16) -> Result<TcpStream> {
+        debug!("connecting to {host}:{port}");
+
+        let addrs = self.resolve(host, port)?;
+
+        let tcp = match addrs.as_slice() {
+            [addr] => self.connect_addr(*addr),
+            _ => self.connect_happy_eyeballs(addrs),
+        };
+
+        tcp.with_context(|| format!("Connect to {host}:{port} error"))
+    }
+
+    /// Resolves `host:port`, then sorts addresses by family
+    /// preference, interleaving families.
+    fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>> {
+        let addrs = self.resolver.resolve(host, port)?;
+
+        let ipv6_first = match self.family {
+            TcpFamily::Any => addrs.first().map(SocketAddr::is_ipv6).unwrap_or_default(),
+            TcpFamily::PreferIpv6 | TcpFamily::Ipv6 => true,
+            TcpFamily::PreferIpv4 | TcpFamily::Ipv4 => false,
+        };
+
+        let (mut ipv6, mut ipv4): (Vec<_>, Vec<_>) =
+            addrs.into_iter().partition(SocketAddr::is_ipv6);
+
+        match (self.family, &self.bind) {
+            (TcpFamily::Ipv4, _) | (_, Some(TcpBind::Address(IpAddr::V4(_)))) => ipv6.clear(),
+            (TcpFamily::Ipv6, _) | (_, Some(TcpBind::Address(IpAddr::V6(_)))) => ipv4.clear(),
+            _ => (),
+        }
+
+        let (first, second) = if ipv6_first {
+            (ipv6, ipv4)
+        } else {
+            (ipv4, ipv6)
+        };
+
+        let mut addrs = Vec::with_capacity(first.len() + second.len());
+        let mut first = first.into_iter();
+        let mut second = second.into_iter();
+
+        loop {
+            match (first.next(), second.next()) {
+                (None, None) => break,
+                (a, b) => addrs.extend(a.into_iter().chain(b)),
+            }
+        }
+
+        if addrs.is_empty() {
+            bail!(
+                "Resolve {host} error: no address found for {:?}",
+                self.family
+            );
+        }
+
+        Ok(addrs)
+    }
+
+    fn connect_happy_eyeballs(&self, addrs: Vec<SocketAddr>) -> Result<TcpStream> {
+        let tcp = Arc::new(self.clone());
+        // sockets still connecting, shut down as soon as one attempt
+        // succeeds so that the other threads do not linger
+        let connecting = Arc::new(Mutex::new(Vec::new()));
+        let (tx, rx) = mpsc::channel();
+        let mut addrs = addrs.into_iter().peekable();
+        let mut pending = 0;
+        let mut errors = Vec::new();
+
+        loop {
+            if pending < MAX_PENDING_ATTEMPTS {
+                if let Some(addr) = addrs.next() {
+                    debug!("trying {addr}");
+                    let (tcp, connecting, tx) = (tcp.clone(), connecting.clone(), tx.clone());
+                    thread::spawn(move || tx.send((addr, tcp.connect_attempt(addr, &connecting))));
+                    pending += 1;
+                }
+            }
+
+            if pending == 0 {
+                break;
+            }
+
+            // waits for the current attempts, but not longer than the
+            // attempt delay while addresses remain to be tried
+            let result = if pending < MAX_PENDING_ATTEMPTS && addrs.peek().is_some() {
+                rx.recv_timeout(CONNECTION_ATTEMPT_DELAY).ok()
+            } else {
+                rx.recv().ok()
+            };
+
+            match result {
+                None => continue,
+                Some((addr, Ok(tcp))) => {
+                    debug!("connected to {addr}");
+                    abort_attempts(&connecting);
+                    return Ok(tcp);
+                }
+                Some((addr, Err(err))) => {
+                    debug!("cannot connect to {addr}: {err:#}");
+                    errors.push(format!("{addr}: {err:#}"));
+                    pending -= 1;
+                }
+            }
+        }
+
+        bail!("all connection attempts failed ({})", errors.join(", "))
+    }
+
+    /// Connects to `addr`, keeping the socket in `connecting` until
+    /// the attempt completes so that it can be aborted.
+    fn connect_attempt(
+        &self,
+        addr: SocketAddr,
+        connecting: &Mutex<Vec<(SocketAddr, Socket)>>,
+    ) -> Result<TcpStream> {
+        let socket = self.socket(addr)?;
+
+        if let Ok(mut connecting) = connecting.lock() {
+            connecting.push((addr, socket.try_clone()?));
+        }
+
+        let tcp = self.connect_socket(socket, addr);
+
+        if let Ok(mut connecting) = connecting.lock() {
+            connecting.retain(|(a, _)| *a != addr);
+        }
+
+        tcp
+    }
+
+    fn connect_addr(&self, addr: SocketAddr) -> Result<TcpStream> {
+        self.connect_socket(self.socket(addr)?, addr)
+    }
+
+    /// Creates a socket for `addr`, bound as configured.
+    fn socket(&self, addr: SocketAddr) -> Result<Socket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+        match &self.bind {
+            None => (),
+            Some(TcpBind::Address(ip)) => {
+                let local = SocketAddr::new(*ip, 0);
+                socket
+                    .bind(&local.into())
+                    .with_context(|| format!("Bind to {ip} error"))?;
+            }
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            Some(TcpBind::Interface(name)) => {
+                socket
+                    .bind_device(Some(name.as_bytes()))
+                    .with_context(|| format!("Bind to interface {name} error"))?;
+            }
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            Some(TcpBind::Interface(name)) => {
+                bail!("Bind to interface {name} error: not supported on this platform");
+            }
+        }
+
+        Ok(socket)
+    }
+
+    fn connect_socket(&self, socket: Socket, addr: SocketAddr) -> Result<TcpStream> {
+        match self.connect_timeout {
+            Some(timeout) => socket.connect_timeout(&addr.into(), timeout)?,
+            None => socket.connect(&addr.into())?,
+        }
+
+        let tcp = TcpStream::from(socket);
+        self.configure(&tcp)?;
+        Ok(tcp)
+    }
+
+    /// Applies socket options to an established connection.
+    fn configure(&self, tcp: &TcpStream) -> Result<()> {
+        tcp.set_nodelay(self.nodelay)?;
+        tcp.set_read_timeout(self.read_timeout)?;
+        tcp.set_write_timeout(self.write_timeout)?;
+
+        if let Some(time) = self.keepalive {
+            let keepalive = TcpKeepalive::new().with_time(time);
+            SockRef::from(tcp).set_tcp_keepalive(&keepalive)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Aborts the connection attempts still in flight. Shutting down a
+/// socket interrupts a pending connect on most platforms; otherwise
+/// the attempt runs until it completes or times out, and the
+/// connection is closed right away.
+fn abort_attempts(connecting: &Mutex<Vec<(SocketAddr, Socket)>>) {
+    let Ok(mut connecting) = connecting.lock() else {
+        return;
+    };
+
+    for (addr, socket) in connecting.drain(..) {
+        debug!("aborting connection attempt to {addr}");
+        let _ = socket.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{SocketAddr, TcpListener};
+
+    use super::Tcp;
+
+    #[test]
+    fn happy_eyeballs_skips_refused_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap();
+        let refused_addr = refused.local_addr().unwrap();
+        drop(refused);
+
+        let addrs: Vec<SocketAddr> = vec![refused_addr, listener.local_addr().unwrap()];
+        let tcp = Tcp::default().connect_happy_eyeballs(addrs).unwrap();
+
+        assert_eq!(tcp.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+
+    #[test]
+    fn happy_eyeballs_reports_all_errors() {
+        let refused = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = refused.local_addr().unwrap();
+        drop(refused);
+
+        let err = Tcp::default()
+            .connect_happy_eyeballs(vec![addr, addr])
+            .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .starts_with("all connection attempts failed"));
+    }
+}
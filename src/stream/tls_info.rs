@@ -0,0 +1,308 @@
+#[cfg(feature = "native-tls")]
+use std::io::{Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+#[cfg(any(
+    feature = "rustls-aws",
+    feature = "rustls-ring",
+    feature = "native-tls"
+))]
+use log::warn;
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use rustls::{ClientConnection, CommonState, ProtocolVersion};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+use x509_parser::{
+    asn1_rs::oid,
+    oid_registry::{
+        OID_HASH_SHA1, OID_NIST_HASH_SHA256, OID_NIST_HASH_SHA384, OID_NIST_HASH_SHA512,
+        OID_PKCS1_MD5WITHRSAENC, OID_PKCS1_RSASSAPSS, OID_PKCS1_SHA1WITHRSA,
+        OID_PKCS1_SHA224WITHRSA, OID_PKCS1_SHA256WITHRSA, OID_PKCS1_SHA384WITHRSA,
+        OID_PKCS1_SHA512WITHRSA, OID_SIG_DSA_WITH_SHA1, OID_SIG_ECDSA_WITH_SHA224,
+        OID_SIG_ECDSA_WITH_SHA256, OID_SIG_ECDSA_WITH_SHA384, OID_SIG_ECDSA_WITH_SHA512,
+    },
+    signature_algorithm::SignatureAlgorithm,
+};
+use x509_parser::{parse_x509_certificate, time::ASN1Time};
+
+#[cfg(feature = "sasl")]
+use crate::sasl::SaslChannelBinding;
+
+use super::{TlsFingerprints, TlsVersion};
+
+/// Details negotiated during a TLS handshake.
+///
+/// Details the TLS provider does not expose are left empty:
+/// native-tls exposes neither the version nor the cipher suite, and
+/// only the leaf certificate of the server. ALPN is only negotiated
+/// by the rustls provider.
+#[derive(Clone, Debug, Default)]
+pub struct TlsInfo {
+    pub version: Option<TlsVersion>,
+    /// Cipher suite, named like rustls does (see
+    /// [`Tls::cipher_suites`](super::Tls::cipher_suites)).
+    pub cipher_suite: Option<String>,
+    /// Protocol negotiated with ALPN.
+    pub alpn: Option<Vec<u8>>,
+    /// Name sent with SNI.
+    pub server_name: Option<String>,
+    /// Certificate chain presented by the server, leaf first.
+    pub peer_certs: Vec<TlsCertInfo>,
+}
+
+impl TlsInfo {
+    /// Returns the certificate of the server itself.
+    pub fn peer_cert(&self) -> Option<&TlsCertInfo> {
+        self.peer_certs.first()
+    }
+
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    pub(crate) fn from_rustls(conn: &CommonState, server_name: Option<String>) -> Result<Self> {
+        let version = match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_0) => Some(TlsVersion::Tls10),
+            Some(ProtocolVersion::TLSv1_1) => Some(TlsVersion::Tls11),
+            Some(ProtocolVersion::TLSv1_2) => Some(TlsVersion::Tls12),
+            Some(ProtocolVersion::TLSv1_3) => Some(TlsVersion::Tls13),
+            _ => None,
+        };
+
+        let cipher_suite = conn
+            .negotiated_cipher_suite()
+            .and_then(|suite| suite.suite().as_str())
+            .map(ToOwned::to_owned);
+
+        let certs = conn.peer_certificates().unwrap_or_default();
+        let peer_certs = parse_peer_certs(certs.iter().map(|cert| cert.as_ref()));
+
+        Ok(Self {
+            version,
+            cipher_suite,
+            alpn: conn.alpn_protocol().map(ToOwned::to_owned),
+            server_name,
+            peer_certs,
+        })
+    }
+
+    #[cfg(feature = "native-tls")]
+    pub(crate) fn from_native_tls<S: Read + Write>(
+        stream: &native_tls::TlsStream<S>,
+        server_name: Option<String>,
+    ) -> Result<Self> {
+        let peer_certs = match stream.peer_certificate()? {
+            Some(cert) => parse_peer_certs([cert.to_der()?.as_slice()]),
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            version: None,
+            cipher_suite: None,
+            alpn: None,
+            server_name,
+            peer_certs,
+        })
+    }
+}
+
+/// Channel binding of a TLS connection (RFC 5056), tying an
+/// authentication to the connection it happens on.
+#[derive(Clone, Debug)]
+pub enum TlsChannelBinding {
+    /// Keying material exported from a TLS 1.3 connection
+    /// (`tls-exporter`, RFC 9266).
+    Exporter(Vec<u8>),
+    /// Hash of the server certificate (`tls-server-end-point`, RFC
+    /// 5929).
+    ServerEndPoint(Vec<u8>),
+}
+
+impl TlsChannelBinding {
+    /// Returns the name of the channel binding type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Exporter(_) => "tls-exporter",
+            Self::ServerEndPoint(_) => "tls-server-end-point",
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Self::Exporter(data) | Self::ServerEndPoint(data) => data,
+        }
+    }
+
+    /// Uses `tls-exporter` for TLS 1.3 connections, and
+    /// `tls-server-end-point` otherwise, since exporters are only safe
+    /// with TLS 1.2 when extended master secrets are negotiated.
+    #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+    pub(crate) fn from_rustls(conn: &ClientConnection) -> Result<Option<Self>> {
+        if conn.protocol_version() == Some(ProtocolVersion::TLSv1_3) {
+            let data = conn.export_keying_material([0; 32], b"EXPORTER-Channel-Binding", None)?;
+            return Ok(Some(Self::Exporter(data.to_vec())));
+        }
+
+        let Some(cert) = conn.peer_certificates().and_then(|certs| certs.first()) else {
+            return Ok(None);
+        };
+
+        let Some(data) = server_end_point(cert)? else {
+            return Ok(None);
+        };
+
+        Ok(Some(Self::ServerEndPoint(data)))
+    }
+
+    #[cfg(feature = "native-tls")]
+    pub(crate) fn from_native_tls<S: Read + Write>(
+        stream: &native_tls::TlsStream<S>,
+    ) -> Result<Option<Self>> {
+        Ok(stream.tls_server_end_point()?.map(Self::ServerEndPoint))
+    }
+}
+
+/// Hashes a certificate for the `tls-server-end-point` channel
+/// binding (RFC 5929): with the hash function of its signature
+/// algorithm, SHA-256 replacing MD5 and SHA-1.
+///
+/// Returns `None` when the signature algorithm does not use exactly
+/// one known hash function, like Ed25519, for which the channel
+/// binding is undefined.
+#[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+fn server_end_point(cert: &[u8]) -> Result<Option<Vec<u8>>> {
+    let Ok((_, parsed)) = parse_x509_certificate(cert) else {
+        bail!("Parse X.509 certificate error");
+    };
+
+    let algorithm = &parsed.signature_algorithm;
+
+    // RSASSA-PSS carries its hash function in its parameters
+    let hash = if algorithm.algorithm == OID_PKCS1_RSASSAPSS {
+        match SignatureAlgorithm::try_from(algorithm) {
+            Ok(SignatureAlgorithm::RSASSA_PSS(params)) => params.hash_algorithm_oid().to_owned(),
+            _ => return Ok(None),
+        }
+    } else {
+        algorithm.algorithm.clone()
+    };
+
+    let sha256 = [
+        OID_PKCS1_MD5WITHRSAENC,
+        OID_PKCS1_SHA1WITHRSA,
+        OID_PKCS1_SHA256WITHRSA,
+        OID_SIG_DSA_WITH_SHA1,
+        oid! {1.2.840.10045.4.1}, // ecdsa-with-SHA1
+        OID_SIG_ECDSA_WITH_SHA256,
+        OID_HASH_SHA1,
+        OID_NIST_HASH_SHA256,
+    ];
+    let sha384 = [
+        OID_PKCS1_SHA384WITHRSA,
+        OID_SIG_ECDSA_WITH_SHA384,
+        OID_NIST_HASH_SHA384,
+    ];
+    let sha512 = [
+        OID_PKCS1_SHA512WITHRSA,
+        OID_SIG_ECDSA_WITH_SHA512,
+        OID_NIST_HASH_SHA512,
+    ];
+
+    let data = if sha256.contains(&hash) {
+        Sha256::digest(cert).to_vec()
+    } else if sha384.contains(&hash) {
+        Sha384::digest(cert).to_vec()
+    } else if sha512.contains(&hash) {
+        Sha512::digest(cert).to_vec()
+    } else if hash == OID_PKCS1_SHA224WITHRSA || hash == OID_SIG_ECDSA_WITH_SHA224 {
+        Sha224::digest(cert).to_vec()
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(data))
+}
+
+#[cfg(feature = "sasl")]
+impl From<TlsChannelBinding> for SaslChannelBinding {
+    fn from(binding: TlsChannelBinding) -> Self {
+        Self {
+            name: binding.name(),
+            data: binding.data().to_vec(),
+        }
+    }
+}
+
+/// Certificate presented by a server.
+#[derive(Clone, Debug)]
+pub struct TlsCertInfo {
+    pub subject: String,
+    pub issuer: String,
+    pub not_before: SystemTime,
+    pub not_after: SystemTime,
+    pub fingerprints: TlsFingerprints,
+    /// The certificate itself, DER-encoded.
+    pub der: Vec<u8>,
+}
+
+impl TlsCertInfo {
+    /// Parses a DER-encoded certificate.
+    pub fn from_der(der: &[u8]) -> Result<Self> {
+        let Ok((_, cert)) = parse_x509_certificate(der) else {
+            bail!("Parse X.509 certificate error");
+        };
+
+        let validity = cert.validity();
+
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            not_before: system_time(validity.not_before),
+            not_after: system_time(validity.not_after),
+            fingerprints: TlsFingerprints::from_der(der)?,
+            der: der.to_vec(),
+        })
+    }
+
+    /// Returns the time left before the certificate expires, or
+    /// `None` if it already expired.
+    pub fn expires_in(&self) -> Option<Duration> {
+        self.not_after.duration_since(SystemTime::now()).ok()
+    }
+}
+
+/// Parses the certificate chain presented by a server, leaf first.
+///
+/// Certificates that cannot be parsed are skipped, except the leaf:
+/// the rest of the chain would then be mistaken for it, so the chain
+/// is left empty instead.
+#[cfg(any(
+    feature = "rustls-aws",
+    feature = "rustls-ring",
+    feature = "native-tls"
+))]
+fn parse_peer_certs<'a>(certs: impl IntoIterator<Item = &'a [u8]>) -> Vec<TlsCertInfo> {
+    let mut peer_certs = Vec::new();
+
+    for (i, der) in certs.into_iter().enumerate() {
+        match TlsCertInfo::from_der(der) {
+            Ok(cert) => peer_certs.push(cert),
+            Err(err) if i == 0 => {
+                warn!("skipping server certificate chain: {err:#}");
+                break;
+            }
+            Err(err) => warn!("skipping server certificate #{i}: {err:#}"),
+        }
+    }
+
+    peer_certs
+}
+
+fn system_time(time: ASN1Time) -> SystemTime {
+    let secs = time.timestamp();
+
+    if secs < 0 {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    } else {
+        UNIX_EPOCH + Duration::from_secs(secs.unsigned_abs())
+    }
+}
@@ -0,0 +1,56 @@
+use anyhow::{bail, Result};
+use log::info;
+use url::Url;
+
+use crate::stream::{
+    tokio::{handshake_timeout, Stream},
+    Tcp, Tls, TlsInfo,
+};
+
+/// Async mirror of [`crate::stream::http::HttpSession`].
+#[derive(Debug)]
+pub struct HttpSession {
+    pub stream: Stream,
+}
+
+impl HttpSession {
+    pub async fn new(url: &Url, tls: Tls) -> Result<Self> {
+        Self::new_with_tcp(url, Tcp::default(), tls).await
+    }
+
+    /// Same as [`Self::new`], connecting with the given TCP options.
+    pub async fn new_with_tcp(url: &Url, tcp: Tcp, tls: Tls) -> Result<Self> {
+        let timeout = tcp.handshake_timeout;
+        handshake_timeout(timeout, Self::handshake(url, tcp, tls)).await
+    }
+
+    async fn handshake(url: &Url, tcp: Tcp, tls: Tls) -> Result<Self> {
+        info!("connecting to HTTP server using {url}");
+
+        let host = url.host_str().unwrap_or("127.0.0.1");
+
+        let stream = match url.scheme() {
+            scheme if scheme.eq_ignore_ascii_case("http") => {
+                let port = url.port().unwrap_or(80);
+                Stream::connect_tcp(&tcp, scheme, host, port).await?
+            }
+            scheme if scheme.eq_ignore_ascii_case("https") => {
+                let port = url.port().unwrap_or(443);
+                let stream = Stream::connect_tcp(&tcp, scheme, host, port).await?;
+                stream.upgrade_tls(host, port, &tls, None).await?
+            }
+            scheme => {
+                bail!("Unknown scheme {scheme}, expected http or https");
+            }
+        };
+
+        let stream = stream.with_transcript(tcp.transcript.as_ref())?;
+
+        Ok(Self { stream })
+    }
+
+    /// Returns the details negotiated by the TLS layer, if any.
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        self.stream.tls_info()
+    }
+}
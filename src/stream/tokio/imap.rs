@@ -0,0 +1,303 @@
+use anyhow::{bail, Result};
+use io_imap::{
+    context::ImapContext,
+    rfc3501::{
+        capability::{ImapCapabilityGet, ImapCapabilityGetResult},
+        greeting_with_capability::{
+            ImapGreetingWithCapabilityGet, ImapGreetingWithCapabilityGetResult,
+        },
+        login::{ImapSessionLogin, ImapSessionLoginParams, ImapSessionLoginResult},
+        starttls::{ImapStartTls, ImapStartTlsResult},
+    },
+    sasl::authenticate_plain::{
+        ImapSessionAuthenticatePlain, ImapSessionAuthenticatePlainParams,
+        ImapSessionAuthenticatePlainResult,
+    },
+    types::response::Capability,
+};
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use url::Url;
+
+use crate::{
+    sasl::{Sasl, SaslClient},
+    stream::{
+        authenticate::{imap_auth_mechanisms, AuthenticateResult, ImapAuthenticate, SaslAuth},
+        tokio::{handshake_timeout, CommandStream, Stream},
+        ReadBuffer, ReadFraming, Tcp, Tls, TlsInfo,
+    },
+};
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Async mirror of [`crate::stream::imap::ImapSession`], driving the
+/// same coroutines.
+#[derive(Debug)]
+pub struct ImapSession {
+    pub context: ImapContext,
+    pub stream: Stream,
+    pub buffer: ReadBuffer,
+}
+
+async fn drive_greeting_with_capability<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    context: ImapContext,
+) -> Result<ImapContext> {
+    let mut coroutine = ImapGreetingWithCapabilityGet::new(context);
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            ImapGreetingWithCapabilityGetResult::Ok(context) => return Ok(context),
+            ImapGreetingWithCapabilityGetResult::WantsRead => {
+                arg = Some(buffer.read_async(stream).await?);
+            }
+            ImapGreetingWithCapabilityGetResult::WantsWrite(bytes) => {
+                stream.write_all(&bytes).await?;
+                arg = None;
+            }
+            ImapGreetingWithCapabilityGetResult::Err { err, .. } => bail!(err),
+        }
+    }
+}
+
+async fn drive_capability<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    context: ImapContext,
+) -> Result<ImapContext> {
+    let mut coroutine = ImapCapabilityGet::new(context);
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            ImapCapabilityGetResult::Ok(context) => return Ok(context),
+            ImapCapabilityGetResult::WantsRead => {
+                arg = Some(buffer.read_async(stream).await?);
+            }
+            ImapCapabilityGetResult::WantsWrite(bytes) => {
+                stream.write_all(&bytes).await?;
+                arg = None;
+            }
+            ImapCapabilityGetResult::Err { err, .. } => bail!(err),
+        }
+    }
+}
+
+async fn drive_starttls<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    context: ImapContext,
+) -> Result<ImapContext> {
+    let mut coroutine = ImapStartTls::new(context);
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            ImapStartTlsResult::WantsStartTls { context, .. } => return Ok(context),
+            ImapStartTlsResult::WantsRead => {
+                arg = Some(buffer.read_async(stream).await?);
+            }
+            ImapStartTlsResult::WantsWrite(bytes) => {
+                stream.write_all(&bytes).await?;
+                arg = None;
+            }
+            ImapStartTlsResult::Err { err, .. } => bail!(err),
+        }
+    }
+}
+
+/// Authenticates with a SASL mechanism the I/O crate does not
+/// support, then refreshes the capabilities, which may change once
+/// authenticated.
+async fn drive_authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    context: ImapContext,
+    client: Box<dyn SaslClient>,
+    ir: bool,
+) -> Result<ImapContext> {
+    let mut coroutine = ImapAuthenticate::new(client, ir);
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            AuthenticateResult::Ok => break,
+            AuthenticateResult::WantsRead => {
+                arg = Some(buffer.read_async(stream).await?);
+            }
+            AuthenticateResult::WantsWrite(bytes) => {
+                stream.write_all(&bytes).await?;
+                arg = None;
+            }
+            AuthenticateResult::Err(err) => return Err(err),
+        }
+    }
+
+    let mut context = drive_capability(stream, buffer, context).await?;
+    context.authenticated = true;
+    Ok(context)
+}
+
+impl ImapSession {
+    /// Connects and authenticates to the IMAP server at `url`.
+    ///
+    /// Supported schemes: `imap` (plain TCP), `imaps` (TLS over TCP),
+    /// `unix` and `unixs` (plain and TLS over a Unix socket, whose
+    /// path is the URL path), `cmd` and `cmds` (plain and TLS over
+    /// the standard I/O of a command spawned through the shell, for
+    /// example `cmd:ssh mailhost /usr/lib/dovecot/imap`). For TLS
+    /// schemes, `starttls` selects STARTTLS instead of implicit TLS.
+    pub async fn new(url: Url, tls: Tls, starttls: bool, sasl: Sasl) -> Result<Self> {
+        Self::new_with_tcp(url, Tcp::default(), tls, starttls, sasl).await
+    }
+
+    /// Same as [`Self::new`], connecting with the given TCP options.
+    pub async fn new_with_tcp(
+        url: Url,
+        tcp: Tcp,
+        tls: Tls,
+        starttls: bool,
+        sasl: Sasl,
+    ) -> Result<Self> {
+        let timeout = tcp.handshake_timeout;
+        handshake_timeout(timeout, Self::handshake(url, tcp, tls, starttls, sasl)).await
+    }
+
+    async fn handshake(url: Url, tcp: Tcp, tls: Tls, starttls: bool, sasl: Sasl) -> Result<Self> {
+        info!("connecting to IMAP server using {url}");
+
+        let context = ImapContext::new();
+        let host = url.host_str().unwrap_or("127.0.0.1");
+
+        // secure schemes use TLS, either implicit or via STARTTLS,
+        // whatever the transport is
+        let (stream, secure) = match url.scheme() {
+            scheme if scheme.eq_ignore_ascii_case("imap") => {
+                let port = url.port().unwrap_or(143);
+                (Stream::connect_tcp(&tcp, scheme, host, port).await?, false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("imaps") => {
+                let port = url.port().unwrap_or(993);
+                (Stream::connect_tcp(&tcp, scheme, host, port).await?, true)
+            }
+            scheme if scheme.eq_ignore_ascii_case("unix") => {
+                (Stream::connect_unix(url.path()).await?, false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("unixs") => {
+                (Stream::connect_unix(url.path()).await?, true)
+            }
+            scheme if scheme.eq_ignore_ascii_case("cmd") => {
+                (Stream::Command(CommandStream::spawn_url(&url)?), false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("cmds") => {
+                (Stream::Command(CommandStream::spawn_url(&url)?), true)
+            }
+            scheme => {
+                bail!("Unknown scheme {scheme}, expected imap, imaps, unix, unixs, cmd or cmds");
+            }
+        };
+
+        let mut stream = stream.with_transcript(tcp.transcript.as_ref())?;
+
+        let mut buffer = ReadBuffer::with_capacity(ReadFraming::Imap, READ_BUFFER_SIZE);
+
+        let mut context = if !secure {
+            drive_greeting_with_capability(&mut stream, &mut buffer, context).await?
+        } else {
+            let context = if starttls {
+                drive_starttls(&mut stream, &mut buffer, context).await?
+            } else {
+                context
+            };
+
+            // bytes received in plaintext after STARTTLS could have
+            // been injected by an attacker
+            if !buffer.pending().is_empty() {
+                bail!("unexpected plaintext data received before TLS handshake");
+            }
+
+            // identifies the server in the known hosts, whatever the
+            // transport is
+            let port = url.port().unwrap_or(993);
+            stream = stream.upgrade_tls(host, port, &tls, Some(b"imap")).await?;
+
+            if starttls {
+                drive_capability(&mut stream, &mut buffer, context).await?
+            } else {
+                drive_greeting_with_capability(&mut stream, &mut buffer, context).await?
+            }
+        };
+
+        if !context.authenticated {
+            let ir = context.capability.contains(&Capability::SaslIr);
+            let mechanisms = imap_auth_mechanisms(context.capability.iter());
+            let channel_binding = || Ok(stream.tls_channel_binding()?.map(Into::into));
+
+            context = match SaslAuth::new(sasl, &mechanisms, channel_binding)? {
+                SaslAuth::Login(auth) => {
+                    let mut coroutine = ImapSessionLogin::new(
+                        context,
+                        ImapSessionLoginParams::new(auth.username, auth.password)?,
+                    );
+                    let mut arg: Option<&[u8]> = None;
+
+                    loop {
+                        match coroutine.resume(arg.take()) {
+                            ImapSessionLoginResult::Ok(c) => break c,
+                            ImapSessionLoginResult::WantsRead => {
+                                arg = Some(buffer.read_async(&mut stream).await?);
+                            }
+                            ImapSessionLoginResult::WantsWrite(bytes) => {
+                                stream.write_all(&bytes).await?;
+                                arg = None;
+                            }
+                            ImapSessionLoginResult::Err { err, .. } => bail!(err),
+                        }
+                    }
+                }
+                SaslAuth::Plain(auth) => {
+                    let mut coroutine = ImapSessionAuthenticatePlain::new(
+                        context,
+                        ImapSessionAuthenticatePlainParams::new(
+                            auth.authzid,
+                            auth.authcid,
+                            auth.passwd,
+                            ir,
+                        ),
+                    );
+                    let mut arg: Option<&[u8]> = None;
+
+                    loop {
+                        match coroutine.resume(arg.take()) {
+                            ImapSessionAuthenticatePlainResult::Ok(c) => break c,
+                            ImapSessionAuthenticatePlainResult::WantsRead => {
+                                arg = Some(buffer.read_async(&mut stream).await?);
+                            }
+                            ImapSessionAuthenticatePlainResult::WantsWrite(bytes) => {
+                                stream.write_all(&bytes).await?;
+                                arg = None;
+                            }
+                            ImapSessionAuthenticatePlainResult::Err { err, .. } => bail!(err),
+                        }
+                    }
+                }
+                SaslAuth::Client(client) => {
+                    drive_authenticate(&mut stream, &mut buffer, context, client, ir).await?
+                }
+            };
+        }
+
+        Ok(Self {
+            context,
+            stream,
+            buffer,
+        })
+    }
+
+    /// Returns the details negotiated by the TLS layer, if any.
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        self.stream.tls_info()
+    }
+}
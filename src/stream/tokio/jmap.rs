@@ -0,0 +1,147 @@
+use anyhow::{bail, Result};
+use io_jmap::rfc8620::{
+    session::JmapSession as IoJmapSession,
+    session_get::{JmapSessionGet, JmapSessionGetResult},
+};
+use log::info;
+use secrecy::SecretString;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use crate::stream::{
+    jmap::JmapAuth,
+    tokio::{handshake_timeout, Stream},
+    ReadBuffer, ReadFraming, Tcp, Tls, TlsInfo,
+};
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Async mirror of [`crate::stream::jmap::JmapSession`], driving the
+/// same coroutines.
+#[derive(Debug)]
+pub struct JmapSession {
+    pub session: IoJmapSession,
+    pub stream: Stream,
+    pub buffer: ReadBuffer,
+    pub http_auth: SecretString,
+    /// TCP options the session was established with, reused for
+    /// new connections.
+    pub tcp: Tcp,
+}
+
+fn use_tls(scheme: &str) -> bool {
+    scheme.eq_ignore_ascii_case("https") || scheme.eq_ignore_ascii_case("jmaps")
+}
+
+fn default_port(scheme: &str) -> u16 {
+    if use_tls(scheme) {
+        443
+    } else {
+        80
+    }
+}
+
+async fn connect(url: &Url, tcp: &Tcp, tls: &Tls) -> Result<Stream> {
+    let host = url.host_str().unwrap_or("localhost");
+    let port = url.port().unwrap_or_else(|| default_port(url.scheme()));
+    let mut stream = Stream::connect_tcp(tcp, url.scheme(), host, port).await?;
+
+    if use_tls(url.scheme()) {
+        stream = stream.upgrade_tls(host, port, tls, None).await?;
+    }
+
+    stream.with_transcript(tcp.transcript.as_ref())
+}
+
+impl JmapSession {
+    /// Returns a new TLS stream to `url` if its authority differs from the
+    /// current JMAP API URL, or `None` if the existing stream can be reused.
+    pub async fn connect_if_different(&self, url: &Url, tls: &Tls) -> Result<Option<Stream>> {
+        let api_url = &self.session.api_url;
+
+        let same_host = api_url.host() == url.host();
+        let same_port = api_url.port_or_known_default() == url.port_or_known_default();
+
+        if same_host && same_port {
+            return Ok(None);
+        }
+
+        let host = url.host_str().unwrap_or("localhost");
+        let port = url.port_or_known_default().unwrap_or(443);
+        let tcp = &self.tcp;
+        let stream = Stream::connect_tcp(tcp, url.scheme(), host, port).await?;
+        let stream = stream.upgrade_tls(host, port, tls, None).await?;
+        Ok(Some(stream.with_transcript(tcp.transcript.as_ref())?))
+    }
+
+    /// Establishes a JMAP session.
+    ///
+    /// See [`crate::stream::jmap::JmapSession::new`].
+    pub async fn new(server: String, tls: Tls, auth: JmapAuth) -> Result<Self> {
+        Self::new_with_tcp(server, Tcp::default(), tls, auth).await
+    }
+
+    /// Same as [`Self::new`], connecting with the given TCP options.
+    pub async fn new_with_tcp(server: String, tcp: Tcp, tls: Tls, auth: JmapAuth) -> Result<Self> {
+        let timeout = tcp.handshake_timeout;
+        handshake_timeout(timeout, Self::handshake(server, tcp, tls, auth)).await
+    }
+
+    async fn handshake(server: String, tcp: Tcp, tls: Tls, auth: JmapAuth) -> Result<Self> {
+        let url = match Url::parse(&server) {
+            Ok(url) => url,
+            Err(url::ParseError::RelativeUrlWithoutBase) => {
+                Url::parse(&format!("https://{server}"))?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        info!("connecting to JMAP server {url}");
+
+        match url.scheme() {
+            s if s.eq_ignore_ascii_case("https") || s.eq_ignore_ascii_case("jmaps") => {}
+            s if s.eq_ignore_ascii_case("http") || s.eq_ignore_ascii_case("jmap") => {}
+            scheme => bail!("unsupported JMAP scheme `{scheme}`, expected http/https/jmap/jmaps"),
+        }
+
+        let mut stream = connect(&url, &tcp, &tls).await?;
+        let mut buffer = ReadBuffer::with_capacity(ReadFraming::None, READ_BUFFER_SIZE);
+
+        let http_auth: SecretString = auth.into();
+        let mut coroutine = JmapSessionGet::new(&http_auth, &url);
+        let mut arg: Option<&[u8]> = None;
+
+        let session = loop {
+            match coroutine.resume(arg.take()) {
+                JmapSessionGetResult::Ok { session, .. } => break session,
+                JmapSessionGetResult::WantsRead => {
+                    arg = Some(buffer.read_async(&mut stream).await?);
+                }
+                JmapSessionGetResult::WantsWrite(bytes) => {
+                    stream.write_all(&bytes).await?;
+                    arg = None;
+                }
+                JmapSessionGetResult::WantsRedirect { url: new_url, .. } => {
+                    stream = connect(&new_url, &tcp, &tls).await?;
+                    buffer = ReadBuffer::with_capacity(ReadFraming::None, READ_BUFFER_SIZE);
+                    coroutine = JmapSessionGet::new(&http_auth, &new_url);
+                    arg = None;
+                }
+                JmapSessionGetResult::Err(err) => return Err(err.into()),
+            }
+        };
+
+        Ok(Self {
+            session,
+            stream,
+            buffer,
+            http_auth,
+            tcp,
+        })
+    }
+
+    /// Returns the details negotiated by the TLS layer, if any.
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        self.stream.tls_info()
+    }
+}
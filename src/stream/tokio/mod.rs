@@ -0,0 +1,17 @@
+//! Async (tokio) mirror of the stream layer.
+//!
+//! Sessions drive the exact same coroutines as their blocking
+//! counterparts, only I/O differs.
+
+#[cfg(feature = "http")]
+pub mod http;
+#[cfg(feature = "imap")]
+pub mod imap;
+#[cfg(feature = "jmap")]
+pub mod jmap;
+#[cfg(feature = "smtp")]
+pub mod smtp;
+mod stream;
+
+#[doc(inline)]
+pub use stream::*;
@@ -0,0 +1,278 @@
+use std::net::Ipv4Addr;
+
+use anyhow::{bail, Result};
+use io_smtp::{
+    login::{SmtpLogin, SmtpLoginResult},
+    rfc3207::starttls::{SmtpStartTls, SmtpStartTlsResult},
+    rfc4616::plain::{SmtpPlain, SmtpPlainResult},
+    rfc5321::{
+        ehlo::{SmtpEhlo, SmtpEhloResult},
+        greeting::{GetSmtpGreeting, GetSmtpGreetingResult},
+        types::ehlo_domain::EhloDomain,
+    },
+};
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use url::Url;
+
+use crate::{
+    sasl::{Sasl, SaslClient},
+    stream::{
+        authenticate::{smtp_ehlo_mechanisms, AuthenticateResult, SaslAuth, SmtpAuthenticate},
+        tokio::{handshake_timeout, CommandStream, Stream},
+        ReadBuffer, ReadFraming, Tcp, Tls, TlsInfo,
+    },
+};
+
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Async mirror of [`crate::stream::smtp::SmtpSession`], driving the
+/// same coroutines.
+#[derive(Debug)]
+pub struct SmtpSession {
+    pub stream: Stream,
+    pub buffer: ReadBuffer,
+}
+
+async fn drive_greeting<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+) -> Result<()> {
+    let mut coroutine = GetSmtpGreeting::new();
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            GetSmtpGreetingResult::Ok { .. } => return Ok(()),
+            GetSmtpGreetingResult::WantsRead => {
+                arg = Some(buffer.read_async(stream).await?);
+            }
+            GetSmtpGreetingResult::Err(err) => bail!(err),
+        }
+    }
+}
+
+/// Sends `EHLO`, returning the SASL mechanisms advertised by the
+/// server.
+async fn drive_ehlo<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    domain: EhloDomain<'_>,
+) -> Result<Vec<String>> {
+    let mut coroutine = SmtpEhlo::new(domain);
+    let mut mechanisms = Vec::new();
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            SmtpEhloResult::Ok { .. } => return Ok(mechanisms),
+            SmtpEhloResult::WantsRead => {
+                let bytes = buffer.read_async(stream).await?;
+
+                if let Some(auth) = smtp_ehlo_mechanisms(bytes) {
+                    mechanisms = auth;
+                }
+
+                arg = Some(bytes);
+            }
+            SmtpEhloResult::WantsWrite(bytes) => {
+                stream.write_all(&bytes).await?;
+                arg = None;
+            }
+            SmtpEhloResult::Err(err) => bail!(err),
+        }
+    }
+}
+
+async fn drive_starttls<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+) -> Result<()> {
+    let mut coroutine = SmtpStartTls::new();
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            SmtpStartTlsResult::WantsStartTls(_) => return Ok(()),
+            SmtpStartTlsResult::WantsRead => {
+                arg = Some(buffer.read_async(stream).await?);
+            }
+            SmtpStartTlsResult::WantsWrite(bytes) => {
+                stream.write_all(&bytes).await?;
+                arg = None;
+            }
+            SmtpStartTlsResult::Err(err) => bail!(err),
+        }
+    }
+}
+
+/// Authenticates with a SASL mechanism the I/O crate does not
+/// support.
+async fn drive_authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buffer: &mut ReadBuffer,
+    client: Box<dyn SaslClient>,
+) -> Result<()> {
+    let mut coroutine = SmtpAuthenticate::new(client);
+    let mut arg: Option<&[u8]> = None;
+
+    loop {
+        match coroutine.resume(arg.take()) {
+            AuthenticateResult::Ok => return Ok(()),
+            AuthenticateResult::WantsRead => {
+                arg = Some(buffer.read_async(stream).await?);
+            }
+            AuthenticateResult::WantsWrite(bytes) => {
+                stream.write_all(&bytes).await?;
+                arg = None;
+            }
+            AuthenticateResult::Err(err) => return Err(err),
+        }
+    }
+}
+
+impl SmtpSession {
+    /// Connects and authenticates to the SMTP server at `url`.
+    ///
+    /// Supported schemes: `smtp` (plain TCP), `smtps` (TLS over TCP),
+    /// `unix` and `unixs` (plain and TLS over a Unix socket, whose
+    /// path is the URL path), `cmd` and `cmds` (plain and TLS over
+    /// the standard I/O of a command spawned through the shell, for
+    /// example `cmd:ssh mailhost nc localhost 25`). For TLS
+    /// schemes, `starttls` selects STARTTLS instead of implicit TLS.
+    pub async fn new(url: Url, tls: Tls, starttls: bool, sasl: Sasl) -> Result<Self> {
+        Self::new_with_tcp(url, Tcp::default(), tls, starttls, sasl).await
+    }
+
+    /// Same as [`Self::new`], connecting with the given TCP options.
+    pub async fn new_with_tcp(
+        url: Url,
+        tcp: Tcp,
+        tls: Tls,
+        starttls: bool,
+        sasl: Sasl,
+    ) -> Result<Self> {
+        let timeout = tcp.handshake_timeout;
+        handshake_timeout(timeout, Self::handshake(url, tcp, tls, starttls, sasl)).await
+    }
+
+    async fn handshake(url: Url, tcp: Tcp, tls: Tls, starttls: bool, sasl: Sasl) -> Result<Self> {
+        info!("connecting to SMTP server using {url}");
+
+        let host = url.host_str().unwrap_or("127.0.0.1");
+        let domain: EhloDomain<'static> = Ipv4Addr::new(127, 0, 0, 1).into();
+
+        // secure schemes use TLS, either implicit or via STARTTLS,
+        // whatever the transport is
+        let (stream, secure) = match url.scheme() {
+            scheme if scheme.eq_ignore_ascii_case("smtp") => {
+                let port = url.port().unwrap_or(25);
+                (Stream::connect_tcp(&tcp, scheme, host, port).await?, false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("smtps") => {
+                let default_port = if starttls { 587 } else { 465 };
+                let port = url.port().unwrap_or(default_port);
+                (Stream::connect_tcp(&tcp, scheme, host, port).await?, true)
+            }
+            scheme if scheme.eq_ignore_ascii_case("unix") => {
+                (Stream::connect_unix(url.path()).await?, false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("unixs") => {
+                (Stream::connect_unix(url.path()).await?, true)
+            }
+            scheme if scheme.eq_ignore_ascii_case("cmd") => {
+                (Stream::Command(CommandStream::spawn_url(&url)?), false)
+            }
+            scheme if scheme.eq_ignore_ascii_case("cmds") => {
+                (Stream::Command(CommandStream::spawn_url(&url)?), true)
+            }
+            scheme => {
+                bail!("Unknown scheme {scheme}, expected smtp, smtps, unix, unixs, cmd or cmds");
+            }
+        };
+
+        let mut stream = stream.with_transcript(tcp.transcript.as_ref())?;
+
+        let mut buffer = ReadBuffer::with_capacity(ReadFraming::Lines, READ_BUFFER_SIZE);
+
+        let mechanisms = if !secure {
+            drive_greeting(&mut stream, &mut buffer).await?;
+            drive_ehlo(&mut stream, &mut buffer, domain.clone()).await?
+        } else {
+            if starttls {
+                drive_greeting(&mut stream, &mut buffer).await?;
+                drive_ehlo(&mut stream, &mut buffer, domain.clone()).await?;
+                drive_starttls(&mut stream, &mut buffer).await?;
+            }
+
+            // bytes received in plaintext after STARTTLS could have
+            // been injected by an attacker
+            if !buffer.pending().is_empty() {
+                bail!("unexpected plaintext data received before TLS handshake");
+            }
+
+            // identifies the server in the known hosts, whatever the
+            // transport is
+            let port = url.port().unwrap_or(if starttls { 587 } else { 465 });
+            stream = stream.upgrade_tls(host, port, &tls, Some(b"smtp")).await?;
+
+            if !starttls {
+                drive_greeting(&mut stream, &mut buffer).await?;
+            }
+
+            // capabilities advertised before TLS cannot be trusted
+            drive_ehlo(&mut stream, &mut buffer, domain.clone()).await?
+        };
+
+        let channel_binding = || Ok(stream.tls_channel_binding()?.map(Into::into));
+
+        match SaslAuth::new(sasl, &mechanisms, channel_binding)? {
+            SaslAuth::Login(auth) => {
+                let mut coroutine = SmtpLogin::new(&auth.username, &auth.password, domain.clone());
+                let mut arg: Option<&[u8]> = None;
+
+                loop {
+                    match coroutine.resume(arg.take()) {
+                        SmtpLoginResult::Ok => break,
+                        SmtpLoginResult::WantsRead => {
+                            arg = Some(buffer.read_async(&mut stream).await?);
+                        }
+                        SmtpLoginResult::WantsWrite(bytes) => {
+                            stream.write_all(&bytes).await?;
+                            arg = None;
+                        }
+                        SmtpLoginResult::Err(err) => bail!(err),
+                    }
+                }
+            }
+            SaslAuth::Plain(auth) => {
+                let mut coroutine = SmtpPlain::new(&auth.authcid, &auth.passwd, domain.clone());
+                let mut arg: Option<&[u8]> = None;
+
+                loop {
+                    match coroutine.resume(arg.take()) {
+                        SmtpPlainResult::Ok => break,
+                        SmtpPlainResult::WantsRead => {
+                            arg = Some(buffer.read_async(&mut stream).await?);
+                        }
+                        SmtpPlainResult::WantsWrite(bytes) => {
+                            stream.write_all(&bytes).await?;
+                            arg = None;
+                        }
+                        SmtpPlainResult::Err(err) => bail!(err),
+                    }
+                }
+            }
+            SaslAuth::Client(client) => {
+                drive_authenticate(&mut stream, &mut buffer, client).await?;
+            }
+        }
+
+        Ok(Self { stream, buffer })
+    }
+
+    /// Returns the details negotiated by the TLS layer, if any.
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        self.stream.tls_info()
+    }
+}
@@ -0,0 +1,442 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use io_process::command::Command;
+use log::{debug, warn};
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    process::{Child, ChildStdin, ChildStdout},
+    task, time,
+};
+use url::Url;
+
+#[cfg(feature = "replay")]
+use crate::stream::ReplayStream;
+#[cfg(any(
+    feature = "rustls-aws",
+    feature = "rustls-ring",
+    feature = "native-tls"
+))]
+use crate::stream::TlsProvider;
+use crate::stream::{
+    command::{command_error, shell_command, stderr_string, stream_command},
+    url_command_line, Tcp, Tls, TlsChannelBinding, TlsInfo, Transcript, TranscriptStream,
+};
+
+/// Runs a session setup, failing once the given timeout elapses (see
+/// [`Tcp::handshake_timeout`]).
+pub async fn handshake_timeout<T>(
+    timeout: Option<Duration>,
+    handshake: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(timeout) = timeout else {
+        return handshake.await;
+    };
+
+    match time::timeout(timeout, handshake).await {
+        Ok(result) => result,
+        Err(_) => bail!("Handshake timed out after {timeout:?}"),
+    }
+}
+
+/// Async connection to a server.
+///
+/// Mirror of the blocking [`crate::stream::Stream`]: TLS variants
+/// wrap another stream, so that TLS can be layered on top of any
+/// transport.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Command(CommandStream),
+    #[cfg(all(
+        feature = "tokio-rustls",
+        any(feature = "rustls-aws", feature = "rustls-ring")
+    ))]
+    Rustls(tokio_rustls::client::TlsStream<Box<Stream>>, Option<String>),
+    #[cfg(feature = "tokio-native-tls")]
+    NativeTls(tokio_native_tls::TlsStream<Box<Stream>>, Option<String>),
+    Transcript(TranscriptStream<Box<Stream>>),
+    #[cfg(feature = "replay")]
+    Replay(ReplayStream),
+}
+
+impl Stream {
+    /// Opens a TCP connection to `host:port`.
+    ///
+    /// The connection is established by [`Tcp::connect`] on the
+    /// blocking thread pool, so that proxies behave exactly like in
+    /// the blocking API. When [`Tcp::replay`] is set, the recorded
+    /// transcript is replayed instead.
+    pub async fn connect_tcp(tcp: &Tcp, scheme: &str, host: &str, port: u16) -> Result<Self> {
+        #[cfg(feature = "replay")]
+        if let Some(replay) = &tcp.replay {
+            debug!("replaying transcript instead of connecting to {host}:{port}");
+            return Ok(Self::Replay(replay.stream()));
+        }
+
+        let (tcp, scheme, host) = (tcp.clone(), scheme.to_owned(), host.to_owned());
+        let tcp = task::spawn_blocking(move || tcp.connect(&scheme, &host, port)).await??;
+        tcp.set_nonblocking(true)?;
+        Ok(Self::Tcp(TcpStream::from_std(tcp)?))
+    }
+
+    /// Connects to the Unix socket at the given path.
+    #[cfg(unix)]
+    pub async fn connect_unix(path: &str) -> Result<Self> {
+        Ok(Self::Unix(UnixStream::connect(path).await?))
+    }
+
+    /// Connects to the Unix socket at the given path.
+    #[cfg(not(unix))]
+    pub async fn connect_unix(_path: &str) -> Result<Self> {
+        bail!("Unix sockets are not supported by the async runtime on this platform")
+    }
+
+    /// Returns the details negotiated by the TLS layer, if any (see
+    /// [`crate::stream::Stream::tls_info`]).
+    pub fn tls_info(&self) -> Result<Option<TlsInfo>> {
+        match self {
+            #[cfg(all(
+                feature = "tokio-rustls",
+                any(feature = "rustls-aws", feature = "rustls-ring")
+            ))]
+            Self::Rustls(s, sni) => Ok(Some(TlsInfo::from_rustls(s.get_ref().1, sni.clone())?)),
+            #[cfg(feature = "tokio-native-tls")]
+            Self::NativeTls(s, sni) => {
+                Ok(Some(TlsInfo::from_native_tls(s.get_ref(), sni.clone())?))
+            }
+            Self::Transcript(s) => s.get_ref().tls_info(),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the channel binding of the TLS layer, if any.
+    pub fn tls_channel_binding(&self) -> Result<Option<TlsChannelBinding>> {
+        match self {
+            #[cfg(all(
+                feature = "tokio-rustls",
+                any(feature = "rustls-aws", feature = "rustls-ring")
+            ))]
+            Self::Rustls(s, _) => TlsChannelBinding::from_rustls(s.get_ref().1),
+            #[cfg(feature = "tokio-native-tls")]
+            Self::NativeTls(s, _) => TlsChannelBinding::from_native_tls(s.get_ref()),
+            Self::Transcript(s) => s.get_ref().tls_channel_binding(),
+            _ => Ok(None),
+        }
+    }
+
+    /// Wraps the stream in the given transcript, if any.
+    pub fn with_transcript(self, transcript: Option<&Transcript>) -> Result<Self> {
+        match transcript {
+            Some(transcript) => {
+                let log = transcript.open()?;
+                Ok(Self::Transcript(TranscriptStream::new(Box::new(self), log)))
+            }
+            None => Ok(self),
+        }
+    }
+
+    /// Layers TLS on top of the given stream.
+    ///
+    /// TLS goes underneath the transcript, if any, so that the
+    /// transcript keeps logging plaintext. Replays are left untouched.
+    /// `alpn` is only sent by the rustls provider. `port` identifies
+    /// the server in the known hosts.
+    #[cfg_attr(
+        not(any(feature = "rustls-aws", feature = "rustls-ring")),
+        allow(unused_variables)
+    )]
+    pub async fn upgrade_tls(
+        self,
+        host: &str,
+        port: u16,
+        tls: &Tls,
+        alpn: Option<&[u8]>,
+    ) -> Result<Self> {
+        #[cfg(feature = "replay")]
+        if let Self::Replay(_) = self {
+            return Ok(self);
+        }
+
+        if let Self::Transcript(stream) = self {
+            let (stream, log) = stream.into_parts();
+            let stream = Box::pin(stream.upgrade_tls(host, port, tls, alpn)).await?;
+            return Ok(Self::Transcript(TranscriptStream::new(
+                Box::new(stream),
+                log,
+            )));
+        }
+
+        match tls.provider()? {
+            #[cfg(all(
+                feature = "tokio-rustls",
+                any(feature = "rustls-aws", feature = "rustls-ring")
+            ))]
+            TlsProvider::Rustls => {
+                let config = tls.rustls_client_config(port, alpn)?;
+                let server_name = tls.rustls_server_name(host)?;
+                let connector = tokio_rustls::TlsConnector::from(config);
+                let stream = connector.connect(server_name, Box::new(self)).await?;
+                Ok(Self::Rustls(stream, tls.sni_for(host)))
+            }
+            #[cfg(not(feature = "tokio-rustls"))]
+            #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+            TlsProvider::Rustls => {
+                bail!("Missing cargo feature: `tokio-rustls`")
+            }
+            #[cfg(feature = "tokio-native-tls")]
+            TlsProvider::NativeTls => {
+                let connector = tls.build_native_tls_connector()?;
+                let connector = tokio_native_tls::TlsConnector::from(connector);
+                let stream = connector
+                    .connect(tls.server_name_for(host), Box::new(self))
+                    .await?;
+                tls.verify_native_tls_peer(host, stream.get_ref().peer_certificate()?)?;
+                Ok(Self::NativeTls(stream, tls.sni_for(host)))
+            }
+            #[cfg(not(feature = "tokio-native-tls"))]
+            #[cfg(feature = "native-tls")]
+            TlsProvider::NativeTls => {
+                bail!("Missing cargo feature: `tokio-native-tls`")
+            }
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Command(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(all(
+                feature = "tokio-rustls",
+                any(feature = "rustls-aws", feature = "rustls-ring")
+            ))]
+            Self::Rustls(s, _) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "tokio-native-tls")]
+            Self::NativeTls(s, _) => Pin::new(s).poll_read(cx, buf),
+            Self::Transcript(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "replay")]
+            Self::Replay(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Command(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(all(
+                feature = "tokio-rustls",
+                any(feature = "rustls-aws", feature = "rustls-ring")
+            ))]
+            Self::Rustls(s, _) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "tokio-native-tls")]
+            Self::NativeTls(s, _) => Pin::new(s).poll_write(cx, buf),
+            Self::Transcript(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "replay")]
+            Self::Replay(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_flush(cx),
+            Self::Command(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(all(
+                feature = "tokio-rustls",
+                any(feature = "rustls-aws", feature = "rustls-ring")
+            ))]
+            Self::Rustls(s, _) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "tokio-native-tls")]
+            Self::NativeTls(s, _) => Pin::new(s).poll_flush(cx),
+            Self::Transcript(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "replay")]
+            Self::Replay(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Command(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(all(
+                feature = "tokio-rustls",
+                any(feature = "rustls-aws", feature = "rustls-ring")
+            ))]
+            Self::Rustls(s, _) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "tokio-native-tls")]
+            Self::NativeTls(s, _) => Pin::new(s).poll_shutdown(cx),
+            Self::Transcript(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "replay")]
+            Self::Replay(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Async stream talking to a spawned program through its standard
+/// input and output.
+///
+/// Mirror of the blocking [`crate::stream::CommandStream`].
+pub struct CommandStream {
+    program: String,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    stderr: Arc<Mutex<Vec<u8>>>,
+}
+
+impl CommandStream {
+    /// Spawns the given command.
+    pub fn spawn(cmd: Command) -> Result<Self> {
+        let (cmd, program) = stream_command(cmd);
+        let mut cmd = tokio::process::Command::from(cmd);
+        cmd.kill_on_drop(true);
+
+        let mut child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(err) => bail!("Spawn stream command {program} error: {err}"),
+        };
+
+        let (Some(stdin), Some(stdout), Some(mut child_stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            bail!("Pipe stream command {program} error");
+        };
+
+        let stderr = Arc::new(Mutex::new(Vec::new()));
+        let stderr_ref = stderr.clone();
+
+        task::spawn(async move {
+            let mut buf = [0u8; 1024];
+
+            loop {
+                match child_stderr.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => match stderr_ref.lock() {
+                        Ok(mut stderr) => stderr.extend_from_slice(&buf[..n]),
+                        Err(_) => break,
+                    },
+                    Err(err) => {
+                        warn!("cannot read stream command stderr: {err}");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            program,
+            child,
+            stdin,
+            stdout,
+            stderr,
+        })
+    }
+
+    /// Spawns the command line of a `cmd:` URL through the system
+    /// shell (see [`url_command_line`]).
+    pub fn spawn_url(url: &Url) -> Result<Self> {
+        Self::spawn_shell(&url_command_line(url)?)
+    }
+
+    /// Spawns the given command line through the system shell.
+    pub fn spawn_shell(line: &str) -> Result<Self> {
+        Self::spawn(shell_command(line))
+    }
+
+    /// Returns what the program wrote on its standard error so far.
+    pub fn stderr(&self) -> String {
+        stderr_string(&self.stderr)
+    }
+
+    fn error(&mut self, kind: io::ErrorKind, reason: impl fmt::Display) -> io::Error {
+        let status = self.child.try_wait().ok().flatten();
+        command_error(&self.program, status, &self.stderr(), kind, reason)
+    }
+}
+
+impl fmt::Debug for CommandStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandStream")
+            .field("program", &self.program)
+            .field("pid", &self.child.id())
+            .finish()
+    }
+}
+
+impl AsyncRead for CommandStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+
+        match Pin::new(&mut this.stdout).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == filled && buf.remaining() > 0 => {
+                debug!("stream command {} closed its output", this.program);
+                let err = this.error(io::ErrorKind::UnexpectedEof, "closed its output");
+                Poll::Ready(Err(err))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(this.error(err.kind(), err))),
+            poll => poll,
+        }
+    }
+}
+
+impl AsyncWrite for CommandStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.stdin).poll_write(cx, buf) {
+            Poll::Ready(Err(err)) => Poll::Ready(Err(this.error(err.kind(), err))),
+            poll => poll,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdin).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stdin).poll_shutdown(cx)
+    }
+}
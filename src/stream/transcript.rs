@@ -0,0 +1,473 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+#[cfg(feature = "tokio")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result};
+use log::{trace, warn};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+#[cfg(feature = "replay")]
+use super::replay::escape;
+
+const REDACTED: &str = "<redacted>";
+
+/// Destination of wire-level protocol transcripts.
+///
+/// A transcript logs the plaintext exchanged with the server, one
+/// line per entry prefixed by `C:` (sent by the client) or `S:`
+/// (sent by the server). Credentials are redacted: `LOGIN`
+/// arguments, `AUTHENTICATE` and `AUTH` exchanges, and
+/// `Authorization` headers, so that transcripts can be attached to
+/// bug reports.
+#[derive(Clone, Debug)]
+pub enum Transcript {
+    /// Logs to the `log` crate at trace level.
+    Log,
+    /// Appends to the given file.
+    File(PathBuf),
+    /// Appends the exact bytes exchanged to the given file, in the
+    /// format replayed by [`Replay`](super::Replay).
+    ///
+    /// Nothing is redacted: recordings contain credentials.
+    #[cfg(feature = "replay")]
+    Record(PathBuf),
+}
+
+impl Transcript {
+    /// Opens the transcript for a new connection.
+    pub fn open(&self) -> Result<TranscriptLog> {
+        let file = match self {
+            Self::Log => None,
+            #[cfg(feature = "replay")]
+            Self::Record(path) => Some(open(path)?),
+            Self::File(path) => Some(open(path)?),
+        };
+
+        Ok(TranscriptLog {
+            file,
+            client: Vec::new(),
+            server: Vec::new(),
+            authenticating: false,
+            #[cfg(feature = "replay")]
+            record: matches!(self, Self::Record(_)),
+        })
+    }
+}
+
+fn open(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Open transcript at {} error", path.display()))
+}
+
+/// Transcript opened for a connection.
+///
+/// Bytes are logged line by line, so that credentials split across
+/// several writes are still redacted.
+#[derive(Debug)]
+pub struct TranscriptLog {
+    file: Option<File>,
+    client: Vec<u8>,
+    server: Vec<u8>,
+    /// Whether an authentication exchange is in progress, in which
+    /// case every client line is redacted.
+    authenticating: bool,
+    /// Whether bytes are recorded as is, for replays.
+    #[cfg(feature = "replay")]
+    record: bool,
+}
+
+impl TranscriptLog {
+    /// Logs bytes sent by the client.
+    pub fn client(&mut self, bytes: &[u8]) {
+        #[cfg(feature = "replay")]
+        if self.record {
+            if !bytes.is_empty() {
+                self.log("C", &escape(bytes));
+            }
+            return;
+        }
+
+        self.client.extend_from_slice(bytes);
+
+        while let Some(line) = take_line(&mut self.client) {
+            let line = self.redact(&line);
+            self.log("C", &line);
+        }
+    }
+
+    /// Logs bytes sent by the server.
+    pub fn server(&mut self, bytes: &[u8]) {
+        #[cfg(feature = "replay")]
+        if self.record {
+            if !bytes.is_empty() {
+                self.log("S", &escape(bytes));
+            }
+            return;
+        }
+
+        self.server.extend_from_slice(bytes);
+
+        while let Some(line) = take_line(&mut self.server) {
+            let line = String::from_utf8_lossy(&line);
+
+            // anything but a continuation request (IMAP `+`, SMTP
+            // `334`) ends the authentication exchange
+            if self.authenticating && !line.starts_with('+') && !line.starts_with("334") {
+                self.authenticating = false;
+            }
+
+            self.log("S", &line);
+        }
+    }
+
+    fn redact(&mut self, line: &[u8]) -> String {
+        let line = String::from_utf8_lossy(line);
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let is = |i: usize, word: &str| match words.get(i) {
+            Some(w) => w.eq_ignore_ascii_case(word),
+            None => false,
+        };
+
+        // SMTP `AUTH <mechanism> [<initial response>]`
+        if is(0, "AUTH") {
+            self.authenticating = true;
+            return redact_after(&words, 2);
+        }
+
+        // IMAP `<tag> LOGIN <username> <password>`
+        if is(1, "LOGIN") {
+            self.authenticating = true;
+            return redact_after(&words, 2);
+        }
+
+        // IMAP `<tag> AUTHENTICATE <mechanism> [<initial response>]`
+        if is(1, "AUTHENTICATE") {
+            self.authenticating = true;
+            return redact_after(&words, 3);
+        }
+
+        if self.authenticating {
+            return REDACTED.to_owned();
+        }
+
+        if let Some((name, _)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Authorization")
+                || name.eq_ignore_ascii_case("Proxy-Authorization")
+            {
+                return format!("{name}: {REDACTED}");
+            }
+        }
+
+        line.into_owned()
+    }
+
+    fn log(&mut self, direction: &str, line: &str) {
+        match &mut self.file {
+            None => trace!("{direction}: {line}"),
+            Some(file) => {
+                if let Err(err) = writeln!(file, "{direction}: {line}") {
+                    warn!("cannot write transcript: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// Keeps the first `n` words of a line, redacting the rest.
+fn redact_after(words: &[&str], n: usize) -> String {
+    let mut line = words[..n.min(words.len())].join(" ");
+
+    if words.len() > n {
+        line.push(' ');
+        line.push_str(REDACTED);
+    }
+
+    line
+}
+
+/// Removes the first complete line from the given bytes, without
+/// its line ending.
+fn take_line(bytes: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let end = bytes.iter().position(|b| *b == b'\n')?;
+    let mut line: Vec<u8> = bytes.drain(..=end).collect();
+
+    line.pop();
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Some(line)
+}
+
+/// Stream logging everything going through it to a transcript.
+#[derive(Debug)]
+pub struct TranscriptStream<S> {
+    inner: S,
+    log: TranscriptLog,
+}
+
+impl<S> TranscriptStream<S> {
+    pub fn new(inner: S, log: TranscriptLog) -> Self {
+        Self { inner, log }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Splits the stream, so that the transcript can be moved on top
+    /// of a new stream (for example after a TLS upgrade).
+    pub fn into_parts(self) -> (S, TranscriptLog) {
+        (self.inner, self.log)
+    }
+}
+
+impl<S: Read> Read for TranscriptStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.log.server(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for TranscriptStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.log.client(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncRead + Unpin> AsyncRead for TranscriptStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let start = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = poll {
+            this.log.server(&buf.filled()[start..]);
+        }
+
+        poll
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: AsyncWrite + Unpin> AsyncWrite for TranscriptStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(n)) = poll {
+            this.log.client(&buf[..n]);
+        }
+
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redact_after, take_line, Transcript, TranscriptLog, REDACTED};
+
+    fn log() -> TranscriptLog {
+        Transcript::Log.open().unwrap()
+    }
+
+    #[test]
+    fn imap_login() {
+        let mut log = log();
+
+        let line = log.redact(b"a1 LOGIN alice secret");
+        assert_eq!(line, format!("a1 LOGIN {REDACTED}"));
+
+        log.server(b"a1 OK LOGIN completed\r\n");
+        assert_eq!(log.redact(b"a2 SELECT INBOX"), "a2 SELECT INBOX");
+    }
+
+    #[test]
+    fn imap_login_literal() {
+        let mut log = log();
+
+        let line = log.redact(b"a1 login alice {6}");
+        assert_eq!(line, format!("a1 login {REDACTED}"));
+
+        log.server(b"+ Ready for literal data\r\n");
+        assert_eq!(log.redact(b"secret"), REDACTED);
+
+        log.server(b"a1 OK LOGIN completed\r\n");
+        assert_eq!(log.redact(b"a2 LOGOUT"), "a2 LOGOUT");
+    }
+
+    #[test]
+    fn imap_login_non_synchronizing_literal() {
+        let mut log = log();
+
+        let line = log.redact(b"a1 LOGIN alice {6+}");
+        assert_eq!(line, format!("a1 LOGIN {REDACTED}"));
+        assert_eq!(log.redact(b"secret"), REDACTED);
+    }
+
+    #[test]
+    fn imap_authenticate() {
+        let mut log = log();
+
+        let line = log.redact(b"a1 AUTHENTICATE PLAIN");
+        assert_eq!(line, "a1 AUTHENTICATE PLAIN");
+
+        log.server(b"+ \r\n");
+        assert_eq!(log.redact(b"AGFsaWNlAHNlY3JldA=="), REDACTED);
+
+        log.server(b"a1 OK AUTHENTICATE completed\r\n");
+        assert_eq!(log.redact(b"a2 NOOP"), "a2 NOOP");
+    }
+
+    #[test]
+    fn imap_authenticate_initial_response() {
+        let mut log = log();
+
+        let line = log.redact(b"a1 AUTHENTICATE PLAIN AGFsaWNlAHNlY3JldA==");
+        assert_eq!(line, format!("a1 AUTHENTICATE PLAIN {REDACTED}"));
+
+        log.server(b"a1 OK AUTHENTICATE completed\r\n");
+        assert_eq!(log.redact(b"a2 NOOP"), "a2 NOOP");
+    }
+
+    #[test]
+    fn imap_authenticate_continuations() {
+        let mut log = log();
+
+        log.redact(b"a1 AUTHENTICATE SCRAM-SHA-256");
+        log.server(b"+ \r\n");
+        assert_eq!(log.redact(b"biwsbj1hbGljZSxyPW5vbmNl"), REDACTED);
+        log.server(b"+ cj1ub25jZXNlcnZlcixzPXNhbHQsaT00MDk2\r\n");
+        assert_eq!(log.redact(b"Yz1iaXdzLHI9bm9uY2UscD1wcm9vZg=="), REDACTED);
+        log.server(b"+ dj1zaWduYXR1cmU=\r\n");
+        assert_eq!(log.redact(b""), REDACTED);
+
+        log.server(b"a1 OK AUTHENTICATE completed\r\n");
+        assert_eq!(log.redact(b"a2 NOOP"), "a2 NOOP");
+    }
+
+    #[test]
+    fn smtp_auth_plain() {
+        let mut log = log();
+
+        let line = log.redact(b"AUTH PLAIN AGFsaWNlAHNlY3JldA==");
+        assert_eq!(line, format!("AUTH PLAIN {REDACTED}"));
+
+        log.server(b"235 2.7.0 Authentication successful\r\n");
+        assert_eq!(
+            log.redact(b"MAIL FROM:<alice@example.com>"),
+            "MAIL FROM:<alice@example.com>"
+        );
+    }
+
+    #[test]
+    fn smtp_auth_login() {
+        let mut log = log();
+
+        assert_eq!(log.redact(b"auth login"), "auth login");
+
+        log.server(b"334 VXNlcm5hbWU6\r\n");
+        assert_eq!(log.redact(b"YWxpY2U="), REDACTED);
+        log.server(b"334 UGFzc3dvcmQ6\r\n");
+        assert_eq!(log.redact(b"c2VjcmV0"), REDACTED);
+
+        log.server(b"235 2.7.0 Authentication successful\r\n");
+        assert_eq!(log.redact(b"QUIT"), "QUIT");
+    }
+
+    #[test]
+    fn smtp_auth_failure() {
+        let mut log = log();
+
+        log.redact(b"AUTH LOGIN");
+        log.server(b"334 VXNlcm5hbWU6\r\n");
+        log.redact(b"YWxpY2U=");
+        log.server(b"535 5.7.8 Authentication credentials invalid\r\n");
+        assert_eq!(log.redact(b"QUIT"), "QUIT");
+    }
+
+    #[test]
+    fn authorization_headers() {
+        let mut log = log();
+
+        let line = log.redact(b"Authorization: Basic YWxpY2U6c2VjcmV0");
+        assert_eq!(line, format!("Authorization: {REDACTED}"));
+
+        let line = log.redact(b"proxy-authorization: Bearer token");
+        assert_eq!(line, format!("proxy-authorization: {REDACTED}"));
+
+        let line = log.redact(b"Host: jmap.example.com");
+        assert_eq!(line, "Host: jmap.example.com");
+
+        // headers do not start an authentication exchange
+        assert_eq!(log.redact(b"Accept: */*"), "Accept: */*");
+    }
+
+    #[test]
+    fn redact_words() {
+        assert_eq!(redact_after(&["a", "LOGIN"], 2), "a LOGIN");
+        assert_eq!(redact_after(&["AUTH"], 2), "AUTH");
+        assert_eq!(
+            redact_after(&["a", "LOGIN", "alice", "secret"], 2),
+            format!("a LOGIN {REDACTED}")
+        );
+    }
+
+    #[test]
+    fn split_lines() {
+        let mut log = log();
+
+        log.client(b"a1 LOGIN alice ");
+        assert_eq!(log.client, b"a1 LOGIN alice ");
+        log.client(b"secret\r\na2 NOOP");
+        assert_eq!(log.client, b"a2 NOOP");
+        assert!(log.authenticating);
+
+        let mut bytes = b"a\r\nb\nc".to_vec();
+        assert_eq!(take_line(&mut bytes).unwrap(), b"a");
+        assert_eq!(take_line(&mut bytes).unwrap(), b"b");
+        assert_eq!(take_line(&mut bytes), None);
+        assert_eq!(bytes, b"c");
+    }
+}
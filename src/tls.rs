@@ -1,23 +1,65 @@
+//! TLS configuration, as found in the configuration files of
+//! Pimalaya applications.
+//!
+//! The model mirrors [`stream::Tls`] and converts into it. Providers
+//! and crypto backends can always be deserialized: requesting one
+//! that was not compiled in fails during the conversion, with the
+//! cargo feature to enable.
+
 use std::path::PathBuf;
 
+use anyhow::{Error, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::{secret::Secret, stream};
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Tls {
     pub provider: Option<TlsProvider>,
+    #[serde(default)]
+    pub rustls: Rustls,
+    /// PEM bundle or directory of PEM files of extra trust roots.
     pub cert: Option<PathBuf>,
+    /// PEM bundle of extra trust roots, given inline.
+    pub cert_pem: Option<String>,
+    /// Trusts only the given roots, instead of adding them to the
+    /// platform trust store.
+    #[serde(default)]
+    pub cert_only: bool,
+    /// Also trusts the certificates designated by the OpenSSL
+    /// `SSL_CERT_FILE` and `SSL_CERT_DIR` environment variables.
+    #[serde(default)]
+    pub env_certs: bool,
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    #[serde(default)]
+    pub cipher_suites: Vec<String>,
+    /// Name sent with SNI and used to verify the server certificate,
+    /// instead of the host connected to.
+    pub server_name: Option<String>,
+    #[serde(default)]
+    pub disable_sni: bool,
+    pub client_auth: Option<TlsClientAuth>,
+    #[serde(default)]
+    pub pins: Vec<TlsPin>,
+    /// Path to the trust-on-first-use store of certificate
+    /// fingerprints.
+    pub known_hosts: Option<PathBuf>,
+    pub revocation: Option<TlsRevocation>,
+    /// File keeping the key exchange groups picked by servers between
+    /// runs, so that first handshakes skip a round trip.
+    pub session_cache: Option<PathBuf>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum TlsProvider {
-    #[cfg(any(feature = "rustls-ring", feature = "rustls-aws"))]
-    Rustls(Rustls),
-    #[cfg(feature = "native-tls")]
+    Rustls,
     NativeTls,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct Rustls {
     pub crypto: Option<RustlsCrypto>,
@@ -29,3 +71,291 @@ pub enum RustlsCrypto {
     Aws,
     Ring,
 }
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsClientAuth {
+    /// PEM certificate chain and PKCS#8 PEM private key.
+    Pem { cert: PathBuf, key: PathBuf },
+    /// PKCS#12 archive, only supported by the `native-tls` provider.
+    Pkcs12 {
+        path: PathBuf,
+        password: Option<Secret>,
+    },
+}
+
+/// SHA-256 fingerprint of the server certificate, hex or base64
+/// encoded (see [`stream::TlsPin`]).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsPin {
+    Cert(String),
+    Spki(String),
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsRevocation {
+    /// PEM or DER certificate revocation lists.
+    #[serde(default)]
+    pub crls: Vec<PathBuf>,
+    /// Checks the OCSP response stapled by the server.
+    #[serde(default)]
+    pub ocsp: bool,
+    #[serde(default)]
+    pub end_entity_only: bool,
+    #[serde(default)]
+    pub policy: TlsRevocationPolicy,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsRevocationPolicy {
+    #[default]
+    HardFail,
+    SoftFail,
+}
+
+impl TryFrom<Tls> for stream::Tls {
+    type Error = Error;
+
+    fn try_from(config: Tls) -> Result<Self> {
+        let client_auth = match config.client_auth {
+            None => None,
+            Some(TlsClientAuth::Pem { cert, key }) => {
+                Some(stream::TlsClientAuth::Pem { cert, key })
+            }
+            Some(TlsClientAuth::Pkcs12 { path, password }) => {
+                let password = match password {
+                    Some(password) => Some(password.get()?),
+                    None => None,
+                };
+
+                Some(stream::TlsClientAuth::Pkcs12 { path, password })
+            }
+        };
+
+        let tls = stream::Tls {
+            provider: config.provider.map(Into::into),
+            rustls: stream::Rustls {
+                crypto: config.rustls.crypto.map(Into::into),
+            },
+            cert: config.cert,
+            cert_pem: config.cert_pem,
+            cert_only: config.cert_only,
+            env_certs: config.env_certs,
+            min_version: config.min_version.map(Into::into),
+            max_version: config.max_version.map(Into::into),
+            cipher_suites: config.cipher_suites,
+            server_name: config.server_name,
+            disable_sni: config.disable_sni,
+            client_auth,
+            pins: config.pins.into_iter().map(Into::into).collect(),
+            known_hosts: config.known_hosts.map(|path| stream::TlsKnownHosts {
+                path,
+                confirm: None,
+            }),
+            revocation: config.revocation.map(Into::into),
+            session_cache: match config.session_cache {
+                Some(path) => stream::TlsSessionCache::persistent(path),
+                None => Default::default(),
+            },
+            config_cache: Default::default(),
+        };
+
+        // fails early when the provider or the crypto backend was
+        // not compiled in
+        #[cfg_attr(
+            not(any(feature = "rustls-aws", feature = "rustls-ring")),
+            allow(unused_variables)
+        )]
+        let provider = tls.provider()?;
+
+        #[cfg(any(feature = "rustls-aws", feature = "rustls-ring"))]
+        if let stream::TlsProvider::Rustls = provider {
+            tls.rustls.crypto()?;
+        }
+
+        tls.check_versions()?;
+
+        Ok(tls)
+    }
+}
+
+impl From<TlsProvider> for stream::TlsProvider {
+    fn from(provider: TlsProvider) -> Self {
+        match provider {
+            TlsProvider::Rustls => Self::Rustls,
+            TlsProvider::NativeTls => Self::NativeTls,
+        }
+    }
+}
+
+impl From<RustlsCrypto> for stream::RustlsCrypto {
+    fn from(crypto: RustlsCrypto) -> Self {
+        match crypto {
+            RustlsCrypto::Aws => Self::Aws,
+            RustlsCrypto::Ring => Self::Ring,
+        }
+    }
+}
+
+impl From<TlsVersion> for stream::TlsVersion {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls10 => Self::Tls10,
+            TlsVersion::Tls11 => Self::Tls11,
+            TlsVersion::Tls12 => Self::Tls12,
+            TlsVersion::Tls13 => Self::Tls13,
+        }
+    }
+}
+
+impl From<TlsPin> for stream::TlsPin {
+    fn from(pin: TlsPin) -> Self {
+        match pin {
+            TlsPin::Cert(fingerprint) => Self::Cert(fingerprint),
+            TlsPin::Spki(fingerprint) => Self::Spki(fingerprint),
+        }
+    }
+}
+
+impl From<TlsRevocation> for stream::TlsRevocation {
+    fn from(revocation: TlsRevocation) -> Self {
+        Self {
+            crls: revocation.crls,
+            ocsp: revocation.ocsp,
+            end_entity_only: revocation.end_entity_only,
+            policy: revocation.policy.into(),
+        }
+    }
+}
+
+impl From<TlsRevocationPolicy> for stream::TlsRevocationPolicy {
+    fn from(policy: TlsRevocationPolicy) -> Self {
+        match policy {
+            TlsRevocationPolicy::HardFail => Self::HardFail,
+            TlsRevocationPolicy::SoftFail => Self::SoftFail,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{
+        RustlsCrypto, Tls, TlsClientAuth, TlsPin, TlsProvider, TlsRevocationPolicy, TlsVersion,
+    };
+    use crate::stream;
+
+    const CONFIG: &str = r#"
+        provider = "rustls"
+        cert-only = true
+        min-version = "1.2"
+        max-version = "1.3"
+        server-name = "mail.example.com"
+        disable-sni = true
+        known-hosts = "/var/lib/known_hosts"
+
+        [[pins]]
+        spki = "lxsdLxoasLtRwYMpGUl9xdjUGkndYzG9c1nDrkTS0I8="
+
+        [rustls]
+        crypto = "ring"
+
+        [client-auth.pem]
+        cert = "/etc/client.crt"
+        key = "/etc/client.key"
+
+        [revocation]
+        crls = ["/etc/crl.pem"]
+        ocsp = true
+        end-entity-only = true
+        policy = "soft-fail"
+    "#;
+
+    #[test]
+    fn deserialize_kebab_case() {
+        let tls: Tls = toml::from_str(CONFIG).unwrap();
+
+        assert!(matches!(tls.provider, Some(TlsProvider::Rustls)));
+        assert!(matches!(tls.rustls.crypto, Some(RustlsCrypto::Ring)));
+        assert!(tls.cert_only);
+        assert!(matches!(tls.min_version, Some(TlsVersion::Tls12)));
+        assert!(matches!(tls.max_version, Some(TlsVersion::Tls13)));
+        assert_eq!(tls.server_name.as_deref(), Some("mail.example.com"));
+        assert!(tls.disable_sni);
+        assert_eq!(tls.known_hosts, Some(PathBuf::from("/var/lib/known_hosts")));
+
+        let [TlsPin::Spki(pin)] = tls.pins.as_slice() else {
+            panic!("expected one SPKI pin: {:?}", tls.pins);
+        };
+        assert_eq!(pin, "lxsdLxoasLtRwYMpGUl9xdjUGkndYzG9c1nDrkTS0I8=");
+
+        let Some(TlsClientAuth::Pem { cert, key }) = tls.client_auth else {
+            panic!("expected PEM client auth: {:?}", tls.client_auth);
+        };
+        assert_eq!(cert, PathBuf::from("/etc/client.crt"));
+        assert_eq!(key, PathBuf::from("/etc/client.key"));
+
+        let revocation = tls.revocation.unwrap();
+        assert_eq!(revocation.crls, vec![PathBuf::from("/etc/crl.pem")]);
+        assert!(revocation.ocsp);
+        assert!(revocation.end_entity_only);
+        assert!(matches!(revocation.policy, TlsRevocationPolicy::SoftFail));
+    }
+
+    #[test]
+    fn deserialize_defaults() {
+        let tls: Tls = toml::from_str("").unwrap();
+
+        assert!(tls.provider.is_none());
+        assert!(!tls.cert_only);
+        assert!(tls.pins.is_empty());
+
+        let tls: Tls = toml::from_str("[revocation]").unwrap();
+        assert!(matches!(
+            tls.revocation.unwrap().policy,
+            TlsRevocationPolicy::HardFail
+        ));
+    }
+
+    #[cfg(not(feature = "native-tls"))]
+    #[test]
+    fn missing_native_tls() {
+        let tls: Tls = toml::from_str(r#"provider = "native-tls""#).unwrap();
+        let err = stream::Tls::try_from(tls).unwrap_err();
+        assert_eq!(err.to_string(), "Missing cargo feature: `native-tls`");
+    }
+
+    #[cfg(not(any(feature = "rustls-aws", feature = "rustls-ring")))]
+    #[test]
+    fn missing_rustls() {
+        let tls: Tls = toml::from_str(r#"provider = "rustls""#).unwrap();
+        let err = stream::Tls::try_from(tls).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Missing cargo feature: `rustls-aws`"));
+    }
+
+    #[cfg(all(feature = "rustls-ring", not(feature = "rustls-aws")))]
+    #[test]
+    fn missing_rustls_crypto() {
+        let tls: Tls = toml::from_str("[rustls]\ncrypto = \"aws\"").unwrap();
+        let err = stream::Tls::try_from(tls).unwrap_err();
+        assert_eq!(err.to_string(), "Missing cargo feature: `rustls-aws`");
+    }
+}
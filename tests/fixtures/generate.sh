@@ -0,0 +1,69 @@
+#!/bin/sh
+# Generates the certificates used by unit tests, valid for a century.
+#
+# Usage: sh tests/fixtures/generate.sh (from the repository root)
+
+set -e
+
+dir=$(dirname "$0")
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+cd "$tmp"
+
+key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+}
+
+issue() {
+    openssl req -new -key "$1.key" -subj "$2" -out "$1.csr"
+    printf "$3" >"$1.ext"
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key -set_serial "$4" \
+        -days 36500 -extfile "$1.ext" -out "$1.pem"
+}
+
+for name in ca forged leaf other responder rogue; do key "$name"; done
+
+openssl req -x509 -new -key ca.key -subj "/CN=Test CA" -days 36500 -out ca.pem \
+    -addext "basicConstraints=critical,CA:true" \
+    -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+# same name as the CA, but another key
+openssl req -x509 -new -key forged.key -subj "/CN=Test CA" -days 36500 -out forged.pem \
+    -addext "basicConstraints=critical,CA:true" \
+    -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+issue leaf "/CN=mail.example.com" \
+    "subjectAltName=DNS:mail.example.com,DNS:*.example.org,IP:127.0.0.1\nbasicConstraints=CA:false\n" \
+    0x1001
+
+openssl req -x509 -new -key other.key -subj "/CN=mail.example.com" -out expired.pem \
+    -not_before 20000101000000Z -not_after 20010101000000Z \
+    -addext "subjectAltName=DNS:mail.example.com"
+
+issue responder "/CN=Test OCSP Responder" \
+    "extendedKeyUsage=OCSPSigning\nbasicConstraints=CA:false\n" 0x1002
+
+# issued by the CA, but not for OCSP signing
+issue rogue "/CN=Test Rogue Responder" "basicConstraints=CA:false\n" 0x1003
+
+for name in ca forged leaf expired; do
+    openssl x509 -in "$name.pem" -outform DER -out "$OLDPWD/$dir/$name.der"
+done
+
+# OCSP responses about the leaf, valid for a century from now
+printf "V\t491231235959Z\t\t1001\tunknown\t/CN=mail.example.com\n" >good.txt
+printf "R\t491231235959Z\t261001000000Z\t1001\tunknown\t/CN=mail.example.com\n" >revoked.txt
+
+openssl ocsp -issuer ca.pem -cert leaf.pem -no_nonce -reqout req.der
+openssl ocsp -issuer forged.pem -cert leaf.pem -no_nonce -reqout req-forged.der
+
+ocsp() {
+    openssl ocsp -index "$1" -CA "$2.pem" -rsigner "$3.pem" -rkey "$3.key" -reqin "$4" \
+        -ndays 36500 -respout "$OLDPWD/$dir/ocsp-$5.der"
+}
+
+ocsp good.txt ca ca req.der good
+ocsp revoked.txt ca ca req.der revoked
+ocsp good.txt forged forged req-forged.der forged
+ocsp good.txt ca responder req.der delegated
+ocsp good.txt ca rogue req.der rogue